@@ -0,0 +1,78 @@
+// benches/scan_throughput.rs
+//
+// Per read throughput of the scanner on reads cut from the bundled IMGT database plus random background reads.
+// Run with: cargo bench --bench scan_throughput
+
+use hmm_mapper::HMM::{DpWorkspace, HMM};
+use hmm_mapper::Scanner;
+use hmm_mapper::VDJmodeler::VDJmodeler;
+
+use std::hint::black_box;
+use std::time::Instant;
+
+/// a small deterministic random number generator - the benchmark should not depend on rand
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+}
+
+fn reads(hmm: &HMM, n: usize, len: usize) -> Vec<Vec<u8>> {
+    let mut rng = Lcg(42);
+    let germlines: Vec<Vec<u8>> = hmm.germlines.iter().flatten()
+        .map(|g| g.sequence.iter().filter(|c| HMM::char2pos(**c).is_some()).cloned().collect::<Vec<u8>>())
+        .filter(|s| s.len() >= len)
+        .collect();
+    (0..n).map(|i| {
+        if i % 2 == 0 && !germlines.is_empty() {
+            let g = &germlines[rng.next() as usize % germlines.len()];
+            let start = rng.next() as usize % (g.len() - len + 1);
+            g[start..start + len].to_vec()
+        } else {
+            (0..len).map(|_| b"ACGT"[rng.next() as usize % 4]).collect()
+        }
+    }).collect()
+}
+
+fn report(name: &str, n: usize, start: Instant) {
+    let secs = start.elapsed().as_secs_f64();
+    println!("{name:<28} {n:>7} reads {secs:>8.3} s {:>12.1} reads/s {:>10.2} us/read", n as f64 / secs, 1e6 * secs / n as f64);
+}
+
+fn main() {
+    let hmm = VDJmodeler::build_models("testData/IMGT+C.fa".to_string()).expect("testData/IMGT+C.fa");
+    let reads = reads(&hmm, 2000, 100);
+
+    let start = Instant::now();
+    for read in &reads {
+        black_box(hmm.find_probable_start(read));
+    }
+    report("find_probable_start", reads.len(), start);
+
+    let mut ws = DpWorkspace::default();
+    let start = Instant::now();
+    for read in &reads {
+        black_box(hmm.locus_scores(read, 0, &mut ws));
+    }
+    report("locus_scores (one start)", reads.len(), start);
+
+    // configured like the command line
+    let mut scanner = Scanner::new(hmm);
+    scanner.both_strands = true;
+    scanner.gene_calls = true;
+    let start = Instant::now();
+    for read in &reads {
+        black_box(scanner.scan(read));
+    }
+    report("Scanner::scan", reads.len(), start);
+
+    scanner.enable_prefilter(16).expect("16 is a supported k-mer length");
+    let start = Instant::now();
+    for read in &reads {
+        black_box(scanner.scan(read));
+    }
+    report("Scanner::scan (k=16 filter)", reads.len(), start);
+}
@@ -3,37 +3,52 @@
 use crate::VDJmodeler::SequenceModel;
 use crate::VDJmodeler::HMMmodel;
 use crate::VDJmodeler::HMMcollector;
-use std::collections::HashSet;
-use std::collections::HashMap;
+use crate::VDJmodeler::PlacedGermline;
+use crate::errors::{HmmError, Result};
+use crate::priors::{EmissionConfig, EmissionPrior};
+use crate::simd::{self, Backend};
 
 use std::f64;
 
+/// The type of the precomputed log probabilities and DP scores;
+/// build with the 'f32' feature to halve the table size.
+#[cfg(feature = "f32")]
+pub type LogProb = f32;
+#[cfg(not(feature = "f32"))]
+pub type LogProb = f64;
+
+/// a LogProb as f64 - a no-op unless built with the 'f32' feature
+#[inline]
+#[allow(clippy::useless_conversion)]
+pub fn log_prob_to_f64(value: LogProb) -> f64 {
+    f64::from(value)
+}
+
+/// ln(sum(exp(values))) without leaving the log space
+fn log_sum_exp(values: impl Iterator<Item = f64>) -> f64 {
+    let values: Vec<f64> = values.collect();
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if max == f64::NEG_INFINITY {
+        return max
+    }
+    max + values.iter().map(|value| (value - max).exp()).sum::<f64>().ln()
+}
+
+/// the emission probability of a base outside the profile of a locus - the same as the background model of the log odds
+pub const BACKGROUND: f64 = 0.25;
+
 pub struct HMMState {
     pub match_emission: Vec<Vec<f64>>,    // Probabilities for A, G, C, T, and `.`
 }
 
 impl HMMState {
 
-    fn adjust_zero_probabilities(probabilities: &mut [f64], min_prob: f64) {
-        for prob in probabilities.iter_mut() {
-            if *prob == 0.0 {
-                *prob = min_prob;
-            }
-        }
-    }
-    fn normalize_probabilities(probabilities: &mut [f64]) {
-        let total: f64 = probabilities.iter().sum();
-        if total > 0.0 {
-            for prob in probabilities.iter_mut() {
-                *prob /= total;
-            }
-        }
-    }
-
+    /// the probability of base pos (see HMM::char2pos) for every locus -
+    /// loci without data at this position (an empty column) emit the uniform background
     pub fn prob_for_pos(&self, pos:usize) -> Vec<f64> {
         let mut ret = Vec::<f64>::with_capacity(self.match_emission.len());
         for emissions in &self.match_emission {
-            ret.push (*emissions.get(pos).unwrap_or( &0.00001 ));
+            ret.push (*emissions.get(pos).unwrap_or( &BACKGROUND ));
         }
         ret
     }
@@ -42,80 +57,361 @@ impl HMMState {
         self.match_emission.len()
     }
 
-    // Normalize match emission probabilities based on a list of HMMcollectors
-    pub fn from_collectors( collectors: &[HMMcollector]) -> Self {
-        //pub match_emission: Vec<[f64; 5]>,    // Probabilities for A, G, C, T, and `.`
-        //pub insertion_emission: Vec<[f64; 5]>,
-        let mut total_counts = vec![0; 5];
-        
-        // Aggregate counts from all collectors
-        for collector in collectors {
-            for (i, &count) in collector.states.iter().enumerate() {
-                total_counts[i] += count;
-            }
-        }
-        let total = total_counts.iter().sum::<usize>() as f64;
+    pub fn is_empty(&self) -> bool {
+        self.match_emission.is_empty()
+    }
 
-        let mut match_emission = vec![vec![0.0; 5]; collectors.len() ];
-        let min_prob = 0.0001;
-        for (i, collector) in collectors.iter().enumerate() {
-            for (j, &count) in collector.states.iter().enumerate() {
-                match_emission[i][j] = count as f64 /total;
-            }
-            Self::adjust_zero_probabilities( &mut match_emission[i], min_prob );
-            Self::normalize_probabilities( &mut match_emission[i]);
-        }
+    /// keep only the emission columns flagged in keep (same order as HMM::names)
+    pub fn retain_columns(&mut self, keep: &[bool]) {
+        let mut it = keep.iter();
+        self.match_emission.retain(|_| *it.next().unwrap_or(&false));
+    }
 
+    /// The emission probabilities of the given collectors (one per locus) - each locus is normalized on its own,
+    /// unseen bases get a probability of 0.0001 (EmissionPrior::Floor).
+    pub fn from_collectors( collectors: &[HMMcollector]) -> Self {
+        let match_emission = collectors.iter().map(|collector| {
+            let mut counts = [0.0; 5];
+            for (value, count) in counts.iter_mut().zip(&collector.states) {
+                *value = *count as f64;
+            }
+            EmissionPrior::Floor.estimate(&counts, &[0.25; 4])
+        }).collect();
         Self{
             match_emission,
         }
     }
-
-
 }
 
 pub struct HMM {
-    /// the different HMM emission states for the different names at each position
+    /// the emission states at each position with one column per locus in the order of names - empty after the end of the locus
     pub states: Vec<HMMState>,
-    /// the fixed transition matrix - never switch from IGH to e.g. TRA
+    /// the locus transition matrix - the identity (never switch from IGH to e.g. TRA) unless allow_locus_switches was called
     pub transition_matrix: Vec<Vec<f64>>,
     /// the different sequence names
     pub names: Vec<SequenceModel>,
+    /// the species each of the names was built from ("" if the database was not species-tagged)
+    pub species: Vec<String>,
+    /// the germline sequences each of the names was built from (used for gene calls)
+    pub germlines: Vec<Vec<PlacedGermline>>,
+    /// the emission probabilities of all states in one position major table [position][base][locus]
+    emission: Vec<f64>,
+    /// emission in base major layout [base][position][locus] - the read base i adds one contiguous row to all diagonals
+    base_emission: Vec<f64>,
+    /// ln of emission in the same layout
+    log_emission: Vec<LogProb>,
+    /// ln of the transition matrix stored as [from][to] so the DP can update all loci at once
+    log_transition: Vec<LogProb>,
+    /// the profile length of every locus - positions after it emit the background
+    lengths: Vec<usize>,
+    /// the fraction of germlines with an IMGT gap ('.') among those covering a position as [position][locus]
+    gap_frequency: Vec<f64>,
+}
+
+/// the number of start candidates per locus forward_algorithm and the Scanner check by default
+pub const START_CANDIDATES: usize = 3;
+
+/// the read bases a start candidate has to share with the profile of a locus (see HMM::start_candidates)
+pub const MIN_OVERLAP: usize = 30;
+
+/// An ungapped placement of a read in one locus (see HMM::start_candidates):
+/// read base read_start is placed at model position start, one of them is 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StartCandidate {
+    /// the locus index in the order of HMM::names
+    pub locus: usize,
+    /// the model position of the first scanned read base
+    pub start: usize,
+    /// the first read base inside the model - the bases before it overhang the start of the model
+    pub read_start: usize,
+    /// the number of read bases inside the locus profile
+    pub len: usize,
+    /// the mean emission probability of the read bases inside the locus profile
+    pub score: f64,
+}
+
+impl StartCandidate {
+    /// the model position of read base 0 - negative if the read starts before the model
+    pub fn diagonal(&self) -> i64 {
+        self.start as i64 - self.read_start as i64
+    }
+
+    /// the summed emission probabilities above the background - how candidates with different overlaps are ranked
+    pub fn excess(&self) -> f64 {
+        (self.score - BACKGROUND) * self.len as f64
+    }
+}
+
+/// a diagonal with the forward_algorithm_pos result for it
+type StartScores = (i64, Option<Vec<(String, f64)>>);
+
+/// Reusable buffers for the per locus dynamic programming - one per thread.
+#[derive(Clone, Default)]
+pub struct DpWorkspace {
+    /// the scores of the last position; holds the final scores after HMM::locus_scores
+    pub prev: Vec<LogProb>,
+    pub cur: Vec<LogProb>,
 }
 
 impl HMM {
     // Create an HMM from the given sequence models
     pub fn from_sequence_models(models: Vec<HMMmodel>) -> Self {
-        // Assume all models have the same length for simplicity
+        Self::from_sequence_models_with(models, &EmissionConfig::default())
+    }
+
+    /// Create an HMM from the given sequence models with these emission priors and sequence weights.
+    pub fn from_sequence_models_with(models: Vec<HMMmodel>, config: &EmissionConfig) -> Self {
         let sequence_length = models.iter().map(|m| m.collector.len()).max().unwrap_or(0);
 
-        // Define a fixed transition matrix
-        // Example: Suppose we have `num_states` states (one for each model)
-        let num_states = models.len(); 
-        let low_prob = 0.001;
-        let diagonal_prob = 1.0 - (low_prob * (num_states as f64 - 1.0));
+        // the (weighted) counts and the base composition of every locus
+        let counts: Vec<Vec<[f64; 5]>> = models.iter().map(|model| config.counts(model)).collect();
+        let backgrounds: Vec<[f64; 4]> = counts.iter().map(|columns| {
+            let mut ret = [0.0; 4];
+            for column in columns {
+                for (value, count) in ret.iter_mut().zip(column) {
+                    *value += count;
+                }
+            }
+            let total: f64 = ret.iter().sum();
+            if total > 0.0 { ret.map(|value| value / total) } else { [0.25; 4] }
+        }).collect();
 
-        // Create transition matrix with the calculated probabilities
-        let mut transition_matrix = vec![vec![low_prob; num_states]; num_states];
-        for i in 0..num_states {
-            transition_matrix[i][i] = diagonal_prob;
-        }
+        // a read stays in the locus it started in - see allow_locus_switches
+        let transition_matrix = Self::switch_matrix(models.len(), 0.0);
 
         // Create HMM states from models
+        // one column per locus in every state - empty after the end of the locus
         let states: Vec<HMMState> = (0..sequence_length)
-            .map(|i| {
-                let collectors: Vec<HMMcollector> = models.iter()
-                    .filter_map(|model| model.collector.get(i).cloned())
-                    .collect();
-                HMMState::from_collectors(&collectors)
+            .map(|i| HMMState {
+                match_emission: counts.iter().zip(&backgrounds)
+                    .map(|(columns, background)| columns.get(i).map(|column| config.prior.estimate(column, background)).unwrap_or_default())
+                    .collect(),
             }).collect();
 
         // Create HMM instance
-        HMM { 
+        let mut hmm = HMM { 
             states,
             transition_matrix,
             names: models.iter().map(|m| m.name.clone()).collect(), // Clone the names
+            species: models.iter().map(|m| m.species.clone()).collect(),
+            germlines: models.iter().map(|m| m.germlines.clone()).collect(),
+            emission: Vec::new(),
+            base_emission: Vec::new(),
+            log_emission: Vec::new(),
+            log_transition: Vec::new(),
+            lengths: Vec::new(),
+            gap_frequency: Vec::new(),
+        };
+        hmm.build_tables();
+        hmm
+    }
+
+    /// a transition matrix switching to every other locus with probability per position
+    fn switch_matrix(num_loci: usize, probability: f64) -> Vec<Vec<f64>> {
+        let diagonal_prob = 1.0 - (probability * (num_loci as f64 - 1.0));
+        let mut ret = vec![vec![probability; num_loci]; num_loci];
+        for (i, row) in ret.iter_mut().enumerate() {
+            row[i] = diagonal_prob;
+        }
+        ret
+    }
+
+    /// Let the best path switch from one locus to every other locus with this probability per position
+    /// (e.g. 0.001) to find trans-locus rearrangements and chimeric reads - see viterbi_path.
+    /// 0 forbids switching again (the default).
+    pub fn allow_locus_switches(&mut self, probability: f64) {
+        self.transition_matrix = Self::switch_matrix(self.num_loci(), probability);
+        self.build_tables();
+    }
+
+    /// (Re-)build the flat probability tables from states and transition_matrix.
+    /// Needs to be called after changing either of them.
+    pub fn build_tables(&mut self) {
+        let num_loci = self.names.len();
+        self.emission = Vec::with_capacity(self.states.len() * 4 * num_loci);
+        for state in &self.states {
+            for base in 0..4 {
+                // the same default as HMMState::prob_for_pos for loci without data at this position
+                self.emission.extend(state.prob_for_pos(base).into_iter().chain(std::iter::repeat(BACKGROUND)).take(num_loci));
+            }
+        }
+        self.base_emission = (0..4)
+            .flat_map(|base| self.emission.chunks_exact(4 * num_loci.max(1)).flat_map(move |row| &row[base * num_loci..(base + 1) * num_loci]))
+            .copied()
+            .collect();
+        self.log_emission = self.emission.iter().map(|p| p.ln() as LogProb).collect();
+        self.log_transition = self.transition_matrix.iter()
+            .flat_map(|row| row.iter().map(|p| p.ln() as LogProb))
+            .collect();
+        self.lengths = (0..num_loci).map(|locus| {
+            self.states.iter()
+                .rposition(|state| state.match_emission.get(locus).is_some_and(|column| !column.is_empty()))
+                .map_or(0, |pos| pos + 1)
+        }).collect();
+        // (gaps, covering germlines) per position and locus
+        let mut coverage = vec![(0usize, 0usize); self.states.len() * num_loci];
+        for (locus, germlines) in self.germlines.iter().enumerate().take(num_loci) {
+            for germline in germlines {
+                for (offset, base) in germline.sequence.iter().enumerate() {
+                    if let Some(cell) = coverage.get_mut((germline.start + offset) * num_loci + locus) {
+                        cell.0 += (*base == b'.') as usize;
+                        cell.1 += 1;
+                    }
+                }
+            }
+        }
+        self.gap_frequency = coverage.iter()
+            .map(|(gaps, total)| if *total > 0 { *gaps as f64 / *total as f64 } else { 0.0 })
+            .collect();
+    }
+
+    /// the number of model positions the profile of locus covers - always starting at position 0
+    pub fn locus_len(&self, locus: usize) -> usize {
+        self.lengths[locus]
+    }
+
+    /// true if a read of len bases placed at model position start stays inside the profile of locus -
+    /// reads longer than the profile only fit at its start
+    pub fn fits_locus(&self, locus: usize, start: usize, len: usize) -> bool {
+        start + len <= self.lengths[locus] || (start == 0 && self.lengths[locus] > 0)
+    }
+
+    /// the number of loci (columns) in the model
+    pub fn num_loci(&self) -> usize {
+        self.names.len()
+    }
+
+    /// the emission probabilities of all loci for base (see char2pos) at model position pos
+    pub fn emissions(&self, pos: usize, base: usize) -> &[f64] {
+        let n = self.names.len();
+        let from = (pos * 4 + base) * n;
+        &self.emission[from..from + n]
+    }
+
+    /// the ln emission probabilities of all loci for base (see char2pos) at model position pos
+    pub fn log_emissions(&self, pos: usize, base: usize) -> &[LogProb] {
+        let n = self.names.len();
+        let from = (pos * 4 + base) * n;
+        &self.log_emission[from..from + n]
+    }
+
+    /// the fraction of the germlines of locus covering model position pos that have an IMGT gap ('.') there -
+    /// 0 if no germline covers it
+    pub fn gap_frequency(&self, pos: usize, locus: usize) -> f64 {
+        self.gap_frequency.get(pos * self.names.len() + locus).copied().unwrap_or(0.0)
+    }
+
+    /// The best scoring stretch of the read in locus with read base read_start placed at model position start:
+    /// (its summed log odds against the background, first read base, end). The read bases outside the stretch
+    /// are left to the background like the bases overhanging the profile - an ungapped read leaves the model
+    /// columns after the first IMGT gap and should not pay for it with the bases it matched before.
+    /// None if no base scores above the background or for invalid characters.
+    pub fn best_stretch(&self, locus: usize, sequence: &[u8], read_start: usize, start: usize) -> Option<(f64, usize, usize)> {
+        let background = BACKGROUND.ln();
+        let end = sequence.len().min(read_start + self.locus_len(locus).saturating_sub(start));
+        let (mut best, mut current) = (None, (0.0, read_start));
+        for (i, base) in sequence.iter().enumerate().take(end).skip(read_start) {
+            let emission = self.log_emissions(start + i - read_start, Self::char2pos(*base)?)[locus];
+            if current.0 <= 0.0 {
+                current = (0.0, i);
+            }
+            current.0 += log_prob_to_f64(emission) - background;
+            if best.is_none_or(|(score, _, _)| current.0 > score) {
+                best = Some((current.0, current.1, i + 1));
+            }
+        }
+        best.filter(|(score, _, _)| *score > 0.0)
+    }
+
+    /// the ln transition matrix as one [from][to] table
+    pub fn log_transitions(&self) -> &[LogProb] {
+        &self.log_transition
+    }
+
+    /// the name reported for model i - species tagged models are reported as e.g. 'human:IGH-VDJ'
+    pub fn label(&self, i: usize) -> String {
+        match self.species[i].as_str() {
+            "" => self.names[i].name(),
+            species => format!("{}:{}", species, self.names[i].name()),
+        }
+    }
+
+    /// The most likely base of locus at every position of its profile - 'N' where no base reaches 0.25 (no data or mostly gaps).
+    /// This is the reference sequence the SAM/BAM output is aligned to.
+    pub fn consensus(&self, locus: usize) -> Vec<u8> {
+        (0..self.locus_len(locus)).map(|pos| {
+            let mut best = (b'N', 0.25);
+            for (base, code) in [b'A', b'G', b'C', b'T'].into_iter().enumerate() {
+                let prob = self.emissions(pos, base)[locus];
+                if prob >= best.1 {
+                    best = (code, prob);
+                }
+            }
+            best.0
+        }).collect()
+    }
+
+    /// the distinct species in this model in the order they were added
+    pub fn species_list(&self) -> Vec<String> {
+        let mut ret: Vec<String> = Vec::new();
+        for species in &self.species {
+            if !ret.contains(species) {
+                ret.push(species.clone());
+            }
+        }
+        ret
+    }
+
+    /// Drop all models not built from this species - the transition rows of the kept loci are renormalised.
+    /// Returns false (and leaves the HMM untouched) if the species is not part of the model.
+    pub fn restrict_to_species(&mut self, species: &str) -> bool {
+        let keep: Vec<bool> = self.species.iter().map(|s| s == species).collect();
+        if !keep.iter().any(|k| *k) {
+            return false
+        }
+        for state in self.states.iter_mut() {
+            state.retain_columns(&keep);
+        }
+        let mut it = keep.iter();
+        self.transition_matrix.retain(|_| *it.next().unwrap());
+        for row in self.transition_matrix.iter_mut() {
+            let mut it = keep.iter();
+            row.retain(|_| *it.next().unwrap());
+            // the switches to the dropped loci go to the kept ones, so every row sums to 1 again
+            let sum: f64 = row.iter().sum();
+            if sum > 0.0 {
+                row.iter_mut().for_each(|p| *p /= sum);
+            }
+        }
+        let mut it = keep.iter();
+        self.names.retain(|_| *it.next().unwrap());
+        let mut it = keep.iter();
+        self.species.retain(|_| *it.next().unwrap());
+        let mut it = keep.iter();
+        self.germlines.retain(|_| *it.next().unwrap());
+        self.build_tables();
+        true
+    }
+
+    /// The most likely species for a forward_algorithm result.
+    /// Every species is scored by its best locus and the species with the highest score is returned -
+    /// summing would favour species that simply have more loci in the database.
+    /// Returns None if the models are not species tagged.
+    pub fn most_likely_species(hits: &[(String, f64)]) -> Option<String> {
+        let mut per_species: Vec<(String, f64)> = Vec::new();
+        for (label, value) in hits {
+            let species = match label.split_once(':') {
+                Some((species, _)) => species.to_string(),
+                None => continue,
+            };
+            match per_species.iter_mut().find(|(s, _)| *s == species) {
+                Some(entry) => entry.1 = entry.1.max(*value),
+                None => per_species.push((species, *value)),
+            }
         }
+        per_species.into_iter()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(species, _)| species)
     }
 
     /// translate the sequence into the correct position in the data
@@ -125,34 +421,39 @@ impl HMM {
             b'G' => Some(1),
             b'C' => Some(2),
             b'T' => Some(3),
-            other => {
+            _other => {
                 //eprintln!("Sorry I can not decode this char {}",other as char );
                 None
             },
         }
     }
 
-    pub fn iupac_char2pos(seq: u8) -> Vec<usize> {
-    match seq.to_ascii_uppercase() {
-        b'A' => vec![0],        // A or a
-        b'G' => vec![1],        // G or g
-        b'C' => vec![2],        // C or c
-        b'T' => vec![3],        // T or t
-        b'.' => vec![],        // . (gap)
-        b'N' => vec![0, 1, 2, 3], // N or n
-        b'R' => vec![0, 1],     // R or r
-        b'Y' => vec![2, 3],     // Y or y
-        b'W' => vec![0, 3],     // W or w
-        b'S' => vec![1, 2],     // S or s
-        b'K' => vec![1, 3],     // K or k
-        b'M' => vec![0, 2],     // M or m
-        b'B' => vec![1, 2, 3],  // B or b
-        b'D' => vec![0, 1, 3],  // D or d
-        b'H' => vec![0, 2, 3],  // H or h
-        b'V' => vec![0, 1, 2],  // V or v
-        other => panic!("Sorry, I cannot decode this char: {}", other as char),
+    /// translate an IUPAC coded (database) character into the matching base positions; '.' is a gap
+    pub fn iupac_char2pos(seq: u8) -> Result<Vec<usize>> {
+        let ret = match seq.to_ascii_uppercase() {
+            b'A' => vec![0],        // A or a
+            b'G' => vec![1],        // G or g
+            b'C' => vec![2],        // C or c
+            b'T' => vec![3],        // T or t
+            b'.' => vec![],        // . (gap)
+            b'N' => vec![0, 1, 2, 3], // N or n
+            b'R' => vec![0, 1],     // R or r
+            b'Y' => vec![2, 3],     // Y or y
+            b'W' => vec![0, 3],     // W or w
+            b'S' => vec![1, 2],     // S or s
+            b'K' => vec![1, 3],     // K or k
+            b'M' => vec![0, 2],     // M or m
+            b'B' => vec![1, 2, 3],  // B or b
+            b'D' => vec![0, 1, 3],  // D or d
+            b'H' => vec![0, 2, 3],  // H or h
+            b'V' => vec![0, 1, 2],  // V or v
+            other => return Err(HmmError::UnsupportedCharacter{
+                character: other as char,
+                context: "a germline sequence".to_string(),
+            }),
+        };
+        Ok(ret)
     }
-}
 
     pub fn states( &self ) -> &Vec<HMMState>{
         &self.states
@@ -160,29 +461,15 @@ impl HMM {
 
     fn try_start_at(&self, sequence: &[u8], pos: usize) -> Option<Vec<f64>> {
         // Initialize the return vector with zeros
-        let mut ret = vec![0.0; self.states[0].match_emission.len()];
+        let mut ret = vec![0.0; self.num_loci()];
 
         // Iterate over the sequence
-        for t in 0..sequence.len() {
-            let seq_id = match HMM::char2pos(sequence[t]) {
-                Some(id) => id,
-                None => {
-                    // Return a vector of zeros if there's an invalid character
-                    return None
-                }
-            };
-
-            // Accumulate probabilities into ret
-            let prob_for_pos = self.states[t+pos].prob_for_pos(seq_id);
-
-            // Ensure the lengths match before adding
-            if prob_for_pos.len() != ret.len() {
-                //eprintln!("Warning: Length mismatch between probability vector and return vector at position {} with start {pos}", t+pos );
-                return None
-            }
+        for (t, base) in sequence.iter().enumerate() {
+            // Return None if there's an invalid character
+            let seq_id = HMM::char2pos(*base)?;
 
             // Perform element-wise addition
-            for (ret_val, prob_val) in ret.iter_mut().zip(prob_for_pos.iter()) {
+            for (ret_val, prob_val) in ret.iter_mut().zip(self.emissions(t+pos, seq_id)) {
                 *ret_val += prob_val;
             }
         }
@@ -190,155 +477,198 @@ impl HMM {
         Some(ret)
     }
 
-    pub fn find_probable_start(&self, sequence: &[u8]) -> Vec<(usize, f64)> {
-        let num_states = self.states[0].len();
-        let mut stats_vec = vec![0.0; num_states];
-        let mut pos_vec = vec![0; num_states];
-
-        // Ensure that sequence length is not greater than the number of positions available
-        let max_start_pos = self.states.len().saturating_sub(sequence.len());
-
-        for t in 0..max_start_pos {
-            let current_probs = match self.try_start_at(sequence, t){
-                Some(ret) => ret,
-                None => {
-                    //out of data in the model;
-                    break;
-                }
+    /// The mean emission probability per locus of the read bases inside the locus profile with base read_start
+    /// placed at model position start - the statistic of the start candidates.
+    /// None if read_start is not part of the sequence, start not part of the model or a base is invalid;
+    /// 0.0 for loci the read does not overlap by MIN_OVERLAP bases (like HMM::start_candidates).
+    pub fn start_score(&self, sequence: &[u8], read_start: usize, start: usize) -> Option<Vec<f64>> {
+        if read_start >= sequence.len() || start >= self.states.len() {
+            return None
+        }
+        if sequence.iter().any(|base| HMM::char2pos(*base).is_none()) {
+            return None
+        }
+        let len = (sequence.len() - read_start).min(self.states.len() - start);
+        let mut ret = self.try_start_at(&sequence[read_start..read_start + len], start)?;
+        for (locus, value) in ret.iter_mut().enumerate() {
+            // the positions after the end of the locus emit the background
+            let inside = len.min(self.locus_len(locus).saturating_sub(start));
+            let min_overlap = MIN_OVERLAP.min(sequence.len()).min(self.locus_len(locus)).max(1);
+            *value = if inside < min_overlap {
+                0.0
+            } else {
+                (*value - (len - inside) as f64 * BACKGROUND) / inside as f64
             };
+        }
+        Some(ret)
+    }
 
-            for (id, value) in current_probs.iter().enumerate() {
-                if stats_vec[id] < *value {
-                    stats_vec[id] = *value;
-                    pos_vec[id] = t;
-                }
-            }
+    /// The best start per locus as (model start, mean emission probability) in the order of names -
+    /// (0, 0.0) for loci the sequence does not overlap enough.
+    pub fn find_probable_start(&self, sequence: &[u8]) -> Vec<(usize, f64)> {
+        if self.states.is_empty() || sequence.is_empty() {
+            return Vec::new()
+        }
+        let mut ret = vec![(0, 0.0); self.num_loci()];
+        for candidate in self.start_candidates(sequence, 1) {
+            ret[candidate.locus] = (candidate.start, candidate.score);
         }
+        ret
+    }
 
-        // Combine pos_vec and stats_vec into a Vec<(usize, f64)>
+    /// The n best ungapped placements of the sequence per locus - the placements whose emission probabilities exceed
+    /// the background the most (see StartCandidate::excess).
+    /// The read may overhang the profile of a locus on both sides (long reads, contigs, reads into the constant region),
+    /// but has to share at least MIN_OVERLAP bases with it - or all of the read or the profile if it is shorter.
+    /// Only local maxima along the diagonals are candidates, so the next candidate of a locus is not
+    /// just the best one shifted by a base.
+    /// Sorted by locus and the best candidate first within a locus; an invalid character gives no candidates.
+    pub fn start_candidates(&self, sequence: &[u8], n: usize) -> Vec<StartCandidate> {
+        let num_loci = self.num_loci();
+        let bases: Option<Vec<usize>> = sequence.iter().map(|base| HMM::char2pos(*base)).collect();
+        let bases = bases.unwrap_or_default();
+        if self.states.is_empty() || bases.is_empty() || n == 0 {
+            return Vec::new()
+        }
+        let len = bases.len() as i64;
+        let model_len = self.states.len() as i64;
+        let min_overlap: Vec<i64> = (0..num_loci)
+            .map(|locus| MIN_OVERLAP.min(bases.len()).min(self.locus_len(locus)) as i64)
+            .collect();
+        // the diagonals (the model position of the first read base - negative if the read starts before the model)
+        // with enough overlap in any locus
+        let loci = || (0..num_loci).filter(|locus| self.locus_len(*locus) > 0);
+        let lowest = match loci().map(|locus| min_overlap[locus] - len).min() {
+            Some(lowest) => lowest,
+            None => return Vec::new(),
+        };
+        let highest = loci().map(|locus| self.locus_len(locus) as i64 - min_overlap[locus]).max().unwrap_or(lowest);
+        let diagonals = (highest - lowest + 1) as usize;
+
+        // the summed emission probabilities of the read bases inside the model for every diagonal and locus
+        // (read base by read base: the base adds the same stretch of its base_emission rows to all diagonals)
+        let mut sums = vec![0.0; diagonals * num_loci];
+        let base_rows = self.states.len() * num_loci;
+        for (i, base) in bases.iter().enumerate() {
+            // the diagonals that place read base i inside the model
+            let from = (-(lowest + i as i64)).max(0);
+            let to = (model_len - lowest - i as i64).min(diagonals as i64);
+            if from >= to {
+                continue
+            }
+            let pos = (lowest + from + i as i64) as usize;
+            let rows = &self.base_emission[base * base_rows + pos * num_loci..];
+            for (value, prob) in sums[from as usize * num_loci..to as usize * num_loci].iter_mut().zip(rows) {
+                *value += prob;
+            }
+        }
 
-        for i in 0..stats_vec.len(){
-            stats_vec[i] =  stats_vec[i] / sequence.len() as f64;
+        let mut ret = Vec::new();
+        for locus in loci() {
+            let candidate = |k: usize| {
+                let diagonal = lowest + k as i64;
+                let first = (-diagonal).max(0);
+                let inside = len.min(self.locus_len(locus) as i64 - diagonal) - first;
+                if inside < min_overlap[locus] {
+                    return None
+                }
+                // the positions after the end of the locus emit the background
+                let in_model = len.min(model_len - diagonal) - first;
+                let sum = sums[k * num_loci + locus] - (in_model - inside) as f64 * BACKGROUND;
+                Some(StartCandidate {
+                    locus,
+                    start: diagonal.max(0) as usize,
+                    read_start: first as usize,
+                    len: inside as usize,
+                    score: sum / inside as f64,
+                })
+            };
+            let excess: Vec<f64> = (0..diagonals)
+                .map(|k| candidate(k).map_or(f64::NEG_INFINITY, |candidate| candidate.excess()))
+                .collect();
+            let mut candidates: Vec<StartCandidate> = (0..diagonals)
+                .filter(|k| (*k == 0 || excess[k - 1] < excess[*k]) && (*k + 1 == diagonals || excess[k + 1] <= excess[*k]))
+                .filter_map(candidate)
+                .collect();
+            let best_first = |a: &StartCandidate, b: &StartCandidate| b.excess().total_cmp(&a.excess()).then(a.diagonal().cmp(&b.diagonal()));
+            if candidates.len() > n {
+                candidates.select_nth_unstable_by(n - 1, best_first);
+                candidates.truncate(n);
+            }
+            candidates.sort_by(best_first);
+            ret.extend(candidates);
         }
-        pos_vec.into_iter()
-            .zip(stats_vec.into_iter())
-            .collect()
-        
+        ret
     }
 
     /// Forward algorithm
     /// The only thing I really need from this as I 'only' want to check if any of the sequences
     /// would be of a VDJ recombination evet.
     pub fn forward_algorithm(&self, sequence: &[u8]) -> Option< Vec<(String, f64)> > {
-        
-
-        let probable_start_values = self.find_probable_start( sequence );
-
-        let mut start_values:HashSet<usize> = HashSet::new();
-        for  (pos, stat ) in &probable_start_values{
-            if stat > &0.3 {
-                start_values.insert( *pos);
-            }
-        }
-
-        //let start_values:Vec<usize> = probable_start_values.iter().filter_map(|x| { if x.1 > 0.3{ Some( x.0 ) }else { None} }).collect();
-
-        if start_values.len() == 0 {
+        let candidates: Vec<StartCandidate> = self.start_candidates( sequence, START_CANDIDATES ).into_iter()
+            .filter(|candidate| candidate.score > 0.3)
+            .collect();
+        if candidates.is_empty() {
             return None
         }
-        //println!("Using these probable_start_values {probable_start_values:?}\nI find these probable start positions: {start_values:?}");
 
-        let mut data: Vec<Option<Vec<(String, f64)>>> = vec![];
-        for start in &start_values {
-            let this = self.forward_algorithm_pos( sequence, *start );
-            data.push( this );
-        }
+        // every diagonal is scored once, but a locus only takes the scores of its own start candidates
+        let mut diagonals: Vec<(i64, usize, usize)> = candidates.iter()
+            .map(|candidate| (candidate.diagonal(), candidate.read_start, candidate.start))
+            .collect();
+        diagonals.sort_unstable();
+        diagonals.dedup();
+        let data: Vec<StartScores> = diagonals.into_iter()
+            .map(|(diagonal, read_start, start)| (diagonal, self.forward_algorithm_pos( &sequence[read_start..], start )))
+            .collect();
 
-        Some(HMM::collapse_to_max( start_values, data ))
+        Some(HMM::collapse_to_max( &candidates, data ))
     }
 
-    fn collapse_to_max(start_values: HashSet<usize>, data: Vec<Option<Vec<(String, f64)>>>) 
+    /// The best value per locus over its start candidates as ('locus|diagonal', value) -
+    /// the diagonal is the model position of the first read base (negative if the read overhangs the model start).
+    fn collapse_to_max(candidates: &[StartCandidate], data: Vec<StartScores>) 
         -> Vec<(String, f64)> {
-        let mut max_values: HashMap<String, (f64, usize)> = HashMap::new();
-
-        for (opt_stats, start) in data.into_iter().zip(start_values) {
-            if let Some(stats) = opt_stats {
-                for (key, value) in stats {
-                    // Update the max value for each key
-                    max_values.entry(key)
-                        .and_modify(|e| {
-                            if value > e.0 {
-                                e.0 = value;
-                                e.1 = start;
-                            }
-                        })
-                        .or_insert((value, start));
+        let mut max_values: Vec<Option<(String, f64, i64)>> = Vec::new();
+
+        for (diagonal, opt_stats) in data {
+            let stats = match opt_stats {
+                Some(stats) => stats,
+                None => continue,
+            };
+            max_values.resize(stats.len(), None);
+            for (locus, (key, value)) in stats.into_iter().enumerate() {
+                if !candidates.iter().any(|c| c.locus == locus && c.diagonal() == diagonal) {
+                    continue
+                }
+                // Update the max value for each locus
+                if max_values[locus].as_ref().is_none_or(|best| value > best.1) {
+                    max_values[locus] = Some((key, value, diagonal));
                 }
             }
         }
 
-        // Convert the HashMap back to a Vec<(String, f64)>
-        let res = max_values.into_iter()
-            .map(|(key, (value, start))| (format!("{}|{}", key, start), value))
-            .collect();
-
-        //println!("Stats result: {:?}", res);
-        res
+        max_values.into_iter().flatten()
+            .map(|(key, value, diagonal)| (format!("{}|{}", key, diagonal), value))
+            .collect()
     }
 
+    /// The relative locus scores of the sequence placed at model position start -
+    /// the bases after the end of the model overhang it and are not scored.
     pub fn forward_algorithm_pos(&self, sequence: &[u8], start:usize) -> Option<Vec<(String, f64)>> {
 
-        let num_states = self.states[0].len();
-        let mut sequence_length = sequence.len();
-
-        // do we have enough info in the model to do this:
-        let this_end = self.states.len().min(start + sequence_length );
-
-        if start > this_end {
-            return None
-        }
-
-        let mut alpha = vec![vec![-f64::INFINITY; num_states]; sequence_length];
-        // Initialize the alpha values for the first position
-
-        let first_pos = match Self::char2pos(sequence[0]){
-            Some(pos) => pos,
-            None => {
-                //eprintln!("Bad sequence:")
-                return None
-            }
-        };
-
-        for (state, emission_prob) in self.states[start].prob_for_pos( first_pos ).iter().enumerate(){
-             alpha[0][state] = emission_prob.ln();
-        }
-
-
-        // Recursively compute the alpha values for the rest of the sequence
-        'main: for t in start+1..this_end {
-            let current_pos = match Self::char2pos(sequence[t-start]){
-                Some(pos) => pos,
-                None => return None,
-            };
-            for (state, emission_prob) in self.states[t].prob_for_pos( current_pos ).iter().enumerate(){
-                let mut max_log = -f64::INFINITY;
-                for i in 0..num_states {
-                    max_log = max_log.max(alpha[t - 1-start][i] + self.transition_matrix[i][state].ln());
-                }
-                alpha[t-start][state] = max_log + emission_prob.ln();
-            }
+        let mut ws = DpWorkspace::default();
+        self.locus_scores( sequence, start, &mut ws )?;
 
-        }
-        sequence_length = this_end - start;
         // Compute the final probability in log-space
-        let final_probabilities: Vec<f64> = alpha[sequence_length - 1].clone();
+        let final_probabilities: Vec<f64> = ws.prev.iter().map(|p| log_prob_to_f64(*p)).collect();
         let max_final_prob = final_probabilities.iter().cloned().fold(-f64::INFINITY, f64::max);
-        let total_prob = (max_final_prob).exp() ;
+        //let total_prob = (max_final_prob).exp() ;
 
         // Pair the names with their corresponding probabilities
-        let mut result: Vec<(String, f64)> = self.names.iter()
-            .zip(final_probabilities.iter())
-            .map(|(name, &prob)| (name.name(), (prob - max_final_prob).exp()))
+        let result: Vec<(String, f64)> = final_probabilities.iter()
+            .enumerate()
+            .map(|(i, &prob)| (self.label(i), (prob - max_final_prob).exp()))
             .collect();
         // Add the total probability as the last entry
         //result.push(("Total".to_string(), total_prob));
@@ -346,84 +676,125 @@ impl HMM {
         Some(result)
     }
 
-    /*
-    // Backward algorithm
-    pub fn backward_algorithm(&self, sequence: &[usize]) -> f64 {
-        let num_states = self.states.len();
-        let sequence_length = sequence.len();
+    /// The best path log probability for every locus for the sequence placed at model position start.
+    /// The scores end up in ws.prev; returns the model position the sequence ended at
+    /// (the sequence is truncated at the end of the model) or None for invalid characters.
+    pub fn locus_scores(&self, sequence: &[u8], start:usize, ws: &mut DpWorkspace) -> Option<usize> {
+        self.locus_scores_with( Backend::detect(), sequence, start, ws )
+    }
 
-        let mut beta = vec![vec![0.0; num_states]; sequence_length];
+    /// locus_scores with an explicit SIMD backend - all backends return the same scores
+    pub fn locus_scores_with(&self, backend: Backend, sequence: &[u8], start:usize, ws: &mut DpWorkspace) -> Option<usize> {
 
-        // Initialize the beta values for the last position
-        for state in 0..num_states {
-            beta[sequence_length - 1][state] = 1.0;
+        if sequence.is_empty() || start >= self.states.len() {
+            return None
         }
+        let num_states = self.num_loci();
 
-        // Recursively compute the beta values for the rest of the sequence
-        for t in (0..sequence_length - 1).rev() {
-            for i in 0..num_states {
-                beta[t][i] = 0.0;
-                for j in 0..num_states {
-                    let emission_prob = self.states[t + 1].match_emission[sequence[t + 1]];
-                    beta[t][i] += self.transition_matrix[i][j] * emission_prob[j] * beta[t + 1][j];
-                }
-            }
-        }
+        // do we have enough info in the model to do this:
+        let this_end = self.states.len().min(start + sequence.len() );
+
+        ws.prev.clear();
+        ws.prev.resize(num_states, LogProb::NEG_INFINITY);
+        ws.cur.clear();
+        ws.cur.resize(num_states, LogProb::NEG_INFINITY);
+
+        // Initialize the alpha values for the first position
+        let first_pos = Self::char2pos(sequence[0])?;
+        ws.prev.copy_from_slice( self.log_emissions(start, first_pos) );
 
-        // Compute the final probability
-        let mut prob = 0.0;
-        for state in 0..num_states {
-            let emission_prob = self.states[0].match_emission[sequence[0]];
-            prob += emission_prob[state] * beta[0][state];
+        // Recursively compute the alpha values for the rest of the sequence
+        for t in start+1..this_end {
+            let current_pos = Self::char2pos(sequence[t-start])?;
+            simd::viterbi_step( backend, &ws.prev, &self.log_transition, self.log_emissions(t, current_pos), &mut ws.cur );
+            std::mem::swap( &mut ws.prev, &mut ws.cur );
         }
-        prob
+
+        Some(this_end)
     }
 
-    // Final function to get the most likely sequence of hidden states
-    pub fn most_likely_states(&self, sequence: &[usize]) -> Vec<usize> {
-        let num_states = self.states.len();
-        let sequence_length = sequence.len();
+    /// The forward algorithm on the same model as locus_scores: ln P(sequence, last position in locus) summed over
+    /// all locus paths instead of the best one, for the sequence placed at model position start (truncated at the model end).
+    /// None for invalid characters.
+    pub fn forward_log_probs(&self, sequence: &[u8], start: usize) -> Option<Vec<f64>> {
+        if sequence.is_empty() || start >= self.states.len() {
+            return None
+        }
+        let n = self.num_loci();
+        let end = self.states.len().min(start + sequence.len());
+        let mut alpha: Vec<f64> = self.log_emissions(start, Self::char2pos(sequence[0])?).iter().map(|p| log_prob_to_f64(*p)).collect();
+        let mut next = vec![f64::NEG_INFINITY; n];
+        for t in start + 1..end {
+            let emissions = self.log_emissions(t, Self::char2pos(sequence[t - start])?);
+            for (to, value) in next.iter_mut().enumerate() {
+                let incoming = (0..n).map(|from| alpha[from] + log_prob_to_f64(self.log_transition[from * n + to]));
+                *value = log_sum_exp(incoming) + log_prob_to_f64(emissions[to]);
+            }
+            std::mem::swap(&mut alpha, &mut next);
+        }
+        Some(alpha)
+    }
 
-        let mut delta = vec![vec![0.0; num_states]; sequence_length];
-        let mut psi = vec![vec![0; num_states]; sequence_length];
+    /// ln P(sequence) over all locus paths - the forward_log_probs of all loci summed up
+    pub fn forward_log_prob(&self, sequence: &[u8], start: usize) -> Option<f64> {
+        Some(log_sum_exp(self.forward_log_probs(sequence, start)?.into_iter()))
+    }
 
-        // Initialize delta and psi for the first position
-        for state in 0..num_states {
-            let emission_prob = self.states[0].match_emission[sequence[0]];
-            delta[0][state] = emission_prob[state];
-            psi[0][state] = 0;
+    /// The backward algorithm - the same ln P(sequence) as forward_log_prob, computed from the last position back.
+    pub fn backward_log_prob(&self, sequence: &[u8], start: usize) -> Option<f64> {
+        if sequence.is_empty() || start >= self.states.len() {
+            return None
         }
+        let n = self.num_loci();
+        let end = self.states.len().min(start + sequence.len());
+        let bases: Vec<usize> = sequence[..end - start].iter().map(|base| Self::char2pos(*base)).collect::<Option<Vec<usize>>>()?;
+        let mut beta = vec![0.0; n];
+        let mut prev = vec![f64::NEG_INFINITY; n];
+        for t in (start + 1..end).rev() {
+            let emissions = self.log_emissions(t, bases[t - start]);
+            for (from, value) in prev.iter_mut().enumerate() {
+                let outgoing = (0..n).map(|to| log_prob_to_f64(self.log_transition[from * n + to]) + log_prob_to_f64(emissions[to]) + beta[to]);
+                *value = log_sum_exp(outgoing);
+            }
+            std::mem::swap(&mut beta, &mut prev);
+        }
+        let first = self.log_emissions(start, bases[0]);
+        Some(log_sum_exp((0..n).map(|i| log_prob_to_f64(first[i]) + beta[i])))
+    }
 
-        // Recursively compute delta and psi for the rest of the sequence
-        for t in 1..sequence_length {
-            for j in 0..num_states {
-                let mut max_delta = 0.0;
-                let mut best_state = 0;
-                for i in 0..num_states {
-                    let prob = delta[t - 1][i] * self.transition_matrix[i][j];
-                    if prob > max_delta {
-                        max_delta = prob;
-                        best_state = i;
+    /// The locus of every position of the best path ending in end_locus for the sequence placed at model position start
+    /// (the sequence is truncated at the end of the model) - the path locus_scores scored. None for invalid characters.
+    /// On ties the path stays in its locus, so it only switches if switching scores better.
+    pub fn viterbi_path(&self, sequence: &[u8], start: usize, end_locus: usize) -> Option<Vec<usize>> {
+        if sequence.is_empty() || start >= self.states.len() || end_locus >= self.num_loci() {
+            return None
+        }
+        let n = self.num_loci();
+        let end = self.states.len().min(start + sequence.len());
+        let mut delta: Vec<f64> = self.log_emissions(start, Self::char2pos(sequence[0])?).iter().map(|p| log_prob_to_f64(*p)).collect();
+        let mut next = vec![f64::NEG_INFINITY; n];
+        // the best previous locus for every position after the first
+        let mut back: Vec<usize> = Vec::with_capacity((end - start - 1) * n);
+        for t in start + 1..end {
+            let emissions = self.log_emissions(t, Self::char2pos(sequence[t - start])?);
+            for (to, value) in next.iter_mut().enumerate() {
+                let mut best = (to, delta[to] + log_prob_to_f64(self.log_transition[to * n + to]));
+                for (from, score) in delta.iter().enumerate() {
+                    let score = score + log_prob_to_f64(self.log_transition[from * n + to]);
+                    if score > best.1 {
+                        best = (from, score);
                     }
                 }
-                delta[t][j] = max_delta * self.states[t].match_emission[sequence[t]][j];
-                psi[t][j] = best_state;
+                back.push(best.0);
+                *value = best.1 + log_prob_to_f64(emissions[to]);
             }
+            std::mem::swap(&mut delta, &mut next);
         }
-
-        // Backtrack to find the most likely sequence of states
-        let mut most_likely_states = vec![0; sequence_length];
-        let mut last_state = delta[sequence_length - 1].iter().enumerate().max_by(|a, b| a.1.partial_cmp(b.1).unwrap()).unwrap().0;
-        most_likely_states[sequence_length - 1] = last_state;
-
-        for t in (0..sequence_length - 1).rev() {
-            last_state = psi[t + 1][last_state];
-            most_likely_states[t] = last_state;
+        let mut ret = vec![end_locus; end - start];
+        for t in (1..ret.len()).rev() {
+            ret[t - 1] = back[(t - 1) * n + ret[t]];
         }
-
-        most_likely_states
+        Some(ret)
     }
-    */
-    // Placeholder for functions like forward_algorithm, viterbi, etc.
 }
 
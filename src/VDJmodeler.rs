@@ -1,10 +1,12 @@
 //VDHmodeler.rs
 
 use crate::HMM::HMM;
-//use crate::fasta_reader::{FastaRecord, FastaReader};
-use needletail::parse_fastx_file;
+use crate::germline::{GermlineRecord, read_germline_database};
+use crate::errors::{HmmError, Result};
+use crate::priors::EmissionConfig;
 use std::collections::HashSet;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Chain{
 	V,
 	D,
@@ -20,24 +22,34 @@ impl Chain{
 		}
 	}
 
-	pub fn starts_at(&self, mode:&str, data:&Vec<usize>) -> usize{
+	/// the AIRR sequence_type / segment column: V, D or J (C and others are not modeled)
+	pub fn from_segment(segment:&str) -> Option<Self>{
+		match segment.trim().to_ascii_uppercase().as_str(){
+			"V" => Some(Chain::V),
+			"D" => Some(Chain::D),
+			"J" => Some(Chain::J),
+			_ => None,
+		}
+	}
+
+	pub fn starts_at(&self, mode:&str, data:&[usize], len:usize) -> Result<usize>{
 		match mode{
 			"HeavyChain" => {
 				match self{
-					Chain::V => 0,
-					Chain::D => data[0],
-					Chain::J => data[1],
+					Chain::V => Ok(0),
+					Chain::D => Ok(data[0]),
+					// J follows the longest V and the longest D and ends with the model
+					Chain::J => Ok(data[0] + data[1] + data[2] - len),
 				}
 			},
 			"LightChain" => {
 				match self{
-					Chain::V => 0,
-					Chain::D => panic!("A light chain has no D segement!"),
-					Chain::J => data[0],
-					_ => unreachable!(),
+					Chain::V => Ok(0),
+					Chain::D => Err(HmmError::InconsistentModel("A light chain has no D segement!".to_string())),
+					Chain::J => Ok(data[0] + data[2] - len),
 				}
 			},
-			_ => unreachable!(),
+			other => Err(HmmError::InconsistentModel(format!("unknown chain mode {other}"))),
 		}
 	}
 }
@@ -64,7 +76,6 @@ impl SequenceModel {
 			SequenceModel::TRB => 4,
 			SequenceModel::TRG => 5,
 			SequenceModel::TRD => 6,
-			_ => unreachable!(),
 		}
 	}
 
@@ -86,17 +97,30 @@ impl SequenceModel {
     }
 
 
-	pub fn has_data(&self, data:&Vec<usize> ) -> bool{
+	/// the locus as used by AIRR and OGRDB e.g. 'IGH' or 'TRB'
+	pub fn from_locus(locus:&str) -> Option<Self> {
+		match locus.trim().to_ascii_uppercase().as_str() {
+			"IGH" => Some(SequenceModel::IGH),
+			"IGL" => Some(SequenceModel::IGL),
+			"IGK" => Some(SequenceModel::IGK),
+			"TRA" => Some(SequenceModel::TRA),
+			"TRB" => Some(SequenceModel::TRB),
+			"TRG" => Some(SequenceModel::TRG),
+			"TRD" => Some(SequenceModel::TRD),
+			_ => None,
+		}
+	}
+
+	pub fn has_data(&self, data:&[usize] ) -> bool{
 		match self {
-            SequenceModel::IGH| SequenceModel::TRA | SequenceModel::TRG => {
+            SequenceModel::IGH | SequenceModel::TRB | SequenceModel::TRD => {
                 // Check for HeavyChain types
                 data[0] != 0 && data[1] != 0 && data[2] != 0
             },
-            SequenceModel::IGL | SequenceModel::IGK | SequenceModel::TRB | SequenceModel::TRD => {
+            SequenceModel::IGL | SequenceModel::IGK | SequenceModel::TRA | SequenceModel::TRG => {
                 // Check for LightChain types
                 data[0] != 0 && data[2] != 0
             },
-            _ => unreachable!(),
         }
 	}
 
@@ -109,19 +133,17 @@ impl SequenceModel {
 			SequenceModel::TRB => "TRB-VDJ".to_string(),
 			SequenceModel::TRG => "TRG-VDJ".to_string(),
 			SequenceModel::TRD => "TRD-VDJ".to_string(),
-			_ => unreachable!(),
 		}
 	}
 
-	pub fn starts_at(&self, chain:&Chain, data:&Vec<usize> ) -> usize{
+	pub fn starts_at(&self, chain:&Chain, data:&[usize], len:usize ) -> Result<usize>{
 		match self{
 			SequenceModel::IGH | SequenceModel::TRB | SequenceModel::TRD => {
-				chain.starts_at( "HeavyChain", data) 
+				chain.starts_at( "HeavyChain", data, len) 
 			},
 			SequenceModel::IGL | SequenceModel::IGK | SequenceModel::TRA | SequenceModel::TRG => {
-				chain.starts_at( "LightChain", data)
+				chain.starts_at( "LightChain", data, len)
 			},
-			_ => unreachable!(),
 		}
 	}
 
@@ -141,18 +163,35 @@ impl Default for HMMcollector{
     }
 }
 
+/// A germline sequence and the model position it was placed at.
+/// Kept with the model to call the most likely V, D and J genes for a hit.
+#[derive(Clone, Debug)]
+pub struct PlacedGermline {
+	pub gene:String,
+	pub segment:Chain,
+	/// the model position the first base of the (gapped) sequence was added to
+	pub start:usize,
+	pub sequence:Vec<u8>,
+}
+
 #[derive(Clone)]
 pub struct HMMmodel {
 	pub name:SequenceModel,
+	/// the species this model was built from - empty if the database was not species-tagged
+	pub species:String,
 	pub collector:Vec<HMMcollector>,
+	/// the germline sequences this model was built from
+	pub germlines:Vec<PlacedGermline>,
 }
 
 impl HMMmodel{
-	pub fn new( name:SequenceModel, size:usize) -> Self{
+	pub fn new( name:SequenceModel, species:&str, size:usize) -> Self{
 		let collector = vec![HMMcollector::default(); size];
 		Self{
 			name,
+			species: species.to_string(),
 			collector,
+			germlines: Vec::new(),
 		}
 	}
 
@@ -163,20 +202,22 @@ impl HMMmodel{
 		self.collector[pos].states[HMM::char2pos(value)] +=1;
 	}*/
 
-	pub fn consume(&mut self, model: SequenceModel, start_at:usize, seq:&[u8] ) -> bool{
+	pub fn consume(&mut self, model: SequenceModel, start_at:usize, seq:&[u8] ) -> Result<bool>{
 		if model != self.name {
-			return false
+			Ok(false)
 		}else {
 			if self.collector.len() < start_at + seq.len() {
-				panic!("Library was not initialized correctly - len {} is smaller than pos {}", self.collector.len(), start_at + seq.len() );
+				return Err(HmmError::InconsistentModel(format!(
+					"Library was not initialized correctly - len {} is smaller than pos {}", self.collector.len(), start_at + seq.len() 
+				)));
 			}
 			for (pos, value) in seq.iter().enumerate(){
-				for id in HMM::iupac_char2pos(*value){
+				for id in HMM::iupac_char2pos(*value)?{
 					self.collector[pos+start_at].states[ id ] +=1;
 				}
 				
 			}
-			return true
+			Ok(true)
 		}
 	}
 
@@ -196,14 +237,11 @@ pub fn identify_model_type(record: &str) -> Option<(SequenceModel, Chain)> {
     if name.contains("IGHV") {
         return Some((SequenceModel::IGH, Chain::V));
     } else if let Some(ighd_pos) = name.find("IGHD") {
-    	// Check if the 5th character after "IGHD" exists and is a digit
-    	name.chars()
+    	// IGHD1-1 is a D segment, IGHD*01 the delta constant gene - that one is not modelled
+    	return name.chars()
     		.nth(ighd_pos + 4)
-    		.filter(|&c| c.is_digit(10)) // will return None if not a digit
-            .map(|_| (SequenceModel::IGH, Chain::D)); {
-            return Some((SequenceModel::IGH, Chain::D));
-        }
-        // if no - the function will return None later on.
+    		.filter(|&c| c.is_ascii_digit()) // will return None if not a digit
+            .map(|_| (SequenceModel::IGH, Chain::D));
     } else if name.contains("IGHJ") {
         return Some((SequenceModel::IGH, Chain::J));
     } else if name.contains("IGLV") {
@@ -247,37 +285,68 @@ pub fn identify_model_type(record: &str) -> Option<(SequenceModel, Chain)> {
     None // If no match found, return None
 }
 
-	pub fn build_models(fasta: String) -> HMM {
+	/// Split a species-tagged database path 'human=IMGT_human.fa' into ("human", "IMGT_human.fa").
+	/// Untagged paths get the empty species "".
+	pub fn parse_database_arg(arg: &str) -> (String, String) {
+		match arg.split_once('=') {
+			Some((species, path)) if !species.is_empty() && !species.contains('/') => {
+				(species.to_string(), path.to_string())
+			},
+			_ => (String::new(), arg.to_string()),
+		}
+	}
+
+	pub fn build_models(fasta: String) -> Result<HMM> {
+		Self::build_species_models( &[(String::new(), fasta)] )
+	}
+
+	/// Build one combined HMM from several (species, database) pairs.
+	/// Every species gets its own set of locus models so that reads can be assigned to a species later on.
+	/// Databases sharing a species (e.g. IMGT plus the novel alleles inferred for a donor) are merged.
+	/// The database format (IMGT/OGRDB fasta, AIRR-C JSON or TSV) is chosen by file extension.
+	pub fn build_species_models(databases: &[(String, String)]) -> Result<HMM> {
+		Self::build_species_models_with( databases, &EmissionConfig::default() )
+	}
+
+	/// build_species_models with these emission priors and sequence weights
+	pub fn build_species_models_with(databases: &[(String, String)], config: &EmissionConfig) -> Result<HMM> {
+		Ok(HMM::from_sequence_models_with( Self::collect_species_models( databases )?, config ))
+	}
+
+	/// The locus models build_species_models combines - they still have the per position base counts.
+	pub fn collect_species_models(databases: &[(String, String)]) -> Result<Vec<HMMmodel>> {
+		let mut per_species: Vec<(String, Vec<GermlineRecord>)> = Vec::new();
+		for (species, path) in databases {
+			let records = read_germline_database( path )?;
+			match per_species.iter_mut().find(|(s, _)| s == species) {
+				Some((_, all)) => all.extend(records),
+				None => per_species.push((species.clone(), records)),
+			}
+		}
+		let mut good_models: Vec<HMMmodel> = Vec::new();
+		for (species, records) in &per_species {
+			good_models.extend( Self::collect_models( species, records )? );
+		}
+		if good_models.is_empty() {
+			return Err(HmmError::InconsistentModel(
+				"none of the databases contains the V and J segments needed to model a locus".to_string()
+			));
+		}
+		Ok(good_models)
+	}
+
+	/// Build the locus models for one species from its germline records.
+	pub fn collect_models(species: &str, records: &[GermlineRecord]) -> Result<Vec<HMMmodel>> {
 
-		let mut reader = match parse_fastx_file(&fasta) {
-        	Ok(reader) => reader,
-        	Err(err) => {
-            	panic!("File {fasta} Read Error: {}", err);
-        	}
-   		};
 	    let mut models: Vec<Option<HMMmodel>> = vec![None; SequenceModel::length()];
-	    let mut sequences = Vec::new();
 
 	    // Initialize the full_matrix with the correct size
 	    let mut full_matrix = vec![vec![0; 3]; SequenceModel::length()];
 
-	    // Collect sequences and update the full_matrix with max lengths
-	    while let Some(record) = reader.next() {
-	    	let read = match record{
-	                Ok( res ) => res,
-	                Err(err) => {
-	                    eprintln!("could not read from fasta:\n{err}");
-	                    continue
-	                }
-	            };
-	        let seq = read.seq().into_owned();
-	        let tmp = &read.id().to_owned();
-	        let acc = String::from_utf8_lossy(tmp);
-	        if let Some(ids) = VDJmodeler::identify_model_type(&acc) {
-	            let (model_id, chain_id) = ids;
-	            full_matrix[model_id.id()][chain_id.id()] = full_matrix[model_id.id()][chain_id.id()].max(seq.len());
-	            sequences.push(((model_id, chain_id), seq ));
-	        }
+	    // Update the full_matrix with max lengths
+	    for record in records {
+	        let (model_id, chain_id) = (record.locus.clone(), record.segment);
+	        full_matrix[model_id.id()][chain_id.id()] = full_matrix[model_id.id()][chain_id.id()].max(record.sequence.len());
 	    }
 
 	    // Identify SequenceModels that have sufficient data
@@ -286,28 +355,43 @@ pub fn identify_model_type(record: &str) -> Option<(SequenceModel, Chain)> {
 	        if let Some(seq_mod) = SequenceModel::from_index(id) {
 	            if seq_mod.has_data(data) {
 	                with_data.insert(seq_mod.clone());
-	                let mut hmm_model = HMMmodel::new(seq_mod.clone(), data.iter().sum());
+	                let hmm_model = HMMmodel::new(seq_mod.clone(), species, data.iter().sum());
 	                models[seq_mod.id()] = Some(hmm_model);
 	            }
 	        }
 	    }
-	    println!("We have found these sequences that can be modeled: {:?}", with_data);
+	    if species.is_empty() {
+	    	eprintln!("We have found these sequences that can be modeled: {:?}", with_data);
+	    }else {
+	    	eprintln!("We have found these {species} sequences that can be modeled: {:?}", with_data);
+	    }
 
 	    // Populate the HMM models with sequence data
-	    for ((model, chain), seq) in &sequences {
+	    for record in records {
+	        let model = &record.locus;
 	        if with_data.contains(model) {
 	            if let Some(hmm_model) = models[model.id()].as_mut() {
+	                let start = model.starts_at(&record.segment, &full_matrix[model.id()], record.sequence.len())
+	                    .map_err(|err| match err {
+	                        HmmError::InconsistentModel(msg) => HmmError::InconsistentModel(format!("{}: {msg}", record.gene)),
+	                        other => other,
+	                    })?;
 	                hmm_model.consume(
 	                    model.clone(),
-	                    model.starts_at(chain, &full_matrix[model.id()]),
-	                    seq,
-	                );
+	                    start,
+	                    &record.sequence,
+	                )?;
+	                hmm_model.germlines.push( PlacedGermline{
+	                    gene: record.gene.clone(),
+	                    segment: record.segment,
+	                    start,
+	                    sequence: record.sequence.clone(),
+	                });
 	            }
 	        }
 	    }
 
 	    // Collect and return the HMM models
-	    let good_models: Vec<HMMmodel> = models.into_iter().filter_map(|model| model).collect();
-	    HMM::from_sequence_models(good_models)
+	    Ok(models.into_iter().flatten().collect())
 	}
 }
\ No newline at end of file
@@ -0,0 +1,382 @@
+// banded.rs
+
+use crate::HMM::{log_prob_to_f64, BACKGROUND, HMM};
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The log probabilities of the insertion and deletion moves of the gapped alignment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GapModel {
+    /// ln probability to open an insertion or deletion
+    pub open: f64,
+    /// ln probability to extend an open insertion or deletion
+    pub extend: f64,
+    /// ln probability to open an insertion repeating the read base before it (a longer homopolymer run)
+    pub homopolymer_open: f64,
+    /// ln emission probability of an inserted read base
+    pub insert_emission: f64,
+    /// let a deletion skip a model position with the IMGT gap frequency of its germlines (HMM::gap_frequency)
+    /// if that is more likely than open or extend - reads do not have the gaps of the IMGT numbering
+    pub profile_gaps: bool,
+    /// ln probability of a soft clipped read base (the background model)
+    pub clip: f64,
+}
+
+impl Default for GapModel {
+    /// short read defaults: indels are rare
+    fn default() -> Self {
+        Self {
+            open: 0.001_f64.ln(),
+            extend: 0.1_f64.ln(),
+            homopolymer_open: 0.001_f64.ln(),
+            insert_emission: 0.25_f64.ln(),
+            profile_gaps: false,
+            clip: 0.25_f64.ln(),
+        }
+    }
+}
+
+impl GapModel {
+    /// Nanopore and PacBio CLR reads: indels are about as common as substitutions and
+    /// most insertions lengthen a homopolymer run.
+    pub fn long_reads() -> Self {
+        Self {
+            open: 0.02_f64.ln(),
+            extend: 0.3_f64.ln(),
+            homopolymer_open: 0.1_f64.ln(),
+            profile_gaps: true,
+            ..Self::default()
+        }
+    }
+}
+
+/// A CIGAR string as (length, operation) pairs with the operations M, I, D and S.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cigar(pub Vec<(u32, char)>);
+
+impl Cigar {
+    /// append len operations, merging them with the last operation if it is the same
+    pub fn push(&mut self, op: char, len: u32) {
+        if len == 0 {
+            return
+        }
+        match self.0.last_mut() {
+            Some((n, last)) if *last == op => *n += len,
+            _ => self.0.push((len, op)),
+        }
+    }
+
+    /// the number of read bases the CIGAR covers (M, I and S)
+    pub fn read_len(&self) -> usize {
+        self.0.iter().filter(|(_, op)| matches!(op, 'M' | 'I' | 'S')).map(|(n, _)| *n as usize).sum()
+    }
+
+    /// the number of model positions the CIGAR covers (M and D)
+    pub fn model_len(&self) -> usize {
+        self.0.iter().filter(|(_, op)| matches!(op, 'M' | 'D')).map(|(n, _)| *n as usize).sum()
+    }
+
+    /// the same alignment read from the other end
+    pub fn reversed(&self) -> Self {
+        Cigar(self.0.iter().rev().cloned().collect())
+    }
+}
+
+impl fmt::Display for Cigar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (n, op) in &self.0 {
+            write!(f, "{n}{op}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The best gapped alignment of a read against one locus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alignment {
+    pub score: f64,
+    /// the aligned read interval [read_start, read_end) - the rest of the read is soft clipped
+    pub read_start: usize,
+    pub read_end: usize,
+    /// the model interval [model_start, model_end)
+    pub model_start: usize,
+    pub model_end: usize,
+    pub cigar: Cigar,
+    /// false if the band was left and the full DP had to be used
+    pub banded: bool,
+}
+
+/// How often the banded alignment had to fall back to the full DP - shared between scanner clones.
+#[derive(Debug, Default)]
+pub struct BandStats {
+    pub alignments: AtomicUsize,
+    pub fallbacks: AtomicUsize,
+}
+
+impl BandStats {
+    pub fn summary(&self) -> String {
+        format!(
+            "Banded alignment: {} alignments, {} fell back to the full DP",
+            self.alignments.load(Ordering::Relaxed),
+            self.fallbacks.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Reusable buffers for the gapped alignment - one per thread.
+#[derive(Clone, Default)]
+pub struct AlignWorkspace {
+    /// the read bases as emission indices (None for N and other non ACGT characters)
+    bases: Vec<Option<usize>>,
+    profile_gaps: Vec<f64>,
+    m_prev: Vec<f64>,
+    i_prev: Vec<f64>,
+    d_prev: Vec<f64>,
+    m_cur: Vec<f64>,
+    i_cur: Vec<f64>,
+    d_cur: Vec<f64>,
+    traceback: Vec<u8>,
+    /// the operations of the traceback, last first
+    ops: Vec<char>,
+}
+
+// traceback bits per cell
+const M_FROM_START: u8 = 0;
+const M_FROM_M: u8 = 1;
+const M_FROM_I: u8 = 2;
+const M_FROM_D: u8 = 3;
+const I_EXTEND: u8 = 4;
+const D_EXTEND: u8 = 8;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    M,
+    I,
+    D,
+}
+
+/// The DP columns a read position may use: [lo(i), lo(i) + width) intersected with the model.
+struct Window {
+    /// the column of read position 0 relative to its diagonal (diag - band) - 0 for the full DP
+    offset: i64,
+    banded: bool,
+    width: usize,
+}
+
+impl Window {
+    fn lo(&self, i: usize) -> i64 {
+        if self.banded { i as i64 + self.offset } else { 0 }
+    }
+}
+
+/// Align sequence against locus with the model position of read base 0 within band of diagonal.
+/// The alignment falls back to the full DP if its path touches the band edge.
+#[allow(clippy::too_many_arguments)]
+pub fn align_banded(hmm: &HMM, locus: usize, sequence: &[u8], diagonal: i64, band: usize, gaps: &GapModel, stats: Option<&BandStats>, ws: &mut AlignWorkspace) -> Option<Alignment> {
+    if let Some(stats) = stats {
+        stats.alignments.fetch_add(1, Ordering::Relaxed);
+    }
+    let window = Window { offset: diagonal - band as i64, banded: true, width: 2 * band + 1 };
+    match align_window(hmm, locus, sequence, &window, gaps, ws)? {
+        (alignment, false) => Some(alignment),
+        (_, true) => {
+            if let Some(stats) = stats {
+                stats.fallbacks.fetch_add(1, Ordering::Relaxed);
+            }
+            align_full(hmm, locus, sequence, gaps, ws)
+        },
+    }
+}
+
+/// Align sequence against locus using all model positions.
+pub fn align_full(hmm: &HMM, locus: usize, sequence: &[u8], gaps: &GapModel, ws: &mut AlignWorkspace) -> Option<Alignment> {
+    if locus >= hmm.num_loci() {
+        return None
+    }
+    let window = Window { offset: 0, banded: false, width: hmm.locus_len(locus) };
+    align_window(hmm, locus, sequence, &window, gaps, ws).map(|(alignment, _)| alignment)
+}
+
+/// The Viterbi alignment with match, insert and delete states; read bases outside the alignment are soft clipped.
+/// The alignment ends with the profile of the locus, read bases overhanging it are clipped.
+/// N and other non ACGT bases match every model position with the background probability.
+/// Returns the alignment and whether its path touches the edge of a band.
+fn align_window(hmm: &HMM, locus: usize, sequence: &[u8], window: &Window, gaps: &GapModel, ws: &mut AlignWorkspace) -> Option<(Alignment, bool)> {
+    if locus >= hmm.num_loci() {
+        return None
+    }
+    let model_len = hmm.locus_len(locus) as i64;
+    let n = sequence.len();
+    let width = window.width;
+    if n == 0 || width == 0 || model_len == 0 {
+        return None
+    }
+    let AlignWorkspace { bases, profile_gaps, m_prev, i_prev, d_prev, m_cur, i_cur, d_cur, traceback, ops } = ws;
+    bases.clear();
+    bases.extend(sequence.iter().map(|base| HMM::char2pos(*base)));
+    // the ln probability to skip every model position
+    profile_gaps.clear();
+    profile_gaps.extend((0..model_len as usize)
+        .map(|j| if gaps.profile_gaps { hmm.gap_frequency(j, locus).ln() } else { f64::NEG_INFINITY }));
+
+    let neg = f64::NEG_INFINITY;
+    for buffer in [&mut *m_prev, &mut *i_prev, &mut *d_prev, &mut *m_cur, &mut *i_cur, &mut *d_cur] {
+        buffer.clear();
+        buffer.resize(width, neg);
+    }
+    traceback.clear();
+    traceback.resize(n * width, 0);
+
+    // (score, read position, column)
+    let mut best: Option<(f64, usize, usize)> = None;
+
+    for (i, base) in bases.iter().enumerate() {
+        let lo = window.lo(i);
+        let lo_prev = if i > 0 { window.lo(i - 1) } else { lo };
+        let start_score = i as f64 * gaps.clip;
+        let end_clip = (n - 1 - i) as f64 * gaps.clip;
+        for k in 0..width {
+            let j = lo + k as i64;
+            let cell = i * width + k;
+            if j < 0 || j >= model_len {
+                m_cur[k] = neg;
+                i_cur[k] = neg;
+                d_cur[k] = neg;
+                continue
+            }
+            let mut tb = 0;
+
+            // match: start here or continue the diagonal
+            let mut from = (start_score, M_FROM_START);
+            if i > 0 {
+                if let Some(kp) = column(j - 1, lo_prev, width) {
+                    for (score, source) in [(m_prev[kp], M_FROM_M), (i_prev[kp], M_FROM_I), (d_prev[kp], M_FROM_D)] {
+                        if score > from.0 {
+                            from = (score, source);
+                        }
+                    }
+                }
+            }
+            let emission = match base {
+                Some(base) => log_prob_to_f64(hmm.log_emissions(j as usize, *base)[locus]),
+                None => BACKGROUND.ln(),
+            };
+            m_cur[k] = from.0 + emission;
+            tb |= from.1;
+
+            // insertion: read base i without a model position
+            i_cur[k] = neg;
+            if i > 0 {
+                if let Some(kp) = column(j, lo_prev, width) {
+                    let open_cost = if base.is_some() && bases[i - 1] == *base { gaps.homopolymer_open } else { gaps.open };
+                    let open = m_prev[kp] + open_cost;
+                    let extend = i_prev[kp] + gaps.extend;
+                    if extend > open {
+                        i_cur[k] = extend + gaps.insert_emission;
+                        tb |= I_EXTEND;
+                    } else {
+                        i_cur[k] = open + gaps.insert_emission;
+                    }
+                }
+            }
+
+            // deletion: model position j without a read base
+            d_cur[k] = neg;
+            if k > 0 {
+                let open = m_cur[k - 1] + gaps.open.max(profile_gaps[j as usize]);
+                let extend = d_cur[k - 1] + gaps.extend.max(profile_gaps[j as usize]);
+                if extend > open {
+                    d_cur[k] = extend;
+                    tb |= D_EXTEND;
+                } else {
+                    d_cur[k] = open;
+                }
+            }
+
+            traceback[cell] = tb;
+            let end = m_cur[k] + end_clip;
+            if best.is_none_or(|b| end > b.0) {
+                best = Some((end, i, k));
+            }
+        }
+        std::mem::swap(m_prev, m_cur);
+        std::mem::swap(i_prev, i_cur);
+        std::mem::swap(d_prev, d_cur);
+    }
+
+    let (score, end_i, end_k) = best?;
+    if score == neg {
+        return None
+    }
+
+    // traceback
+    ops.clear();
+    let (mut i, mut k, mut state) = (end_i, end_k, State::M);
+    let model_end = (window.lo(end_i) + end_k as i64) as usize + 1;
+    let mut touched_edge = false;
+    let read_start;
+    let model_start;
+    loop {
+        let lo = window.lo(i);
+        let j = lo + k as i64;
+        if window.banded && ((k == 0 && j > 0) || (k == width - 1 && j < model_len - 1)) {
+            touched_edge = true;
+        }
+        let tb = traceback[i * width + k];
+        match state {
+            State::M => {
+                ops.push('M');
+                let source = tb & 3;
+                if source == M_FROM_START {
+                    read_start = i;
+                    model_start = j as usize;
+                    break
+                }
+                let lo_prev = window.lo(i - 1);
+                k = column(j - 1, lo_prev, width)?;
+                i -= 1;
+                state = match source {
+                    M_FROM_M => State::M,
+                    M_FROM_I => State::I,
+                    _ => State::D,
+                };
+            },
+            State::I => {
+                ops.push('I');
+                state = if tb & I_EXTEND != 0 { State::I } else { State::M };
+                let lo_prev = window.lo(i - 1);
+                k = column(j, lo_prev, width)?;
+                i -= 1;
+            },
+            State::D => {
+                ops.push('D');
+                state = if tb & D_EXTEND != 0 { State::D } else { State::M };
+                k -= 1;
+            },
+        }
+    }
+
+    let mut cigar = Cigar::default();
+    cigar.push('S', read_start as u32);
+    for op in ops.iter().rev() {
+        cigar.push(*op, 1);
+    }
+    cigar.push('S', (n - end_i - 1) as u32);
+
+    Some((Alignment {
+        score,
+        read_start,
+        read_end: end_i + 1,
+        model_start,
+        model_end,
+        cigar,
+        banded: window.banded,
+    }, touched_edge))
+}
+
+/// the index of model column j in a row starting at column lo
+fn column(j: i64, lo: i64, width: usize) -> Option<usize> {
+    let k = j - lo;
+    if k >= 0 && (k as usize) < width { Some(k as usize) } else { None }
+}
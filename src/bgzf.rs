@@ -0,0 +1,95 @@
+// bgzf.rs
+
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+
+use std::io::{self, Write};
+
+/// the maximum uncompressed size of one block - small enough that the compressed block always fits into 64 KiB
+const BLOCK_SIZE: usize = 0xff00;
+
+/// the empty block marking the end of a BGZF file
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+    0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// A blocked gzip (BGZF) writer as used by samtools/htslib.
+/// The output is a valid multi member gzip file that can also be indexed (e.g. with samtools faidx or tabix).
+/// Call finish to write the end of file marker - dropping the writer only flushes the data.
+pub struct BgzfWriter<W: Write> {
+    inner: Option<W>,
+    buffer: Vec<u8>,
+    compression: Compression,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner: Some(inner),
+            buffer: Vec::with_capacity(BLOCK_SIZE),
+            compression: Compression::default(),
+        }
+    }
+
+    /// Compress the buffered data into one block - also called whenever a block is full.
+    pub fn flush_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(())
+        }
+        let mut encoder = DeflateEncoder::new(Vec::with_capacity(self.buffer.len()), self.compression);
+        encoder.write_all(&self.buffer)?;
+        let compressed = encoder.finish()?;
+        let mut crc = Crc::new();
+        crc.update(&self.buffer);
+
+        // header (18 bytes) + data + crc32 + isize
+        let block_size = 18 + compressed.len() + 8;
+        let bsize = u16::try_from(block_size - 1)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "BGZF block does not fit into 64 KiB"))?;
+        let inner = self.inner.as_mut().expect("BGZF writer used after finish");
+        inner.write_all(&[0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 0x06, 0x00, b'B', b'C', 0x02, 0x00])?;
+        inner.write_all(&bsize.to_le_bytes())?;
+        inner.write_all(&compressed)?;
+        inner.write_all(&crc.sum().to_le_bytes())?;
+        inner.write_all(&(self.buffer.len() as u32).to_le_bytes())?;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Write the last block and the end of file marker and return the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_block()?;
+        let mut inner = self.inner.take().expect("BGZF writer used after finish");
+        inner.write_all(&EOF_BLOCK)?;
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_block()?;
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.flush_block();
+        }
+    }
+}
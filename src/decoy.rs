@@ -0,0 +1,92 @@
+// decoy.rs
+
+use std::sync::Mutex;
+
+/// How the decoy version of a read is made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecoyKind {
+    /// the read reversed (not complemented) - same composition, no biological orientation
+    Reverse,
+    /// the read bases in random order - the order only depends on the read, so reruns give the same decoys
+    Shuffle,
+}
+
+impl DecoyKind {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "reverse" => Some(DecoyKind::Reverse),
+            "shuffle" => Some(DecoyKind::Shuffle),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DecoyKind::Reverse => "reverse",
+            DecoyKind::Shuffle => "shuffle",
+        }
+    }
+
+    /// write the decoy of sequence into ret
+    pub fn decoy(&self, sequence: &[u8], ret: &mut Vec<u8>) {
+        ret.clear();
+        match self {
+            DecoyKind::Reverse => ret.extend(sequence.iter().rev()),
+            DecoyKind::Shuffle => {
+                ret.extend_from_slice(sequence);
+                // FNV-1a of the read seeds the generator
+                let mut state = sequence.iter().fold(0xcbf29ce484222325u64, |hash, base| (hash ^ *base as u64).wrapping_mul(0x100000001b3));
+                for i in (1..ret.len()).rev() {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    ret.swap(i, (state >> 33) as usize % (i + 1));
+                }
+            },
+        }
+    }
+}
+
+/// The log odds of the best decoy hit of every scanned read - shared between scanner clones.
+#[derive(Debug, Default)]
+pub struct DecoyScores {
+    scores: Mutex<Vec<f64>>,
+}
+
+impl DecoyScores {
+    pub fn add(&self, score: f64) {
+        self.scores.lock().expect("decoy scores lock").push(score);
+    }
+
+    pub fn scores(&self) -> Vec<f64> {
+        self.scores.lock().expect("decoy scores lock").clone()
+    }
+}
+
+/// The target-decoy q-value of every target score: the lowest estimated FDR (decoys / targets scoring at least as well)
+/// of any threshold that still accepts the target. NaN scores are ranked last.
+pub fn q_values(targets: &[f64], decoys: &[f64]) -> Vec<f64> {
+    let rank = |score: f64| if score.is_nan() { f64::NEG_INFINITY } else { score };
+    let mut decoys: Vec<f64> = decoys.iter().map(|score| rank(*score)).collect();
+    decoys.sort_by(f64::total_cmp);
+    let mut order: Vec<usize> = (0..targets.len()).collect();
+    order.sort_by(|a, b| rank(targets[*b]).total_cmp(&rank(targets[*a])));
+
+    let mut ret = vec![1.0; targets.len()];
+    let mut i = 0;
+    while i < order.len() {
+        // all targets tied with this one
+        let score = rank(targets[order[i]]);
+        let end = i + order[i..].iter().take_while(|k| rank(targets[**k]) == score).count();
+        let decoys_above = decoys.len() - decoys.partition_point(|d| *d < score);
+        let fdr = (decoys_above as f64 / end as f64).min(1.0);
+        for k in &order[i..end] {
+            ret[*k] = fdr;
+        }
+        i = end;
+    }
+    let mut lowest: f64 = 1.0;
+    for k in order.iter().rev() {
+        lowest = lowest.min(ret[*k]);
+        ret[*k] = lowest;
+    }
+    ret
+}
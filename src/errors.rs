@@ -0,0 +1,83 @@
+// errors.rs
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// The errors the library code can return instead of aborting the process.
+#[derive(Debug)]
+pub enum HmmError {
+    /// a file could not be opened, read or written
+    Io { path: String, source: io::Error },
+    /// a database entry could not be parsed
+    Parse {
+        file: String,
+        /// the line in the file (starting with 1) if known
+        line: Option<u64>,
+        /// the header / gene name of the entry if known
+        header: Option<String>,
+        message: String,
+    },
+    /// a character that is neither a base nor an IUPAC code
+    UnsupportedCharacter { character: char, context: String },
+    /// the model definition does not fit together, e.g. a D segment for a light chain locus
+    InconsistentModel(String),
+}
+
+pub type Result<T> = std::result::Result<T, HmmError>;
+
+impl HmmError {
+    pub fn io(path: &str, source: io::Error) -> Self {
+        HmmError::Io { path: path.to_string(), source }
+    }
+
+    pub fn parse(file: &str, line: Option<u64>, header: Option<&str>, message: impl Into<String>) -> Self {
+        HmmError::Parse {
+            file: file.to_string(),
+            line,
+            header: header.map(str::to_string),
+            message: message.into(),
+        }
+    }
+
+    /// convert a needletail fasta/fastq parse error keeping the file, line and record id
+    pub fn from_needletail(file: &str, err: needletail::errors::ParseError) -> Self {
+        HmmError::Parse {
+            file: file.to_string(),
+            line: if err.position.line > 0 { Some(err.position.line) } else { None },
+            header: err.position.id.clone(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for HmmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HmmError::Io { path, source } => write!(f, "I/O error on {path}: {source}"),
+            HmmError::Parse { file, line, header, message } => {
+                write!(f, "parse error in {file}")?;
+                if let Some(line) = line {
+                    write!(f, " line {line}")?;
+                }
+                if let Some(header) = header {
+                    write!(f, " ({header})")?;
+                }
+                write!(f, ": {message}")
+            },
+            HmmError::UnsupportedCharacter { character, context } => {
+                write!(f, "unsupported character '{}' in {context}", character.escape_default())
+            },
+            HmmError::InconsistentModel(message) => write!(f, "inconsistent model: {message}"),
+        }
+    }
+}
+
+impl Error for HmmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HmmError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,468 @@
+// evaluate.rs
+
+use crate::errors::{HmmError, Result};
+use crate::sam_input::SamFilter;
+use crate::streams;
+
+use serde_json::{json, Value};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+
+/// The hit value the ROC and PR curves are computed over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreField {
+    LogOdds,
+    Posterior,
+    Score,
+}
+
+impl ScoreField {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "log_odds" => Some(ScoreField::LogOdds),
+            "posterior" => Some(ScoreField::Posterior),
+            "score" => Some(ScoreField::Score),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScoreField::LogOdds => "log_odds",
+            ScoreField::Posterior => "posterior",
+            ScoreField::Score => "score",
+        }
+    }
+
+    /// the SAM tag of the value
+    fn tag(&self) -> &'static str {
+        match self {
+            ScoreField::LogOdds => "ZO",
+            ScoreField::Posterior => "ZP",
+            ScoreField::Score => "ZS",
+        }
+    }
+}
+
+/// The true origin of a read.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Expected {
+    /// the locus (e.g. IGH), None for reads without a rearrangement
+    pub locus: Option<String>,
+    /// the V and J alleles - reference annotations may list several equally good ones
+    pub v: Vec<String>,
+    pub j: Vec<String>,
+}
+
+/// The best hit the scan reported for a read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Called {
+    pub locus: String,
+    pub score: f64,
+    pub v: Option<String>,
+    pub j: Option<String>,
+}
+
+/// The locus of a label as the scan, the simulator or an AIRR table writes it:
+/// human:IGH-VDJ, IGH-VDJ and IGH are all IGH. Decoys and empty values have none.
+pub fn locus_key(label: &str) -> Option<String> {
+    let label = label.trim();
+    let label = label.rsplit(':').next().unwrap_or(label);
+    let locus = label.split('-').next().unwrap_or(label).to_ascii_uppercase();
+    match locus.as_str() {
+        "" | "DECOY" | "NONE" | "NA" => None,
+        _ => Some(locus),
+    }
+}
+
+/// Read a tab separated truth table with a header line: the simulator truth table or an AIRR rearrangement table
+/// (e.g. from IgBLAST or MiXCR). The columns read/sequence_id and locus are needed, v_gene/v_call and j_gene/j_call are used if present.
+pub fn read_truth_table(path: &str) -> Result<HashMap<String, Expected>> {
+    let content = fs::read_to_string(path).map_err(|err| HmmError::io(path, err))?;
+    let mut lines = content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+    let header: Vec<&str> = match lines.next() {
+        Some((_, line)) => line.split('\t').map(str::trim).collect(),
+        None => return Ok(HashMap::new()),
+    };
+    let column = |names: &[&str]| header.iter().position(|h| names.contains(h));
+    let (read, locus) = match (column(&["read", "sequence_id"]), column(&["locus"])) {
+        (Some(read), Some(locus)) => (read, locus),
+        _ => return Err(HmmError::parse(path, Some(1), None, "expected the columns read (or sequence_id) and locus")),
+    };
+    let v = column(&["v_gene", "v_call"]);
+    let j = column(&["j_gene", "j_call"]);
+    let genes = |fields: &[&str], col: Option<usize>| -> Vec<String> {
+        col.and_then(|col| fields.get(col))
+            .map(|value| value.split(',').map(str::trim).filter(|g| !g.is_empty() && *g != "-").map(str::to_string).collect())
+            .unwrap_or_default()
+    };
+
+    let mut ret = HashMap::new();
+    for (line_nr, line) in lines {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let name = match fields.get(read) {
+            Some(name) if !name.is_empty() => name.to_string(),
+            _ => return Err(HmmError::parse(path, Some(line_nr as u64 + 1), None, "missing read name")),
+        };
+        ret.insert(name, Expected {
+            locus: fields.get(locus).and_then(|l| locus_key(l)),
+            v: genes(&fields, v),
+            j: genes(&fields, j),
+        });
+    }
+    Ok(ret)
+}
+
+/// Read the best hits from a scan outfile - fasta/fastq with key=value header tags or SAM/BAM with the Z? tags.
+pub fn read_calls(path: &str, field: ScoreField) -> Result<HashMap<String, Called>> {
+    let filter = SamFilter {
+        keep_tags: vec![*b"ZL", *b"ZS", *b"ZO", *b"ZP", *b"ZV", *b"ZJ"],
+        ..SamFilter::default()
+    };
+    let mut ret = HashMap::new();
+    for record in streams::open_records(path, filter)? {
+        let record = record?;
+        let comment = String::from_utf8_lossy(record.comment()).to_string();
+        let mut called = Called { locus: String::new(), score: f64::NAN, v: None, j: None };
+        for word in comment.split_ascii_whitespace() {
+            let (key, value) = match word.split_once('=') {
+                Some(pair) => pair,
+                // SAM tags: XX:T:value
+                None => match word.splitn(3, ':').collect::<Vec<&str>>()[..] {
+                    [key, _, value] if key.len() == 2 => (key, value),
+                    _ => continue,
+                },
+            };
+            match key {
+                "locus" | "ZL" => called.locus = value.to_string(),
+                "v" | "ZV" => called.v = Some(value.to_string()),
+                "j" | "ZJ" => called.j = Some(value.to_string()),
+                key if key == field.name() || key == field.tag() => called.score = value.parse().unwrap_or(f64::NAN),
+                _ => {},
+            }
+        }
+        if let Some(locus) = locus_key(&called.locus) {
+            called.locus = locus;
+            ret.insert(String::from_utf8_lossy(record.name()).to_string(), called);
+        }
+    }
+    Ok(ret)
+}
+
+/// Precision and recall of one locus.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LocusCounts {
+    /// reads of this locus called as this locus
+    pub true_positives: usize,
+    /// reads of another locus or decoys called as this locus
+    pub false_positives: usize,
+    /// reads of this locus called as another locus or not at all
+    pub false_negatives: usize,
+}
+
+impl LocusCounts {
+    pub fn precision(&self) -> f64 {
+        ratio(self.true_positives, self.true_positives + self.false_positives)
+    }
+
+    pub fn recall(&self) -> f64 {
+        ratio(self.true_positives, self.true_positives + self.false_negatives)
+    }
+}
+
+/// How often the V or J call of the correctly placed reads is right.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeneAccuracy {
+    /// reads with a known gene and the right locus call
+    pub reads: usize,
+    pub allele_correct: usize,
+    /// the gene without the allele (the part before '*') is right
+    pub gene_correct: usize,
+    pub no_call: usize,
+}
+
+impl GeneAccuracy {
+    fn add(&mut self, expected: &[String], called: Option<&String>) {
+        if expected.is_empty() {
+            return
+        }
+        self.reads += 1;
+        let called = match called {
+            Some(called) => called,
+            None => {
+                self.no_call += 1;
+                return
+            }
+        };
+        let gene = |allele: &str| allele.split('*').next().unwrap_or(allele).to_string();
+        if expected.contains(called) {
+            self.allele_correct += 1;
+        }
+        if expected.iter().any(|e| gene(e) == gene(called)) {
+            self.gene_correct += 1;
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "reads": self.reads,
+            "allele_correct": self.allele_correct,
+            "gene_correct": self.gene_correct,
+            "no_call": self.no_call,
+            "allele_accuracy": ratio(self.allele_correct, self.reads),
+            "gene_accuracy": ratio(self.gene_correct, self.reads),
+        })
+    }
+}
+
+/// One threshold of the ROC and PR curves - all hits scoring at least the threshold are called.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurvePoint {
+    pub threshold: f64,
+    /// rearranged reads called with the right locus
+    pub true_positives: usize,
+    /// decoys and wrong locus calls
+    pub false_positives: usize,
+    /// called decoys (the ROC false positives)
+    pub decoy_hits: usize,
+}
+
+/// Scan results compared with the truth.
+#[derive(Debug, Clone)]
+pub struct Evaluation {
+    pub score: ScoreField,
+    /// reads with a rearrangement
+    pub positives: usize,
+    /// reads without a rearrangement
+    pub negatives: usize,
+    /// hits for reads missing from the truth table - they are ignored
+    pub unknown_reads: usize,
+    pub per_locus: BTreeMap<String, LocusCounts>,
+    /// (true locus or 'decoy', called locus or 'none') -> reads
+    pub confusion: BTreeMap<(String, String), usize>,
+    pub v: GeneAccuracy,
+    pub j: GeneAccuracy,
+    pub curve: Vec<CurvePoint>,
+}
+
+impl Evaluation {
+    pub fn new(truth: &HashMap<String, Expected>, calls: &HashMap<String, Called>, score: ScoreField) -> Self {
+        let mut ret = Self {
+            score,
+            positives: 0,
+            negatives: 0,
+            unknown_reads: calls.keys().filter(|read| !truth.contains_key(*read)).count(),
+            per_locus: BTreeMap::new(),
+            confusion: BTreeMap::new(),
+            v: GeneAccuracy::default(),
+            j: GeneAccuracy::default(),
+            curve: Vec::new(),
+        };
+        // (score, right locus, decoy) of every hit
+        let mut hits: Vec<(f64, bool, bool)> = Vec::new();
+        for (read, expected) in truth {
+            let called = calls.get(read);
+            let truth_label = expected.locus.clone().unwrap_or_else(|| "decoy".to_string());
+            let called_label = called.map(|c| c.locus.clone()).unwrap_or_else(|| "none".to_string());
+            *ret.confusion.entry((truth_label, called_label)).or_insert(0) += 1;
+            match &expected.locus {
+                Some(_) => ret.positives += 1,
+                None => ret.negatives += 1,
+            }
+            let right = matches!((&expected.locus, called), (Some(locus), Some(c)) if *locus == c.locus);
+            if let Some(locus) = &expected.locus {
+                let counts = ret.per_locus.entry(locus.clone()).or_default();
+                if right {
+                    counts.true_positives += 1;
+                } else {
+                    counts.false_negatives += 1;
+                }
+            }
+            if let Some(called) = called {
+                if !right {
+                    ret.per_locus.entry(called.locus.clone()).or_default().false_positives += 1;
+                }
+                // hits without a score can not be ranked - they come last
+                let score = if called.score.is_nan() { f64::NEG_INFINITY } else { called.score };
+                hits.push((score, right, expected.locus.is_none()));
+            }
+            if right {
+                let called = called.expect("a right call has a hit");
+                ret.v.add(&expected.v, called.v.as_ref());
+                ret.j.add(&expected.j, called.j.as_ref());
+            }
+        }
+
+        hits.sort_by(|a, b| b.0.total_cmp(&a.0));
+        let mut point = CurvePoint { threshold: f64::INFINITY, true_positives: 0, false_positives: 0, decoy_hits: 0 };
+        for (i, (score, right, decoy)) in hits.iter().enumerate() {
+            if *right {
+                point.true_positives += 1;
+            } else {
+                point.false_positives += 1;
+            }
+            if *decoy {
+                point.decoy_hits += 1;
+            }
+            point.threshold = *score;
+            if hits.get(i + 1).is_none_or(|next| next.0 != *score) {
+                ret.curve.push(point.clone());
+            }
+        }
+        ret
+    }
+
+    /// the area under the ROC curve (rearranged reads with the right locus vs. called decoys),
+    /// reads without a hit tie at the end
+    pub fn roc_auc(&self) -> f64 {
+        if self.positives == 0 || self.negatives == 0 {
+            return f64::NAN
+        }
+        let mut area = 0.0;
+        let (mut x, mut y) = (0.0, 0.0);
+        for point in self.curve.iter().chain([&CurvePoint { threshold: f64::NEG_INFINITY, true_positives: self.positives, false_positives: 0, decoy_hits: self.negatives }]) {
+            let (next_x, next_y) = (point.decoy_hits as f64 / self.negatives as f64, point.true_positives as f64 / self.positives as f64);
+            area += (next_x - x) * (y + next_y) / 2.0;
+            (x, y) = (next_x, next_y);
+        }
+        area
+    }
+
+    /// the area under the precision-recall curve as the average precision over the recall steps
+    pub fn average_precision(&self) -> f64 {
+        if self.positives == 0 {
+            return f64::NAN
+        }
+        let mut area = 0.0;
+        let mut recall = 0.0;
+        for point in &self.curve {
+            let next = point.true_positives as f64 / self.positives as f64;
+            area += (next - recall) * ratio(point.true_positives, point.true_positives + point.false_positives);
+            recall = next;
+        }
+        area
+    }
+
+    /// all calls regardless of their score
+    fn overall(&self) -> LocusCounts {
+        let true_positives = self.per_locus.values().map(|c| c.true_positives).sum();
+        LocusCounts {
+            true_positives,
+            false_positives: self.per_locus.values().map(|c| c.false_positives).sum(),
+            false_negatives: self.positives - true_positives,
+        }
+    }
+
+    /// the called decoys and the decoys without a hit
+    fn decoys(&self) -> (usize, usize) {
+        let called = self.confusion.iter().filter(|((truth, called), _)| truth == "decoy" && called != "none").map(|(_, n)| n).sum();
+        (called, self.negatives - called)
+    }
+
+    /// The ROC and PR curves as tab separated table - one line per distinct score.
+    pub fn write_curves<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "threshold\ttrue_positives\tfalse_positives\tdecoy_hits\ttrue_positive_rate\tfalse_positive_rate\tprecision\trecall")?;
+        for point in &self.curve {
+            writeln!(
+                out, "{}\t{}\t{}\t{}\t{:.6}\t{:.6}\t{:.6}\t{:.6}",
+                point.threshold, point.true_positives, point.false_positives, point.decoy_hits,
+                ratio(point.true_positives, self.positives), ratio(point.decoy_hits, self.negatives),
+                ratio(point.true_positives, point.true_positives + point.false_positives), ratio(point.true_positives, self.positives),
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Value {
+        let overall = self.overall();
+        let (called_decoys, clean_decoys) = self.decoys();
+        let per_locus: BTreeMap<&String, Value> = self.per_locus.iter().map(|(locus, counts)| (locus, json!({
+            "true_positives": counts.true_positives,
+            "false_positives": counts.false_positives,
+            "false_negatives": counts.false_negatives,
+            "precision": counts.precision(),
+            "recall": counts.recall(),
+        }))).collect();
+        let mut confusion: BTreeMap<&String, BTreeMap<&String, usize>> = BTreeMap::new();
+        for ((truth, called), count) in &self.confusion {
+            confusion.entry(truth).or_default().insert(called, *count);
+        }
+        json!({
+            "score": self.score.name(),
+            "reads": self.positives + self.negatives,
+            "rearranged_reads": self.positives,
+            "decoy_reads": self.negatives,
+            "unknown_reads": self.unknown_reads,
+            "precision": overall.precision(),
+            "recall": overall.recall(),
+            "specificity": ratio(clean_decoys, self.negatives),
+            "decoy_hits": called_decoys,
+            "roc_auc": self.roc_auc(),
+            "average_precision": self.average_precision(),
+            "per_locus": per_locus,
+            "confusion": confusion,
+            "v_calls": self.v.to_json(),
+            "j_calls": self.j.to_json(),
+        })
+    }
+
+    pub fn text(&self) -> String {
+        let overall = self.overall();
+        let (called_decoys, clean_decoys) = self.decoys();
+        let mut out = String::from("Evaluation\n");
+        let _ = writeln!(out, "  reads                    {:>9}  ({} rearranged, {} decoys)", self.positives + self.negatives, self.positives, self.negatives);
+        if self.unknown_reads > 0 {
+            let _ = writeln!(out, "  hits not in the truth    {:>9}", self.unknown_reads);
+        }
+        let _ = writeln!(out, "  precision                {:>9}", fixed(overall.precision()));
+        let _ = writeln!(out, "  recall                   {:>9}", fixed(overall.recall()));
+        let _ = writeln!(out, "  specificity              {:>9}  ({} of {} decoys with a hit)", fixed(ratio(clean_decoys, self.negatives)), called_decoys, self.negatives);
+        let _ = writeln!(out, "  {:<24} {:>9}", format!("ROC AUC ({})", self.score.name()), fixed(self.roc_auc()));
+        let _ = writeln!(out, "  average precision        {:>9}", fixed(self.average_precision()));
+
+        out += "  per locus                     TP        FP        FN  precision   recall\n";
+        for (locus, counts) in &self.per_locus {
+            let _ = writeln!(
+                out, "    {locus:<22} {:>9} {:>9} {:>9} {:>10} {:>8}",
+                counts.true_positives, counts.false_positives, counts.false_negatives, fixed(counts.precision()), fixed(counts.recall())
+            );
+        }
+
+        out += "  confusion (rows: truth, columns: call)\n";
+        let rows: BTreeSet<&String> = self.confusion.keys().map(|(truth, _)| truth).collect();
+        let columns: BTreeSet<&String> = self.confusion.keys().map(|(_, called)| called).collect();
+        let _ = write!(out, "    {:<8}", "");
+        for column in &columns {
+            let _ = write!(out, " {column:>8}");
+        }
+        out += "\n";
+        for row in &rows {
+            let _ = write!(out, "    {row:<8}");
+            for column in &columns {
+                let _ = write!(out, " {:>8}", self.confusion.get(&((*row).clone(), (*column).clone())).unwrap_or(&0));
+            }
+            out += "\n";
+        }
+
+        for (segment, accuracy) in [("V", &self.v), ("J", &self.j)] {
+            let _ = writeln!(
+                out, "  {segment} calls                  {:>9}  allele {}, gene {}, no call {}",
+                accuracy.reads, fixed(ratio(accuracy.allele_correct, accuracy.reads)), fixed(ratio(accuracy.gene_correct, accuracy.reads)), accuracy.no_call
+            );
+        }
+        out
+    }
+}
+
+/// n / total - NaN without a total, which the JSON reports as null (serde_json has no NaN) and the text as '-'
+fn ratio(n: usize, total: usize) -> f64 {
+    if total == 0 { f64::NAN } else { n as f64 / total as f64 }
+}
+
+/// a ratio with four decimals for the text report, '-' if it is undefined
+fn fixed(value: f64) -> String {
+    if value.is_nan() { "-".to_string() } else { format!("{value:.4}") }
+}
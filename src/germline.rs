@@ -0,0 +1,217 @@
+// germline.rs
+
+use crate::HMM::HMM;
+use crate::VDJmodeler::{Chain, SequenceModel, VDJmodeler};
+use crate::errors::{HmmError, Result};
+use flate2::read::MultiGzDecoder;
+use needletail::parse_fastx_file;
+use serde_json::Value;
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+
+/// One germline allele (V, D or J segment) used to build the locus models.
+#[derive(Clone, Debug)]
+pub struct GermlineRecord {
+    /// the gene/allele name e.g. IGHV1-2*02
+    pub gene: String,
+    pub segment: Chain,
+    pub locus: SequenceModel,
+    /// the sequence - IMGT gapped ('.') or ungapped
+    pub sequence: Vec<u8>,
+}
+
+/// The germline database formats we can read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GermlineFormat {
+    /// IMGT or OGRDB fasta - the gene name is identified from the header
+    Fasta,
+    /// AIRR-C germline set JSON (e.g. downloaded from OGRDB)
+    AirrJson,
+    /// tab separated gene, segment, locus, sequence (.tsv)
+    Tsv,
+}
+
+impl GermlineFormat {
+    /// guess the format from the file extension (.json, .tsv, everything else is fasta) - a trailing .gz is ignored
+    pub fn from_path(path: &str) -> Self {
+        let lower = path.to_ascii_lowercase();
+        let lower = lower.strip_suffix(".gz").unwrap_or(&lower);
+        if lower.ends_with(".json") {
+            GermlineFormat::AirrJson
+        } else if lower.ends_with(".tsv") {
+            GermlineFormat::Tsv
+        } else {
+            GermlineFormat::Fasta
+        }
+    }
+}
+
+/// Read all usable germline records from a database file in any of the supported formats.
+pub fn read_germline_database(path: &str) -> Result<Vec<GermlineRecord>> {
+    match GermlineFormat::from_path(path) {
+        GermlineFormat::Fasta => read_fasta(path),
+        GermlineFormat::AirrJson => read_airr_json(path),
+        GermlineFormat::Tsv => read_tsv(path),
+    }
+}
+
+/// Identify locus and segment from a fasta header.
+/// IMGT headers are either the plain allele name or '|' separated with the allele in the second field,
+/// OGRDB headers start with the allele name - therefore all fields are checked in order.
+pub fn identify_header(header: &str) -> Option<(String, SequenceModel, Chain)> {
+    for field in header.split(['|', ' ', '\t']) {
+        if let Some((locus, segment)) = VDJmodeler::identify_model_type(field) {
+            return Some((field.to_string(), locus, segment));
+        }
+    }
+    None
+}
+
+/// Make sure a germline sequence only contains bases, IUPAC codes and IMGT gaps.
+fn check_sequence(file: &str, line: Option<u64>, header: &str, sequence: &[u8]) -> Result<()> {
+    for c in sequence {
+        if let Err(err) = HMM::iupac_char2pos(*c) {
+            return Err(HmmError::parse(file, line, Some(header), err.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// the whole file as text - .gz files are decompressed (needletail does that on its own for the fasta files)
+pub(crate) fn read_file(path: &str) -> Result<String> {
+    if !path.to_ascii_lowercase().ends_with(".gz") {
+        return fs::read_to_string(path).map_err(|err| HmmError::io(path, err));
+    }
+    let file = File::open(path).map_err(|err| HmmError::io(path, err))?;
+    let mut content = String::new();
+    MultiGzDecoder::new(file).read_to_string(&mut content).map_err(|err| HmmError::io(path, err))?;
+    Ok(content)
+}
+
+/// IMGT or OGRDB fasta files
+pub fn read_fasta(path: &str) -> Result<Vec<GermlineRecord>> {
+    let mut reader = match parse_fastx_file(path) {
+        Ok(reader) => reader,
+        Err(err) => {
+            if !Path::new(path).exists() {
+                return Err(HmmError::io(path, std::io::Error::new(std::io::ErrorKind::NotFound, err.to_string())));
+            }
+            return Err(HmmError::from_needletail(path, err));
+        }
+    };
+    let mut ret = Vec::new();
+    while let Some(record) = reader.next() {
+        let read = record.map_err(|err| HmmError::from_needletail(path, err))?;
+        let header = String::from_utf8_lossy(read.id()).to_string();
+        if let Some((gene, locus, segment)) = identify_header(&header) {
+            let sequence = read.seq().into_owned();
+            check_sequence(path, Some(read.start_line_number()), &header, &sequence)?;
+            ret.push(GermlineRecord {
+                gene,
+                segment,
+                locus,
+                sequence,
+            });
+        }
+    }
+    Ok(ret)
+}
+
+/// A simple tab separated table with the columns gene, segment (V/D/J), locus (e.g. IGH) and sequence.
+/// Empty lines, lines starting with '#' and a header line starting with 'gene' are ignored.
+pub fn read_tsv(path: &str) -> Result<Vec<GermlineRecord>> {
+    let content = read_file(path)?;
+    let mut ret = Vec::new();
+    for (line_nr, line) in content.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') || line.to_ascii_lowercase().starts_with("gene\t") {
+            continue
+        }
+        let line_nr = Some(line_nr as u64 + 1);
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 4 {
+            return Err(HmmError::parse(path, line_nr, None,
+                format!("expected gene, segment, locus and sequence - got '{line}'")));
+        }
+        let segment = match Chain::from_segment(fields[1]) {
+            Some(segment) => segment,
+            None => {
+                // C segments or other annotations are not modeled
+                continue
+            }
+        };
+        let locus = match SequenceModel::from_locus(fields[2]) {
+            Some(locus) => locus,
+            None => return Err(HmmError::parse(path, line_nr, Some(fields[0]),
+                format!("unknown locus '{}'", fields[2]))),
+        };
+        let sequence = fields[3].trim().as_bytes().to_vec();
+        check_sequence(path, line_nr, fields[0], &sequence)?;
+        ret.push(GermlineRecord {
+            gene: fields[0].to_string(),
+            segment,
+            locus,
+            sequence,
+        });
+    }
+    Ok(ret)
+}
+
+/// AIRR-C germline set JSON. The file can contain one GermlineSet, a list of them
+/// or an object with a 'GermlineSet' entry (the OGRDB download format).
+/// A file without a usable allele description is a parse error.
+pub fn read_airr_json(path: &str) -> Result<Vec<GermlineRecord>> {
+    let content = read_file(path)?;
+    let json: Value = serde_json::from_str(&content)
+        .map_err(|err| HmmError::parse(path, Some(err.line() as u64), None, format!("not valid JSON: {err}")))?;
+    let sets: Vec<&Value> = match json.get("GermlineSet") {
+        Some(Value::Array(sets)) => sets.iter().collect(),
+        Some(set) => vec![set],
+        None => match &json {
+            Value::Array(sets) => sets.iter().collect(),
+            set => vec![set],
+        },
+    };
+    let mut ret = Vec::new();
+    for set in sets {
+        let set_locus = set.get("locus").and_then(Value::as_str);
+        let alleles = match set.get("allele_descriptions").and_then(Value::as_array) {
+            Some(alleles) => alleles,
+            None => continue,
+        };
+        for allele in alleles {
+            if let Some(record) = airr_allele(allele, set_locus) {
+                check_sequence(path, None, &record.gene, &record.sequence)?;
+                ret.push(record);
+            }
+        }
+    }
+    if ret.is_empty() {
+        return Err(HmmError::parse(path, None, None, "no usable allele_descriptions (label, sequence_type, locus and sequence)"));
+    }
+    Ok(ret)
+}
+
+fn airr_allele(allele: &Value, set_locus: Option<&str>) -> Option<GermlineRecord> {
+    let gene = allele.get("label").and_then(Value::as_str)?;
+    let segment = Chain::from_segment(allele.get("sequence_type").and_then(Value::as_str)?)?;
+    let locus = SequenceModel::from_locus(allele.get("locus").and_then(Value::as_str).or(set_locus)?)?;
+    // the IMGT gapped V sequence keeps the model positions in sync with the IMGT databases
+    let aligned = allele.get("v_gene_delineations")
+        .and_then(Value::as_array)
+        .and_then(|delineations| delineations.iter()
+            .filter_map(|d| d.get("aligned_sequence").and_then(Value::as_str))
+            .find(|seq| !seq.is_empty())
+        );
+    let sequence = aligned
+        .or_else(|| allele.get("coding_sequence").and_then(Value::as_str).filter(|seq| !seq.is_empty()))
+        .or_else(|| allele.get("sequence").and_then(Value::as_str))?;
+    Some(GermlineRecord {
+        gene: gene.to_string(),
+        segment,
+        locus,
+        sequence: sequence.as_bytes().to_vec(),
+    })
+}
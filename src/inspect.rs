@@ -0,0 +1,115 @@
+// inspect.rs
+
+use crate::HMM::HMM;
+use crate::VDJmodeler::{Chain, HMMmodel};
+
+use std::io::{self, Write};
+
+/// The bases in output order and their index in the model tables (see HMM::char2pos).
+const BASES: [(char, usize); 4] = [('A', 0), ('C', 2), ('G', 1), ('T', 3)];
+
+/// What one locus emits at one model position.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PositionInfo {
+    pub position: usize,
+    /// the locus label e.g. 'IGH-VDJ' or 'human:IGH-VDJ'
+    pub locus: String,
+    /// the segment of the germlines placed at this position - None without germline data
+    pub segment: Option<Chain>,
+    /// the number of germline bases counted at this position (IUPAC codes count once per base they stand for)
+    pub coverage: usize,
+    /// the A, C, G, T emission probabilities the scan uses
+    pub bases: [f64; 4],
+    /// the gap ('.') column of HMMState::match_emission - 0 after the end of the locus
+    pub gap: f64,
+    /// the Shannon entropy of the base distribution in bits (0: one base, 2: uniform)
+    pub entropy: f64,
+    /// the Kullback-Leibler divergence of the base distribution from the background composition in bits
+    pub relative_entropy: f64,
+}
+
+/// The per position emission statistics of every locus.
+/// models are the locus models the HMM was built from (VDJmodeler::collect_species_models) - they carry the coverage,
+/// the segments and the base composition of all germlines, which is used as background.
+pub fn inspect(hmm: &HMM, models: &[HMMmodel]) -> Vec<PositionInfo> {
+    let mut composition = [0.0; 4];
+    for collector in models.iter().flat_map(|model| &model.collector) {
+        for (total, count) in composition.iter_mut().zip(&collector.states) {
+            *total += *count as f64;
+        }
+    }
+    let sum: f64 = composition.iter().sum();
+    let background: Vec<f64> = BASES.iter().map(|(_, i)| if sum > 0.0 { composition[*i] / sum } else { 0.25 }).collect();
+
+    let mut ret = Vec::with_capacity(hmm.states.len() * hmm.num_loci());
+    for (locus, model) in models.iter().enumerate().take(hmm.num_loci()) {
+        let mut segments: Vec<Option<Chain>> = vec![None; hmm.states.len()];
+        for germline in &model.germlines {
+            for segment in segments.iter_mut().skip(germline.start).take(germline.sequence.len()) {
+                *segment = Some(germline.segment);
+            }
+        }
+        for (position, state) in hmm.states.iter().enumerate() {
+            let bases = BASES.map(|(_, i)| hmm.emissions(position, i)[locus]);
+            // the entropies describe the distribution the bases are drawn from - the gap column is not part of it
+            let total: f64 = bases.iter().sum();
+            let (mut entropy, mut relative_entropy) = (0.0, 0.0);
+            for (p, q) in bases.iter().zip(&background) {
+                let p = p / total;
+                if p > 0.0 {
+                    entropy -= p * p.log2();
+                    relative_entropy += p * (p / q).log2();
+                }
+            }
+            ret.push(PositionInfo {
+                position,
+                locus: hmm.label(locus),
+                segment: segments[position],
+                coverage: model.collector.get(position).map_or(0, |c| c.states[..4].iter().sum()),
+                bases,
+                gap: state.match_emission.get(locus).and_then(|column| column.get(4)).copied().unwrap_or(0.0),
+                entropy,
+                relative_entropy,
+            });
+        }
+    }
+    ret
+}
+
+fn segment_name(segment: Option<Chain>) -> &'static str {
+    match segment {
+        Some(Chain::V) => "V",
+        Some(Chain::D) => "D",
+        Some(Chain::J) => "J",
+        None => "-",
+    }
+}
+
+/// One tab separated line per locus and model position.
+pub fn write_tsv<W: Write>(out: &mut W, infos: &[PositionInfo]) -> io::Result<()> {
+    writeln!(out, "locus\tposition\tsegment\tcoverage\tA\tC\tG\tT\tgap\tentropy\trelative_entropy")?;
+    for info in infos {
+        writeln!(
+            out, "{}\t{}\t{}\t{}\t{:.6}\t{:.6}\t{:.6}\t{:.6}\t{:.6}\t{:.4}\t{:.4}",
+            info.locus, info.position, segment_name(info.segment), info.coverage,
+            info.bases[0], info.bases[1], info.bases[2], info.bases[3], info.gap, info.entropy, info.relative_entropy
+        )?;
+    }
+    Ok(())
+}
+
+/// The sequence logo letter heights: every base probability times the information content of the position
+/// (2 - entropy bits), one line per locus and position - e.g. for logomaker after selecting one locus.
+pub fn write_logo_matrix<W: Write>(out: &mut W, infos: &[PositionInfo]) -> io::Result<()> {
+    writeln!(out, "locus\tposition\tA\tC\tG\tT")?;
+    for info in infos {
+        let total: f64 = info.bases.iter().sum();
+        let information = 2.0 - info.entropy;
+        let heights = info.bases.map(|p| p / total * information);
+        writeln!(
+            out, "{}\t{}\t{:.6}\t{:.6}\t{:.6}\t{:.6}",
+            info.locus, info.position, heights[0], heights[1], heights[2], heights[3]
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+// kmer_index.rs
+
+use crate::HMM::HMM;
+use crate::errors::{HmmError, Result};
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// the k-mer lengths the index supports - a k-mer is packed into a u64 with 2 bits per base
+pub const KMER_LENGTHS: RangeInclusive<usize> = 4..=31;
+
+/// A k-mer index over the germline sequences of all loci.
+/// Reads sharing no k-mer with any germline are rejected before the (expensive) model scan,
+/// the shared k-mers of the others vote for the model offsets (diagonals) the read most likely starts at.
+pub struct KmerIndex {
+    k: usize,
+    /// k-mer code -> (locus, model position of the first k-mer base)
+    index: HashMap<u64, Vec<(u32, u32)>>,
+}
+
+/// A candidate model offset for a read seeded by shared k-mers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Seed {
+    pub locus: usize,
+    /// the model position the first scanned read base is placed at
+    pub start: usize,
+    /// the first scanned read base - the bases before it overhang the start of the model
+    pub read_start: usize,
+    /// the number of shared k-mers on this diagonal
+    pub hits: usize,
+}
+
+/// How many reads passed the prefilter - shared between all clones of a Scanner.
+#[derive(Debug, Default)]
+pub struct PrefilterStats {
+    pub reads: AtomicUsize,
+    pub passed: AtomicUsize,
+    /// reads the prefilter rejected but the full scan would have reported (only counted in audit mode)
+    pub lost_hits: AtomicUsize,
+}
+
+impl PrefilterStats {
+    pub fn pass_rate(&self) -> f64 {
+        let reads = self.reads.load(Ordering::Relaxed);
+        if reads == 0 {
+            return 0.0
+        }
+        self.passed.load(Ordering::Relaxed) as f64 / reads as f64
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "Prefilter: {} of {} reads passed ({:.2}%), {} rejected reads would have been reported by the full scan",
+            self.passed.load(Ordering::Relaxed),
+            self.reads.load(Ordering::Relaxed),
+            100.0 * self.pass_rate(),
+            self.lost_hits.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl KmerIndex {
+    /// Index all k-mers of the germline sequences placed in the model.
+    /// Only stretches of k unambiguous bases without IMGT gaps are used, so every k-mer maps to k consecutive model positions.
+    /// k has to be in KMER_LENGTHS.
+    pub fn build(hmm: &HMM, k: usize) -> Result<Self> {
+        if !KMER_LENGTHS.contains(&k) {
+            return Err(HmmError::InconsistentModel(format!(
+                "k-mer length {k} is not supported - it has to be between {} and {}", KMER_LENGTHS.start(), KMER_LENGTHS.end()
+            )))
+        }
+        let mut index: HashMap<u64, Vec<(u32, u32)>> = HashMap::new();
+        for (locus, germlines) in hmm.germlines.iter().enumerate() {
+            for germline in germlines {
+                for_each_kmer(&germline.sequence, k, |pos, code| {
+                    let entry = index.entry(code).or_default();
+                    let value = (locus as u32, (germline.start + pos) as u32);
+                    if !entry.contains(&value) {
+                        entry.push(value);
+                    }
+                });
+            }
+        }
+        Ok(Self { k, index })
+    }
+
+    pub fn k(&self) -> usize {
+        self.k
+    }
+
+    /// the number of distinct k-mers in the index
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// The diagonals supported by shared k-mers, most supported first.
+    /// An empty result means the read shares no k-mer with any germline sequence.
+    pub fn seeds(&self, sequence: &[u8]) -> Vec<Seed> {
+        // (locus, model position of read base 0 - negative if the read starts before the model)
+        let mut diagonals: Vec<(usize, i64)> = Vec::new();
+        for_each_kmer(sequence, self.k, |pos, code| {
+            if let Some(hits) = self.index.get(&code) {
+                for (locus, model_pos) in hits {
+                    diagonals.push((*locus as usize, *model_pos as i64 - pos as i64));
+                }
+            }
+        });
+        diagonals.sort_unstable();
+        let mut ret: Vec<Seed> = Vec::new();
+        for (locus, diagonal) in diagonals {
+            let (start, read_start) = (diagonal.max(0) as usize, (-diagonal).max(0) as usize);
+            match ret.last_mut() {
+                Some(seed) if seed.locus == locus && seed.start == start && seed.read_start == read_start => seed.hits += 1,
+                _ => ret.push(Seed { locus, start, read_start, hits: 1 }),
+            }
+        }
+        ret.sort_by(|a, b| b.hits.cmp(&a.hits).then(a.start.cmp(&b.start)).then(a.read_start.cmp(&b.read_start)));
+        ret
+    }
+}
+
+/// call f(position, 2 bit code) for every k-mer of unambiguous bases in sequence
+fn for_each_kmer<F: FnMut(usize, u64)>(sequence: &[u8], k: usize, mut f: F) {
+    let mask: u64 = if k >= 32 { u64::MAX } else { (1 << (2 * k)) - 1 };
+    let mut code: u64 = 0;
+    let mut valid = 0;
+    for (i, base) in sequence.iter().enumerate() {
+        match HMM::char2pos(*base) {
+            Some(id) => {
+                code = ((code << 2) | id as u64) & mask;
+                valid += 1;
+            },
+            None => {
+                valid = 0;
+                code = 0;
+            },
+        }
+        if valid >= k {
+            f(i + 1 - k, code);
+        }
+    }
+}
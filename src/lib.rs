@@ -1,8 +1,52 @@
+pub mod errors;
+
+#[allow(non_snake_case)]
 pub mod HMM;
 
+#[allow(non_snake_case)]
 pub mod VDJmodeler;
 
+pub mod germline;
+
+pub mod scan_result;
+
+pub mod scanner;
+
+pub mod pipeline;
+
+pub mod kmer_index;
+
+pub mod simd;
+
+pub mod banded;
+
+pub mod bgzf;
+
+pub mod streams;
+
+pub mod sam;
+
+pub mod sam_input;
+
+pub mod report;
+
+pub mod simulate;
+
+pub mod evaluate;
+
+pub mod decoy;
+
+pub mod inspect;
+
+pub mod priors;
+
 
 pub use HMM::HMMState as HMMState;
 
-pub use VDJmodeler::HMMmodel as HMMmodel;
\ No newline at end of file
+pub use VDJmodeler::HMMmodel as HMMmodel;
+
+pub use scan_result::{ScanResult, Strand, GeneCalls};
+
+pub use scanner::Scanner;
+
+pub use errors::{HmmError, Result};
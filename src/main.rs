@@ -1,184 +1,728 @@
-
 // main.rs
-use hmm_mapper::HMM::{HMM}; // Import from lib.rs
+use hmm_mapper::Scanner;
+use hmm_mapper::banded::GapModel;
+use hmm_mapper::decoy::{self, DecoyKind};
+use hmm_mapper::evaluate::{self, Evaluation, ScoreField};
+use hmm_mapper::inspect;
+use hmm_mapper::HMM::HMM;
 use hmm_mapper::VDJmodeler::VDJmodeler;
-use needletail::parse_fastx_file;
-use needletail::parser::SequenceRecord;
+use hmm_mapper::pipeline::{Pipeline, Seqrec};
+use hmm_mapper::priors::{EmissionConfig, EmissionPrior};
+use hmm_mapper::report::RunReport;
+use hmm_mapper::sam::{self, SamFormat, SamWriter};
+use hmm_mapper::sam_input::{Region, SamFilter};
+use hmm_mapper::scan_result::{self, ScanResult};
+use hmm_mapper::simulate::{SimConfig, Simulator, Truth};
+use hmm_mapper::streams::{self, Output};
+use hmm_mapper::VDJmodeler::SequenceModel;
+
 
-use std::io::BufReader;
+use clap::{Parser, Subcommand};
 
-use clap::Parser;
 
+use serde_json::json;
 
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fs::File;
-use std::io::BufWriter;
-use std::io::Write;
+use std::io::{self, Write};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
-use rayon::iter::ParallelIterator;
-use rayon::prelude::ParallelSlice;
 
+/// Without a subcommand the options of 'scan' are expected.
+#[derive(Parser)]
+#[clap(version = "1.1.0", author = "Stefan L. <stefan.lang@med.lu.se>")]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
 
-struct Seqrec{
-    pub id:Vec<u8>,
-    pub seq:Vec<u8>,
+#[derive(Subcommand)]
+enum Command {
+    /// scan reads for VDJ recombination events (the default)
+    Scan(ScanOpts),
+    /// simulate V(D)J rearranged reads with a truth table from a germline database
+    Simulate(SimulateOpts),
+    /// compare scan results with a truth table: precision/recall, ROC/PR curves, locus confusion and gene calls
+    Evaluate(EvaluateOpts),
+    /// export the per position emissions, entropy and germline coverage of every locus model
+    Inspect(InspectOpts),
 }
 
-impl Seqrec{
-    pub fn new (id:&[u8], seq:&[u8] ) -> Self{
-        Self{
-            id: id.to_vec(),
-            seq: seq.to_vec(),
-        }
-    }
+#[derive(clap::Args)]
+struct ScanOpts {
+    /// the fasta formated IMGT database; use species=path (e.g. human=IMGT_human.fa)
+    /// and repeat the option to build a combined multi-species model
+    #[clap(short, long, multiple_occurrences = true, required = true)]
+    database: Vec<String>,
+    /// only scan against the models of this species (needs species-tagged databases)
+    #[clap(short, long)]
+    species: Option<String>,
+    /// the fastq file you want to check for VDJ recombination events ('-' for stdin);
+    /// fasta, SAM and BAM files are detected automatically
+    #[clap(short, long)]
+    fastq: String,
+    /// the outfile with likely VDJ recombination evens in the input format ('-' for stdout);
+    /// compressed by extension: .gz (gzip), .bgz (BGZF), .zst (zstd feature);
+    /// .sam and .bam write alignments against the locus consensus sequences
+    #[clap(short, long)]
+    outfile: String,
+    /// the number of scanner threads (default: all cores)
+    #[clap(short, long)]
+    threads: Option<usize>,
+    /// the number of reads per batch handed to a scanner thread
+    #[clap(short, long, default_value_t = 1000)]
+    batch_size: usize,
+    /// only scan reads sharing a k-mer of this length (4 to 31) with the germline sequences (e.g. 12)
+    #[clap(short, long)]
+    kmer: Option<usize>,
+    /// also fully scan the reads the k-mer prefilter rejected and report how many hits it lost
+    #[clap(long)]
+    prefilter_audit: bool,
+    /// the number of sliding window start positions checked per locus and strand
+    #[clap(long, default_value_t = 3)]
+    start_candidates: usize,
+    /// tag every hit with the start candidates of its locus (starts=model_pos:score,...)
+    #[clap(long)]
+    report_starts: bool,
+    /// realign the best start of every locus allowing insertions and deletions
+    #[clap(long)]
+    indels: bool,
+    /// the model positions the gapped alignment may leave the start diagonal by before
+    /// it falls back to the full DP; 0 always runs the full DP
+    #[clap(long, default_value_t = 16)]
+    band: usize,
+    /// Nanopore/PacBio reads: align with an indel-heavy, homopolymer-aware gap model (implies --indels,
+    /// a band of at least 48 and --kmer 12 unless set) and report every rearrangement of a read
+    /// (concatemers) as its own segment
+    #[clap(long)]
+    long_reads: bool,
+    /// with --long-reads: the maximum number of segments reported per read
+    #[clap(long, default_value_t = 8)]
+    max_segments: usize,
+    /// with --long-reads: the log odds a hit in the rest of a read needs to be reported as another segment
+    #[clap(long, default_value_t = 25.0)]
+    min_segment_log_odds: f64,
+    /// write the locus consensus sequences the SAM/BAM output is aligned to into this fasta file
+    #[clap(long)]
+    reference: Option<String>,
+    /// SAM/BAM input: only scan the unmapped reads
+    #[clap(long)]
+    unmapped_only: bool,
+    /// SAM/BAM input: scan the unmapped reads and the reads mapped to these regions
+    /// (comma separated chr:start-end or 'grch38' for the IG/TR loci)
+    #[clap(long)]
+    regions: Option<String>,
+    /// SAM/BAM input: the aux tags copied into the read header (e.g. cell barcode and UMI)
+    #[clap(long, default_value = "CB,UB")]
+    keep_tags: String,
+    /// write the run summary (counts, locus distribution, histograms, timing) as JSON to this file
+    #[clap(long)]
+    summary: Option<String>,
+    /// also scan a decoy of every read (reverse or shuffle) and tag every hit with its target-decoy q-value;
+    /// the hits are kept in memory until the whole input is scanned
+    #[clap(long)]
+    decoy: Option<String>,
+    /// with --decoy: only write the hits with a q-value of at most this false discovery rate (e.g. 0.01)
+    #[clap(long, default_value_t = 1.0)]
+    fdr: f64,
+    /// let the best path switch locus with this probability per position (e.g. 0.001) and report the reads that do
+    /// (trans-locus rearrangements, chimeras); without it a read stays in one locus
+    #[clap(long)]
+    locus_switches: Option<f64>,
+    #[clap(flatten)]
+    model: ModelOpts,
+}
 
-    pub fn id(&self) -> &[u8] {
-        &self.id
-    }
-    pub fn seq(&self) -> &[u8] {
-        &self.seq
-    }
+/// How the emission probabilities are estimated from the germline sequences.
+#[derive(clap::Args)]
+struct ModelOpts {
+    /// the Dirichlet prior of the emissions: floor (observed frequencies only), uniform, background
+    /// (the base composition of the locus) or mixture (background plus one conserved component per base)
+    #[clap(long, default_value = "background")]
+    prior: String,
+    /// the pseudocounts the prior adds to every model column
+    #[clap(long, default_value_t = 2.0)]
+    prior_strength: f64,
+    /// count every germline sequence once instead of weighting near identical alleles down (Henikoff weights)
+    #[clap(long)]
+    no_sequence_weights: bool,
 }
 
+impl ModelOpts {
+    fn config(&self) -> EmissionConfig {
+        let prior = match EmissionPrior::from_name(&self.prior, self.prior_strength) {
+            Some(prior) if self.prior_strength > 0.0 => prior,
+            _ => {
+                eprintln!("Unknown prior {} (strength {}) - use floor, uniform, background or mixture with a positive strength", self.prior, self.prior_strength);
+                std::process::exit(1);
+            }
+        };
+        EmissionConfig { prior, sequence_weights: !self.no_sequence_weights }
+    }
+}
 
-#[derive(Parser)]
-#[clap(version = "1.1.0", author = "Stefan L. <stefan.lang@med.lu.se>")]
-struct Opts {
-    /// the fasta formated IMGT database
+#[derive(clap::Args)]
+struct SimulateOpts {
+    /// the germline database the models are built from (species=path labels the loci like the scan)
     #[clap(short, long)]
     database: String,
-    /// the fastq file you want to check for VDJ recombination events
+    /// the simulated reads as fastq ('-' for stdout; compressed by extension)
     #[clap(short, long)]
-    fastq: String,
-    /// the fasta formated outfile with likely VDJ recombination evens
+    outfile: String,
+    /// the truth table: origin, trimming, additions and mutations of every read (tab separated)
+    #[clap(long)]
+    truth: String,
+    /// the number of reads
+    #[clap(short, long, default_value_t = 10000)]
+    reads: usize,
+    /// the read length
+    #[clap(short = 'l', long, default_value_t = 150)]
+    read_length: usize,
+    /// only simulate these loci (comma separated e.g. IGH,TRB)
+    #[clap(long)]
+    loci: Option<String>,
+    /// only use these genes, alleles or subgroups (comma separated e.g. IGHV1-2,IGHJ4*02,IGHG1)
+    #[clap(long)]
+    genes: Option<String>,
+    /// the maximum number of bases trimmed from each joined segment end
+    #[clap(long, default_value_t = 8)]
+    max_trim: usize,
+    /// the maximum number of P nucleotides added to an untrimmed segment end
+    #[clap(long, default_value_t = 2)]
+    max_p: usize,
+    /// the maximum number of N nucleotides added per junction
+    #[clap(long, default_value_t = 10)]
+    max_n: usize,
+    /// the number of constant gene bases following J (0 for none)
+    #[clap(long, default_value_t = 60)]
+    constant_length: usize,
+    /// the somatic hypermutation rate of the V(D)J bases
+    #[clap(long, default_value_t = 0.0)]
+    shm: f64,
+    /// the sequencing error rate (also sets the base qualities)
+    #[clap(long, default_value_t = 0.001)]
+    errors: f64,
+    /// the fraction of reverse complement reads
+    #[clap(long, default_value_t = 0.5)]
+    reverse: f64,
+    /// the fraction of decoy reads without a rearrangement
+    #[clap(long, default_value_t = 0.1)]
+    decoys: f64,
+    /// cut the decoy reads from this fasta file (e.g. a transcriptome) instead of using random sequence
+    #[clap(long)]
+    background: Option<String>,
+    /// the random seed - the same seed and options give the same reads
+    #[clap(long, default_value_t = 1)]
+    seed: u64,
+}
+
+#[derive(clap::Args)]
+struct EvaluateOpts {
+    /// the truth table: the simulate truth table or an AIRR rearrangement table (sequence_id, locus, v_call, j_call)
+    #[clap(long)]
+    truth: String,
+    /// the scan outfile (fasta/fastq with hit tags, SAM or BAM)
+    #[clap(long)]
+    hits: String,
+    /// the hit value the ROC and PR curves rank by: log_odds, posterior or score
+    #[clap(long, default_value = "log_odds")]
+    score: String,
+    /// write the ROC and PR curves (one line per score threshold) to this tab separated file
+    #[clap(long)]
+    curves: Option<String>,
+    /// write the evaluation as JSON to this file
+    #[clap(long)]
+    json: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct InspectOpts {
+    /// the germline database (species=path, repeatable - the same as for the scan)
+    #[clap(short, long, multiple_occurrences = true, required = true)]
+    database: Vec<String>,
+    /// the per locus and position table: segment, coverage, A/C/G/T/gap emissions, entropy and relative entropy
+    /// ('-' for stdout; compressed by extension)
     #[clap(short, long)]
     outfile: String,
+    /// write the sequence logo letter heights (bits) per locus and position to this tab separated file
+    #[clap(long)]
+    logo: Option<String>,
+    #[clap(flatten)]
+    model: ModelOpts,
+}
+
+/// the output file - annotated reads or alignments
+enum HitWriter {
+    Fastx(Output),
+    Sam(SamWriter),
+}
+
+impl HitWriter {
+    /// write the best hit and the other segments of the read (if any)
+    fn write(&mut self, record: &Seqrec, segments: &[ScanResult]) -> io::Result<()> {
+        let best = match segments.first() {
+            Some(best) => best,
+            None => return Ok(()),
+        };
+        match self {
+            HitWriter::Fastx(out) if segments.len() > 1 => {
+                record.write_annotated(out, &format!("{} segments={}", best.tags(), scan_result::segments_text(segments)))
+            },
+            HitWriter::Fastx(out) => record.write_annotated(out, &best.tags()),
+            HitWriter::Sam(out) => out.write_segments(record, segments),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            HitWriter::Fastx(out) => out.finish(),
+            HitWriter::Sam(out) => out.finish(),
+        }
+    }
 }
 
 fn main() {
-    let opts: Opts = Opts::parse();
+    // the options of a bare call (hmm_mapper -d ... -f ... -o ...) belong to scan
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let known = ["scan", "simulate", "evaluate", "inspect", "help", "-h", "--help", "-V", "--version"];
+    if args.get(1).is_some_and(|arg| !known.iter().any(|k| arg == k)) {
+        args.insert(1, OsString::from("scan"));
+    }
+    match Cli::parse_from(args).command {
+        Command::Scan(opts) => scan(opts),
+        Command::Simulate(opts) => simulate(opts),
+        Command::Evaluate(opts) => evaluate(opts),
+        Command::Inspect(opts) => inspect(opts),
+    }
+}
 
-    let hmm = VDJmodeler::build_models(opts.database);
+fn scan(opts: ScanOpts) {
+
+    let databases: Vec<(String, String)> = opts.database.iter()
+        .map(|arg| VDJmodeler::parse_database_arg(arg))
+        .collect();
+    let started = Instant::now();
+    let mut hmm = match VDJmodeler::build_species_models_with(&databases, &opts.model.config()) {
+        Ok(hmm) => hmm,
+        Err(err) => {
+            eprintln!("Could not build the model: {err}");
+            std::process::exit(1);
+        }
+    };
 
-    println!("Initialized HMM with {} states.", hmm.states().len());
+    if let Some(species) = &opts.species {
+        if !hmm.restrict_to_species(species) {
+            let known: Vec<String> = hmm.species_list().into_iter().filter(|s| !s.is_empty()).collect();
+            if known.is_empty() {
+                eprintln!("Species {species} is not part of the model - the databases are not species tagged (use -d species=path)");
+            } else {
+                eprintln!("Species {species} is not part of the model - known species: {}", known.join(", "));
+            }
+            std::process::exit(1);
+        }
+        eprintln!("Restricted the scan to the {species} models.");
+    }
 
-    let fasta_path = &opts.outfile;
-    let fasta_file = File::create(fasta_path).expect("Unable to create FASTA file");
-    let mut fasta_writer = BufWriter::new(fasta_file);
+    if let Some(probability) = opts.locus_switches {
+        if !(0.0..1.0).contains(&probability) || probability * (hmm.num_loci() as f64 - 1.0) >= 1.0 {
+            eprintln!("The locus switch probability {probability} is too high for {} loci", hmm.num_loci());
+            std::process::exit(1);
+        }
+        hmm.allow_locus_switches(probability);
+    }
 
-    // Define the chunk size
-    let chunk_size = 10000; // Adjust as needed
+    eprintln!("Initialized HMM with {} states.", hmm.states().len());
+    let mut report = RunReport::new(&hmm);
+    let mut scanner = Scanner::new(hmm);
+    // the command line scans both strands and calls the genes of every hit
+    scanner.both_strands = true;
+    scanner.gene_calls = true;
+    if let Some(k) = opts.kmer {
+        if let Err(err) = scanner.enable_prefilter(k) {
+            eprintln!("--kmer {k}: {err}");
+            std::process::exit(1);
+        }
+        scanner.prefilter_audit = opts.prefilter_audit;
+    }
+    if opts.start_candidates == 0 {
+        eprintln!("--start-candidates needs at least one start per locus");
+        std::process::exit(1);
+    }
+    scanner.start_candidates = opts.start_candidates;
+    scanner.report_starts = opts.report_starts;
+    if opts.indels {
+        scanner.gaps = Some(GapModel::default());
+        scanner.band = (opts.band > 0).then_some(opts.band);
+    }
+    if opts.long_reads {
+        if opts.max_segments == 0 {
+            eprintln!("--max-segments needs at least one segment per read");
+            std::process::exit(1);
+        }
+        scanner.band = (opts.band > 0).then_some(opts.band);
+        scanner.enable_long_reads(opts.max_segments);
+        scanner.min_segment_log_odds = opts.min_segment_log_odds;
+    }
+    scanner.switch_detection = opts.locus_switches.is_some();
+    if let Some(kind) = &opts.decoy {
+        scanner.decoy = match DecoyKind::from_name(kind) {
+            Some(kind) => Some(kind),
+            None => {
+                eprintln!("Unknown decoy {kind} - use reverse or shuffle");
+                std::process::exit(1);
+            }
+        };
+    }
 
-    let mut reader = match parse_fastx_file(&opts.fastq) {
-        Ok(reader) => reader,
+    let out_path = &opts.outfile;
+    if let Some(reference) = &opts.reference {
+        if let Err(err) = sam::write_reference(scanner.hmm(), reference) {
+            eprintln!("Could not write the reference: {err}");
+            std::process::exit(1);
+        }
+    }
+    let writer = match SamFormat::from_path(out_path) {
+        Some(format) => {
+            let command_line: Vec<String> = std::env::args().collect();
+            SamWriter::create(out_path, format, scanner.hmm(), &command_line.join(" ")).map(HitWriter::Sam)
+        },
+        None => Output::create(out_path).map(HitWriter::Fastx),
+    };
+    let mut writer = match writer {
+        Ok(writer) => writer,
         Err(err) => {
-            panic!("File {} Read Error: {}", &opts.fastq, err);
+            eprintln!("Could not create the outfile: {err}");
+            std::process::exit(1);
         }
     };
 
-    let mut batch = Vec::with_capacity(chunk_size);
-    let mut record_count = 0;
+    let threads = opts.threads.unwrap_or_else(|| Pipeline::default().threads);
+    let pipeline = Pipeline::new(threads, opts.batch_size);
 
-    while let Some(record) = &reader.next() {
-        match record {
-            Ok(res) => {
-                batch.push( Seqrec::new( &res.id(), &res.seq()));
-                record_count += 1;
+    let mut filter = SamFilter {
+        unmapped_only: opts.unmapped_only,
+        ..SamFilter::default()
+    };
+    if let Some(regions) = &opts.regions {
+        filter.regions = match Region::parse_list(regions) {
+            Ok(regions) => regions,
+            Err(err) => {
+                eprintln!("Could not parse the regions: {err}");
+                std::process::exit(1);
+            }
+        };
+    }
+    filter.keep_tags = opts.keep_tags.split(',')
+        .filter_map(|tag| <[u8; 2]>::try_from(tag.trim().as_bytes()).ok())
+        .collect();
 
-                if batch.len() >= chunk_size {
-                    // Process the current batch
-                    process_batch(&batch, &hmm, &mut fasta_writer);
-                    batch.clear(); // Clear the batch for the next set of records
+    let mut reader = match streams::open_records(&opts.fastq, filter) {
+        Ok(reader) => reader,
+        Err(err) => {
+            eprintln!("Could not read the reads: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let records = std::iter::from_fn(move || {
+        for record in reader.by_ref() {
+            match record {
+                Ok(record) => return Some(record),
+                Err(err) => {
+                    eprintln!("Error reading record: {}", err);
                 }
             }
-            Err(err) => {
-                eprintln!("Error reading record: {}", err);
+        }
+        None
+    });
+
+    report.timing.model = started.elapsed();
+    let scan_started = Instant::now();
+    // the q-values need the scores of the whole sample
+    let mut pending: Vec<(Seqrec, Vec<ScanResult>)> = Vec::new();
+    let keep_hits = scanner.decoy.is_some();
+    // best hits with a locus switch per from>to pair
+    let mut switches: BTreeMap<String, usize> = BTreeMap::new();
+    let mut switched_reads = 0;
+    // reads with more than one segment and their segments
+    let (mut segmented_reads, mut segments) = (0, 0);
+    let segment_mode = scanner.max_segments > 1;
+    let stats = pipeline.run(records, &scanner, |record, mut hits| {
+        report.add(record.seq(), &hits);
+        if let Some(best) = hits.first().filter(|best| !best.switches.is_empty()) {
+            switched_reads += 1;
+            for switch in &best.switches {
+                *switches.entry(format!("{}>{}", switch.from, switch.to)).or_insert(0) += 1;
             }
         }
+        if !segment_mode {
+            hits.truncate(1);
+        } else if hits.len() > 1 {
+            segmented_reads += 1;
+            segments += hits.len();
+        }
+        if keep_hits && !hits.is_empty() {
+            pending.push((record, hits));
+        } else {
+            writer.write(&record, &hits)?;
+        }
+        Ok(())
+    });
+    let stats = match stats {
+        Ok(stats) => stats,
+        Err(err) => {
+            eprintln!("Could not write the results: {err}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(probability) = opts.locus_switches {
+        eprintln!("{switched_reads} best hits switch locus: {switches:?}");
+        report.note("locus_switches", json!({
+            "probability": probability,
+            "reads": switched_reads,
+            "switches": switches,
+        }));
+    }
+    if segment_mode {
+        eprintln!("{segmented_reads} reads hold more than one rearrangement ({segments} segments).");
+        report.note("long_reads", json!({
+            "max_segments": scanner.max_segments,
+            "segmented_reads": segmented_reads,
+            "segments": segments,
+        }));
+    }
+    if let (Some(kind), Some(decoys)) = (scanner.decoy, scanner.decoy_scores()) {
+        let decoys = decoys.scores();
+        let targets: Vec<f64> = pending.iter().map(|(_, hits)| hits[0].log_odds).collect();
+        let q_values = decoy::q_values(&targets, &decoys);
+        let mut accepted = 0;
+        let mut threshold = f64::INFINITY;
+        for ((record, mut hits), q_value) in pending.into_iter().zip(q_values) {
+            if q_value > opts.fdr {
+                continue
+            }
+            accepted += 1;
+            threshold = threshold.min(hits[0].log_odds);
+            hits[0].q_value = Some(q_value);
+            if let Err(err) = writer.write(&record, &hits) {
+                eprintln!("Could not write the results: {err}");
+                std::process::exit(1);
+            }
+        }
+        eprintln!(
+            "Target-decoy ({} decoys): {} target and {} decoy hits, {} hits at FDR {} (log odds >= {:.3}).",
+            kind.name(), targets.len(), decoys.len(), accepted, opts.fdr, threshold
+        );
+        report.note("decoy", json!({
+            "kind": kind.name(),
+            "target_hits": targets.len(),
+            "decoy_hits": decoys.len(),
+            "fdr": opts.fdr,
+            "accepted_hits": accepted,
+            "log_odds_threshold": if accepted > 0 { json!(threshold) } else { json!(null) },
+        }));
+    }
+    if let Err(err) = writer.finish() {
+        eprintln!("Could not write the results: {err}");
+        std::process::exit(1);
+    }
+    report.timing.scan = scan_started.elapsed();
+
+    eprintln!("{} reads scanned in {} batches -> {} potential VDJ reads found.", stats.reads, stats.batches, stats.hits);
+    if let Some(prefilter) = scanner.prefilter_stats() {
+        eprintln!("{}", prefilter.summary());
+        report.note("prefilter", json!({
+            "reads": prefilter.reads.load(Ordering::Relaxed),
+            "passed": prefilter.passed.load(Ordering::Relaxed),
+            "lost_hits": prefilter.lost_hits.load(Ordering::Relaxed),
+        }));
+    }
+    if let Some(band) = scanner.band_stats() {
+        eprintln!("{}", band.summary());
+        report.note("banded_alignment", json!({
+            "alignments": band.alignments.load(Ordering::Relaxed),
+            "full_dp_fallbacks": band.fallbacks.load(Ordering::Relaxed),
+        }));
+    }
+
+    if !report.hits_per_species.is_empty() {
+        let mut counts: Vec<(&String, &usize)> = report.hits_per_species.iter().collect();
+        counts.sort_by(|a, b| b.1.cmp(a.1));
+        eprintln!("Reads per most likely species: {:?}", counts);
+        eprintln!("Most likely species for this sample: {}", counts[0].0);
     }
 
-    // Process any remaining records in the batch
-    if !batch.is_empty() {
-        process_batch(&batch, &hmm, &mut fasta_writer);
+    report.timing.total = started.elapsed();
+    eprint!("{}", report.text());
+    if let Some(path) = &opts.summary {
+        let written = File::create(path)
+            .and_then(|mut file| writeln!(file, "{:#}", report.to_json()));
+        if let Err(err) = written {
+            eprintln!("Could not write the summary to {path}: {err}");
+            std::process::exit(1);
+        }
     }
 
-    println!("Processing completed. Results written to {}", fasta_path);
+    eprintln!("Processing completed. Results written to {}", out_path);
 }
 
-fn process_batch(batch: &[Seqrec], hmm: &HMM, fasta_writer: &mut BufWriter<File>) {
-    let chunk_size = 100;
-    let results : Vec<Vec<String>> = batch
-    .par_chunks(chunk_size) // Specify the chunk size, e.g., 100 or another appropriate value
-    .map(|chunk| {
-        let mut result = Vec::<String>::with_capacity(chunk_size);
-        for record in chunk.iter() {
-            let seq = record.seq();
-            if let Some(hmm_result) = hmm.forward_algorithm(&seq) {
-
-                let id = String::from_utf8_lossy(&record.id()).to_string() + &format!("{:?}", hmm_result);
-                let seq_str = String::from_utf8_lossy(&seq);
-                let fasta_entry = format!(">{}\n{}\n", id, seq_str);
-                result.push( fasta_entry );
+fn simulate(opts: SimulateOpts) {
+    let list = |value: &Option<String>| -> Vec<String> {
+        value.iter().flat_map(|v| v.split(',')).map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect()
+    };
+    let mut loci = Vec::new();
+    for locus in list(&opts.loci) {
+        match SequenceModel::from_locus(&locus) {
+            Some(locus) => loci.push(locus),
+            None => {
+                eprintln!("Unknown locus {locus} - use IGH, IGK, IGL, TRA, TRB, TRG or TRD");
+                std::process::exit(1);
             }
         }
-        return result
-    })
-    .collect();
+    }
+    let config = SimConfig {
+        read_length: opts.read_length,
+        loci,
+        genes: list(&opts.genes),
+        max_trim: opts.max_trim,
+        max_p: opts.max_p,
+        max_n: opts.max_n,
+        constant_length: opts.constant_length,
+        shm_rate: opts.shm,
+        error_rate: opts.errors,
+        reverse_fraction: opts.reverse,
+        decoy_fraction: opts.decoys,
+        seed: opts.seed,
+    };
 
-    let mut it = 0;
-    for result_vec in results {
-        it += result_vec.len();
-        for result in &result_vec{
-            //println!( "{:?}", result);
-            write!(fasta_writer, "{}", result).expect("Failed to write to FASTA file");
+    let (species, path) = VDJmodeler::parse_database_arg(&opts.database);
+    let simulator = Simulator::from_database(&species, &path, config)
+        .and_then(|simulator| match &opts.background {
+            Some(background) => simulator.with_background(background),
+            None => Ok(simulator),
+        });
+    let mut simulator = match simulator {
+        Ok(simulator) => simulator,
+        Err(err) => {
+            eprintln!("Could not set up the simulation: {err}");
+            std::process::exit(1);
         }
+    };
+    eprintln!("Simulating {} reads from the loci {:?}.", opts.reads, simulator.loci());
+
+    let written = Output::create(&opts.outfile).and_then(|out| Ok((out, Output::create(&opts.truth)?)));
+    let (mut out, mut truth) = match written {
+        Ok(files) => files,
+        Err(err) => {
+            eprintln!("Could not create the outfiles: {err}");
+            std::process::exit(1);
+        }
+    };
+    let written = (|| -> io::Result<()> {
+        writeln!(truth, "{}", Truth::HEADER)?;
+        for _ in 0..opts.reads {
+            let read = simulator.next_read();
+            read.record.write_annotated(&mut out, "")?;
+            read.truth.write(&mut truth)?;
+        }
+        out.finish()?;
+        truth.finish()
+    })();
+    if let Err(err) = written {
+        eprintln!("Could not write the simulated reads: {err}");
+        std::process::exit(1);
     }
-    println!("Batch processed -> {it} potential VDJ reads found.");
+    eprintln!("Reads written to {}, the truth table to {}", opts.outfile, opts.truth);
 }
 
-/*fn main() {
-
-    let opts: Opts = Opts::parse();
-
-    let hmm = VDJmodeler::build_models( opts.database );
-
-    println!("Initialized HMM with {} states.", hmm.states().len());
+fn evaluate(opts: EvaluateOpts) {
+    let score = match ScoreField::from_name(&opts.score) {
+        Some(score) => score,
+        None => {
+            eprintln!("Unknown score {} - use log_odds, posterior or score", opts.score);
+            std::process::exit(1);
+        }
+    };
+    let truth = evaluate::read_truth_table(&opts.truth)
+        .and_then(|truth| Ok((truth, evaluate::read_calls(&opts.hits, score)?)));
+    let (truth, calls) = match truth {
+        Ok(tables) => tables,
+        Err(err) => {
+            eprintln!("Could not read the input: {err}");
+            std::process::exit(1);
+        }
+    };
+    let evaluation = Evaluation::new(&truth, &calls, score);
+    print!("{}", evaluation.text());
+
+    if let Some(path) = &opts.curves {
+        let written = File::create(path).and_then(|file| {
+            let mut out = io::BufWriter::new(file);
+            evaluation.write_curves(&mut out)?;
+            out.flush()
+        });
+        if let Err(err) = written {
+            eprintln!("Could not write the curves to {path}: {err}");
+            std::process::exit(1);
+        }
+    }
+    if let Some(path) = &opts.json {
+        let written = File::create(path)
+            .and_then(|mut file| writeln!(file, "{:#}", evaluation.to_json()));
+        if let Err(err) = written {
+            eprintln!("Could not write the evaluation to {path}: {err}");
+            std::process::exit(1);
+        }
+    }
+}
 
-    let mut reader = match parse_fastx_file(&opts.fastq) {
-        Ok(reader) => reader,
+fn inspect(opts: InspectOpts) {
+    let databases: Vec<(String, String)> = opts.database.iter()
+        .map(|arg| VDJmodeler::parse_database_arg(arg))
+        .collect();
+    let models = match VDJmodeler::collect_species_models(&databases) {
+        Ok(models) => models,
         Err(err) => {
-            panic!("File {} Read Error: {}",&opts.fastq, err);
+            eprintln!("Could not build the model: {err}");
+            std::process::exit(1);
         }
     };
+    let hmm = HMM::from_sequence_models_with(models.clone(), &opts.model.config());
+    let infos = inspect::inspect(&hmm, &models);
 
-    // Iterate over the records
-    while let Some(record) = reader.next() {
-        let read = match record{
-            Ok( res ) => {
-                if let Some( hmm_result ) = hmm.forward_algorithm(&res.seq()){
-                    //if hmm_result.iter().take(hmm_result.len() - 1).any(|x| x.1 < 1e-08){
-                        /*println!( "This {:?} is what the HMM tells me for \n{:?}\n{:?}",
-                            hmm_result,
-                            String::from_utf8_lossy( &res.id() ),
-                            String::from_utf8_lossy( &res.seq() ),
-                        );*/
-                        println!( ">{}\n{}", 
-                            String::from_utf8_lossy( &res.id() ),
-                            String::from_utf8_lossy( &res.seq() ),
-                            );
-                    //}
-                    
-                }/*else {
-                    eprintln!("Couln not read this sequence: {}",String::from_utf8_lossy( &res.seq() ) )
-                }*/
-                
-            },
-            Err(err) => {
-                eprintln!("could not read from fasta:\n{err}");
-                continue
-            }
-        };
+    let mut out = match Output::create(&opts.outfile) {
+        Ok(out) => out,
+        Err(err) => {
+            eprintln!("Could not create the outfile: {err}");
+            std::process::exit(1);
+        }
+    };
+    if let Err(err) = inspect::write_tsv(&mut out, &infos).and_then(|_| out.finish()) {
+        eprintln!("Could not write the table to {}: {err}", opts.outfile);
+        std::process::exit(1);
+    }
+    if let Some(path) = &opts.logo {
+        let written = File::create(path).and_then(|file| {
+            let mut out = io::BufWriter::new(file);
+            inspect::write_logo_matrix(&mut out, &infos)?;
+            out.flush()
+        });
+        if let Err(err) = written {
+            eprintln!("Could not write the logo matrix to {path}: {err}");
+            std::process::exit(1);
+        }
     }
 
+    // a short overview: how informative each locus model is where it has germline data
+    for locus in 0..hmm.num_loci() {
+        let label = hmm.label(locus);
+        let covered: Vec<&inspect::PositionInfo> = infos.iter().filter(|info| info.locus == label && info.coverage > 0).collect();
+        let mean = |value: fn(&inspect::PositionInfo) -> f64| covered.iter().map(|info| value(info)).sum::<f64>() / covered.len().max(1) as f64;
+        eprintln!(
+            "{label}: {} positions with germline data, mean entropy {:.3} bits, mean relative entropy {:.3} bits",
+            covered.len(), mean(|info| info.entropy), mean(|info| info.relative_entropy)
+        );
+    }
 }
-
-*/
\ No newline at end of file
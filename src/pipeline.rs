@@ -0,0 +1,230 @@
+// pipeline.rs
+
+use crate::errors::{HmmError, Result};
+use crate::scan_result::ScanResult;
+use crate::scanner::Scanner;
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// One input read.
+#[derive(Clone, Debug, Default)]
+pub struct Seqrec{
+    /// the full header line without '>'/'@' - the read name and the comment
+    pub id:Vec<u8>,
+    pub seq:Vec<u8>,
+    /// the quality string of fastq input
+    pub qual:Option<Vec<u8>>,
+}
+
+impl Seqrec{
+    pub fn new (id:&[u8], seq:&[u8] ) -> Self{
+        Self{
+            id: id.to_vec(),
+            seq: seq.to_vec(),
+            qual: None,
+        }
+    }
+
+    /// a fastq record
+    pub fn with_qual (id:&[u8], seq:&[u8], qual:&[u8] ) -> Self{
+        Self{
+            id: id.to_vec(),
+            seq: seq.to_vec(),
+            qual: Some(qual.to_vec()),
+        }
+    }
+
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+    /// the read name - the header up to the first whitespace
+    pub fn name(&self) -> &[u8] {
+        match self.id.iter().position(|c| c.is_ascii_whitespace()) {
+            Some(end) => &self.id[..end],
+            None => &self.id,
+        }
+    }
+    /// the header comment after the read name (empty if there is none)
+    pub fn comment(&self) -> &[u8] {
+        match self.id.iter().position(|c| c.is_ascii_whitespace()) {
+            Some(end) => &self.id[end + 1..],
+            None => &[],
+        }
+    }
+    pub fn seq(&self) -> &[u8] {
+        &self.seq
+    }
+    pub fn qual(&self) -> Option<&[u8]> {
+        self.qual.as_deref()
+    }
+
+    /// Write the record in its input format (fastq if it has qualities, fasta otherwise)
+    /// with the annotation appended to the original header comment.
+    pub fn write_annotated<W: Write>(&self, out: &mut W, annotation: &str) -> std::io::Result<()> {
+        let marker = if self.qual.is_some() { b'@' } else { b'>' };
+        out.write_all(&[marker])?;
+        out.write_all(&self.id)?;
+        if !annotation.is_empty() {
+            out.write_all(b" ")?;
+            out.write_all(annotation.as_bytes())?;
+        }
+        out.write_all(b"\n")?;
+        out.write_all(&self.seq)?;
+        out.write_all(b"\n")?;
+        if let Some(qual) = &self.qual {
+            out.write_all(b"+\n")?;
+            out.write_all(qual)?;
+            out.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// A batch of reads with its position in the input.
+type Batch = (usize, Vec<Seqrec>);
+/// A scanned batch - every read with its (possibly empty) scan results.
+type ScannedBatch = (usize, Vec<(Seqrec, Vec<ScanResult>)>);
+
+/// Counts returned by Pipeline::run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    pub reads: usize,
+    /// reads with at least one scan result
+    pub hits: usize,
+    pub batches: usize,
+}
+
+/// A reader thread, a pool of scanner threads and a writer thread connected by bounded queues.
+/// At most queue_depth batches wait in each queue and the reader only reads a batch with a permit the writer
+/// returns once the batch is written, so at most Pipeline::in_flight batches are held between reading and writing
+/// (including the early batches the writer keeps until it is their turn) - the memory use is independent of the input size.
+/// The results are handed to the sink in input order.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    pub threads: usize,
+    pub batch_size: usize,
+    pub queue_depth: usize,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        Self::new(threads, 1000)
+    }
+}
+
+impl Pipeline {
+    pub fn new(threads: usize, batch_size: usize) -> Self {
+        let threads = threads.max(1);
+        Self {
+            threads,
+            batch_size: batch_size.max(1),
+            queue_depth: 2 * threads,
+        }
+    }
+
+    /// the number of batches read but not yet written: one per queue slot and worker
+    pub fn in_flight(&self) -> usize {
+        (2 * self.queue_depth + self.threads).max(1)
+    }
+
+    /// Scan all records and pass every record with its scan results to sink - in input order.
+    /// With scanner.max_segments above 1 the results are the segments of Scanner::scan_segments.
+    /// The sink runs on the writer thread; an error from the sink stops the pipeline.
+    pub fn run<I, W>(&self, records: I, scanner: &Scanner, mut sink: W) -> Result<PipelineStats>
+    where
+        I: Iterator<Item = Seqrec> + Send,
+        W: FnMut(Seqrec, Vec<ScanResult>) -> std::io::Result<()> + Send,
+    {
+        let (batch_tx, batch_rx) = sync_channel::<Batch>(self.queue_depth);
+        let (result_tx, result_rx) = sync_channel::<ScannedBatch>(self.queue_depth);
+        let batch_rx = Arc::new(Mutex::new(batch_rx));
+        let batch_size = self.batch_size;
+        // the reader takes a permit per batch, the writer returns it once the batch is written
+        let (permit_tx, permit_rx) = sync_channel::<()>(self.in_flight());
+        for _ in 0..self.in_flight() {
+            permit_tx.send(()).expect("the permit queue has room for all permits");
+        }
+
+        thread::scope(|scope| {
+            // reader
+            scope.spawn(move || {
+                let mut records = records;
+                let mut id = 0;
+                // waits while in_flight batches are not written yet
+                while permit_rx.recv().is_ok() {
+                    let batch: Vec<Seqrec> = records.by_ref().take(batch_size).collect();
+                    if batch.is_empty() {
+                        break
+                    }
+                    // the workers are gone if the writer failed
+                    if batch_tx.send((id, batch)).is_err() {
+                        break
+                    }
+                    id += 1;
+                }
+            });
+
+            // workers
+            for _ in 0..self.threads {
+                let batch_rx = Arc::clone(&batch_rx);
+                let result_tx = result_tx.clone();
+                let mut scanner = scanner.clone();
+                scope.spawn(move || {
+                    while let Some((id, batch)) = next_batch(&batch_rx) {
+                        let scanned = batch.into_iter()
+                            .map(|record| {
+                                let hits = if scanner.max_segments > 1 {
+                                    scanner.scan_segments(record.seq())
+                                } else {
+                                    scanner.scan(record.seq())
+                                };
+                                (record, hits)
+                            })
+                            .collect();
+                        if result_tx.send((id, scanned)).is_err() {
+                            break
+                        }
+                    }
+                });
+            }
+            // only the workers may keep the queues alive - otherwise a failing writer would block the reader
+            drop(batch_rx);
+            drop(result_tx);
+
+            // writer - keeps early batches until all batches before them are written, at most in_flight of them
+            let writer = scope.spawn(move || -> Result<PipelineStats> {
+                let mut stats = PipelineStats::default();
+                let mut pending: BTreeMap<usize, Vec<(Seqrec, Vec<ScanResult>)>> = BTreeMap::new();
+                let mut next = 0;
+                for (id, scanned) in result_rx {
+                    pending.insert(id, scanned);
+                    while let Some(scanned) = pending.remove(&next) {
+                        for (record, hits) in scanned {
+                            stats.reads += 1;
+                            if !hits.is_empty() {
+                                stats.hits += 1;
+                            }
+                            sink(record, hits).map_err(|err| HmmError::io("the output", err))?;
+                        }
+                        stats.batches += 1;
+                        next += 1;
+                        // the reader may have stopped after the last batch
+                        let _ = permit_tx.send(());
+                    }
+                }
+                Ok(stats)
+            });
+            writer.join().expect("the writer thread panicked")
+        })
+    }
+}
+
+fn next_batch(batch_rx: &Mutex<Receiver<Batch>>) -> Option<Batch> {
+    // a poisoned lock means another worker panicked - stop this one, too
+    batch_rx.lock().ok()?.recv().ok()
+}
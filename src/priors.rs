@@ -0,0 +1,233 @@
+// priors.rs
+
+use crate::HMM::HMM;
+use crate::VDJmodeler::{Chain, HMMmodel};
+
+/// One component of a Dirichlet mixture over the bases A, G, C, T (the model order, see HMM::char2pos).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirichletComponent {
+    /// the prior probability of the component
+    pub weight: f64,
+    pub alpha: [f64; 4],
+}
+
+/// How the emission probabilities of a locus are estimated from its germline base counts.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmissionPrior {
+    /// the observed frequencies with unseen bases set to 0.0001
+    Floor,
+    /// strength pseudocounts spread evenly over the bases
+    Uniform { strength: f64 },
+    /// strength pseudocounts spread like the base composition of the locus
+    Background { strength: f64 },
+    /// a background component mixed with one conserved component per base - columns dominated by one base
+    /// stay sharp while mixed columns fall back to the composition of the locus
+    BackgroundMixture { strength: f64 },
+    /// any Dirichlet mixture
+    Custom(Vec<DirichletComponent>),
+}
+
+impl EmissionPrior {
+    /// 'floor', 'uniform', 'background' or 'mixture' with this strength
+    pub fn from_name(name: &str, strength: f64) -> Option<Self> {
+        match name {
+            "floor" => Some(EmissionPrior::Floor),
+            "uniform" => Some(EmissionPrior::Uniform { strength }),
+            "background" => Some(EmissionPrior::Background { strength }),
+            "mixture" => Some(EmissionPrior::BackgroundMixture { strength }),
+            _ => None,
+        }
+    }
+
+    /// the mixture components for a locus with this base composition - None for Floor
+    fn components(&self, background: &[f64; 4]) -> Option<Vec<DirichletComponent>> {
+        match self {
+            EmissionPrior::Floor => None,
+            EmissionPrior::Uniform { strength } => Some(vec![DirichletComponent { weight: 1.0, alpha: [strength / 4.0; 4] }]),
+            EmissionPrior::Background { strength } => Some(vec![DirichletComponent { weight: 1.0, alpha: background.map(|p| p * strength) }]),
+            EmissionPrior::BackgroundMixture { strength } => {
+                let mut ret = vec![DirichletComponent { weight: 0.5, alpha: background.map(|p| p * strength) }];
+                for base in 0..4 {
+                    let mut alpha = [0.05 * strength; 4];
+                    alpha[base] = 0.85 * strength;
+                    ret.push(DirichletComponent { weight: 0.125, alpha });
+                }
+                Some(ret)
+            },
+            EmissionPrior::Custom(components) => Some(components.clone()),
+        }
+    }
+
+    /// The A, G, C, T and gap probabilities of one model column with these (weighted) counts.
+    /// background is the base composition of the locus.
+    pub fn estimate(&self, counts: &[f64; 5], background: &[f64; 4]) -> Vec<f64> {
+        let components = match self.components(background) {
+            Some(components) => components,
+            None => return floor_estimate(counts),
+        };
+        let bases = [counts[0], counts[1], counts[2], counts[3]];
+        let total: f64 = bases.iter().sum();
+        // the posterior probability of every component given the counts (the multinomial coefficient cancels)
+        let log_posterior: Vec<f64> = components.iter().map(|component| {
+            let strength: f64 = component.alpha.iter().sum();
+            let mut ret = component.weight.ln() + ln_gamma(strength) - ln_gamma(total + strength);
+            for (n, a) in bases.iter().zip(&component.alpha) {
+                ret += ln_gamma(n + a) - ln_gamma(*a);
+            }
+            ret
+        }).collect();
+        let max = log_posterior.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let posterior: Vec<f64> = log_posterior.iter().map(|p| (p - max).exp()).collect();
+        let sum: f64 = posterior.iter().sum();
+
+        let mut ret = vec![0.0; 5];
+        let mut strength = 0.0;
+        for (component, p) in components.iter().zip(&posterior) {
+            let p = p / sum;
+            let component_strength: f64 = component.alpha.iter().sum();
+            strength += p * component_strength;
+            for (value, (n, a)) in ret.iter_mut().zip(bases.iter().zip(&component.alpha)) {
+                *value += p * (n + a) / (total + component_strength);
+            }
+        }
+        // gaps take their share of the column without a prior of their own
+        let gap = counts[4] / (total + counts[4] + strength);
+        for value in ret.iter_mut().take(4) {
+            *value *= 1.0 - gap;
+        }
+        ret[4] = gap;
+        ret
+    }
+}
+
+/// the frequencies of the locus itself with unseen symbols set to 0.0001 - uniform without counts
+fn floor_estimate(counts: &[f64; 5]) -> Vec<f64> {
+    let total: f64 = counts.iter().sum();
+    let mut ret: Vec<f64> = counts.iter()
+        .map(|count| if *count > 0.0 { count / total } else { 0.0001 })
+        .collect();
+    let sum: f64 = ret.iter().sum();
+    for value in ret.iter_mut() {
+        *value /= sum;
+    }
+    ret
+}
+
+/// How HMM::from_sequence_models_with turns the germline sequences into emission probabilities.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmissionConfig {
+    pub prior: EmissionPrior,
+    /// weight the germlines of every locus segment with Henikoff position based weights,
+    /// so that many near identical alleles count about as much as one distinct gene
+    pub sequence_weights: bool,
+}
+
+impl Default for EmissionConfig {
+    fn default() -> Self {
+        Self {
+            prior: EmissionPrior::Background { strength: 2.0 },
+            sequence_weights: true,
+        }
+    }
+}
+
+impl EmissionConfig {
+    /// the A, G, C, T, gap counts of every position of the model - weighted if sequence_weights is set
+    /// and the model knows its germlines, the collector counts otherwise
+    pub fn counts(&self, model: &HMMmodel) -> Vec<[f64; 5]> {
+        if !self.sequence_weights || model.germlines.is_empty() {
+            return model.collector.iter().map(|collector| {
+                let mut ret = [0.0; 5];
+                for (value, count) in ret.iter_mut().zip(&collector.states) {
+                    *value = *count as f64;
+                }
+                ret
+            }).collect()
+        }
+        let mut ret = vec![[0.0; 5]; model.collector.len()];
+        for segment in [Chain::V, Chain::D, Chain::J] {
+            let germlines: Vec<(usize, &[u8])> = model.germlines.iter()
+                .filter(|germline| germline.segment == segment)
+                .map(|germline| (germline.start, germline.sequence.as_slice()))
+                .collect();
+            for ((start, sequence), weight) in germlines.iter().zip(henikoff_weights(&germlines)) {
+                for (offset, base) in sequence.iter().enumerate() {
+                    for id in HMM::iupac_char2pos(*base).unwrap_or_default() {
+                        ret[start + offset][id] += weight;
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// Henikoff position based weights of sequences placed at (start, sequence) - gaps ('.') are ignored.
+/// Every sequence gets the mean over its bases of 1 / (distinct bases in the column * sequences sharing its base);
+/// the weights are scaled to sum up to the number of sequences.
+pub fn henikoff_weights(sequences: &[(usize, &[u8])]) -> Vec<f64> {
+    let end = sequences.iter().map(|(start, sequence)| start + sequence.len()).max().unwrap_or(0);
+    // the sequences with each upper case byte per column
+    let mut columns: Vec<Vec<(u8, usize)>> = vec![Vec::new(); end];
+    for (start, sequence) in sequences {
+        for (offset, base) in sequence.iter().enumerate() {
+            let base = base.to_ascii_uppercase();
+            if base == b'.' {
+                continue
+            }
+            let column = &mut columns[start + offset];
+            match column.iter_mut().find(|(b, _)| *b == base) {
+                Some((_, count)) => *count += 1,
+                None => column.push((base, 1)),
+            }
+        }
+    }
+    let mut ret: Vec<f64> = sequences.iter().map(|(start, sequence)| {
+        let mut sum = 0.0;
+        let mut bases = 0;
+        for (offset, base) in sequence.iter().enumerate() {
+            let base = base.to_ascii_uppercase();
+            if base == b'.' {
+                continue
+            }
+            let column = &columns[start + offset];
+            let count = column.iter().find(|(b, _)| *b == base).map_or(1, |(_, count)| *count);
+            sum += 1.0 / (column.len() * count) as f64;
+            bases += 1;
+        }
+        if bases > 0 { sum / bases as f64 } else { 0.0 }
+    }).collect();
+    let total: f64 = ret.iter().sum();
+    if total > 0.0 {
+        for weight in ret.iter_mut() {
+            *weight *= sequences.len() as f64 / total;
+        }
+    }
+    ret
+}
+
+/// ln(Gamma(x)) for x > 0 (Lanczos approximation, g = 7)
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // the reflection formula
+        return (std::f64::consts::PI / (std::f64::consts::PI * x).sin()).ln() - ln_gamma(1.0 - x);
+    }
+    let x = x - 1.0;
+    let mut sum = COEFFICIENTS[0];
+    for (i, coefficient) in COEFFICIENTS.iter().enumerate().skip(1) {
+        sum += coefficient / (x + i as f64);
+    }
+    let t = x + 7.5;
+    0.5 * (2.0 * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + sum.ln()
+}
@@ -0,0 +1,234 @@
+// report.rs
+
+use crate::scan_result::{ScanResult, Strand};
+use crate::HMM::HMM;
+
+use serde_json::{json, Value};
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Fixed width bins over [min, min + width * bins] - the last bin includes its upper edge (e.g. a posterior of 1);
+/// values outside land in the under- and overflow counts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub min: f64,
+    pub width: f64,
+    pub counts: Vec<usize>,
+    pub underflow: usize,
+    pub overflow: usize,
+}
+
+impl Histogram {
+    pub fn new(min: f64, width: f64, bins: usize) -> Self {
+        Self { min, width, counts: vec![0; bins], underflow: 0, overflow: 0 }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        if value.is_nan() {
+            return
+        }
+        let mut bin = ((value - self.min) / self.width).floor();
+        if bin as usize == self.counts.len() && value <= self.min + self.counts.len() as f64 * self.width {
+            bin -= 1.0;
+        }
+        if bin < 0.0 {
+            self.underflow += 1;
+        } else if bin as usize >= self.counts.len() {
+            self.overflow += 1;
+        } else {
+            self.counts[bin as usize] += 1;
+        }
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum::<usize>() + self.underflow + self.overflow
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "min": self.min,
+            "bin_width": self.width,
+            "counts": self.counts,
+            "underflow": self.underflow,
+            "overflow": self.overflow,
+        })
+    }
+
+    /// one line per bin with a bar scaled to the largest bin
+    fn text(&self, out: &mut String) {
+        let max = self.counts.iter().chain([&self.underflow, &self.overflow]).copied().max().unwrap_or(0).max(1);
+        let bar = |count: usize| "#".repeat((count * 40).div_ceil(max));
+        if self.underflow > 0 {
+            let _ = writeln!(out, "    {:>18} {:>9} {}", format!("< {}", edge(self.min)), self.underflow, bar(self.underflow));
+        }
+        for (i, count) in self.counts.iter().enumerate() {
+            let from = self.min + i as f64 * self.width;
+            let close = if i + 1 == self.counts.len() { ']' } else { ')' };
+            let _ = writeln!(out, "    {:>18} {:>9} {}", format!("[{}, {}{close}", edge(from), edge(from + self.width)), count, bar(*count));
+        }
+        if self.overflow > 0 {
+            let end = self.min + self.counts.len() as f64 * self.width;
+            let _ = writeln!(out, "    {:>18} {:>9} {}", format!("> {}", edge(end)), self.overflow, bar(self.overflow));
+        }
+    }
+}
+
+/// a bin edge without floating point noise (0.30000000000000004 -> 0.3)
+fn edge(value: f64) -> f64 {
+    (value * 1e6).round() / 1e6
+}
+
+/// Wall clock times of the run phases.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Timing {
+    pub model: Duration,
+    pub scan: Duration,
+    pub total: Duration,
+}
+
+/// The counts of one run - fed with every read and its scan results (best hit first).
+#[derive(Debug, Clone)]
+pub struct RunReport {
+    pub reads: usize,
+    pub bases: usize,
+    /// reads with a character other than ACGT - they can not be scored
+    pub invalid_characters: usize,
+    /// reads longer than the model - they can not be placed
+    pub too_long: usize,
+    pub reads_with_hits: usize,
+    /// best hits per locus label
+    pub hits_per_locus: BTreeMap<String, usize>,
+    /// best hits per species (multi-species models only)
+    pub hits_per_species: BTreeMap<String, usize>,
+    pub forward: usize,
+    pub reverse: usize,
+    /// log odds of the best hit per read
+    pub log_odds: Histogram,
+    /// posterior of the best hit per read
+    pub posterior: Histogram,
+    pub timing: Timing,
+    /// extra lines for the text report and fields for the JSON summary (e.g. prefilter counts)
+    pub notes: Vec<(String, Value)>,
+    model_len: usize,
+}
+
+impl RunReport {
+    pub fn new(hmm: &HMM) -> Self {
+        Self {
+            reads: 0,
+            bases: 0,
+            invalid_characters: 0,
+            too_long: 0,
+            reads_with_hits: 0,
+            hits_per_locus: (0..hmm.num_loci()).map(|i| (hmm.label(i), 0)).collect(),
+            hits_per_species: BTreeMap::new(),
+            forward: 0,
+            reverse: 0,
+            log_odds: Histogram::new(-50.0, 10.0, 20),
+            posterior: Histogram::new(0.0, 0.1, 10),
+            timing: Timing::default(),
+            notes: Vec::new(),
+            model_len: hmm.states.len(),
+        }
+    }
+
+    /// Count one read and its scan results.
+    pub fn add(&mut self, sequence: &[u8], hits: &[ScanResult]) {
+        self.reads += 1;
+        self.bases += sequence.len();
+        if sequence.iter().any(|base| HMM::char2pos(*base).is_none()) {
+            self.invalid_characters += 1;
+        }
+        if sequence.len() > self.model_len {
+            self.too_long += 1;
+        }
+        let best = match hits.first() {
+            Some(best) => best,
+            None => return,
+        };
+        self.reads_with_hits += 1;
+        *self.hits_per_locus.entry(best.label()).or_insert(0) += 1;
+        let posteriors: Vec<(String, f64)> = hits.iter().map(|hit| (hit.label(), hit.posterior)).collect();
+        if let Some(species) = HMM::most_likely_species(&posteriors) {
+            *self.hits_per_species.entry(species).or_insert(0) += 1;
+        }
+        match best.strand {
+            Strand::Forward => self.forward += 1,
+            Strand::Reverse => self.reverse += 1,
+        }
+        self.log_odds.add(best.log_odds);
+        self.posterior.add(best.posterior);
+    }
+
+    /// an extra named value shown in both reports
+    pub fn note(&mut self, name: &str, value: Value) {
+        self.notes.push((name.to_string(), value));
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut ret = json!({
+            "reads": self.reads,
+            "bases": self.bases,
+            "invalid_characters": self.invalid_characters,
+            "too_long_for_model": self.too_long,
+            "model_length": self.model_len,
+            "reads_with_hits": self.reads_with_hits,
+            "hits_per_locus": self.hits_per_locus,
+            "hits_per_species": self.hits_per_species,
+            "strand": { "forward": self.forward, "reverse": self.reverse },
+            "log_odds_histogram": self.log_odds.to_json(),
+            "posterior_histogram": self.posterior.to_json(),
+            "timing_seconds": {
+                "model": self.timing.model.as_secs_f64(),
+                "scan": self.timing.scan.as_secs_f64(),
+                "total": self.timing.total.as_secs_f64(),
+            },
+            "reads_per_second": self.reads_per_second(),
+        });
+        for (name, value) in &self.notes {
+            ret[name] = value.clone();
+        }
+        ret
+    }
+
+    fn reads_per_second(&self) -> f64 {
+        match self.timing.scan.as_secs_f64() {
+            secs if secs > 0.0 => self.reads as f64 / secs,
+            _ => 0.0,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        let percent = |n: usize| if self.reads > 0 { 100.0 * n as f64 / self.reads as f64 } else { 0.0 };
+        let mut out = String::from("Run summary\n");
+        let _ = writeln!(out, "  reads                    {:>9}", self.reads);
+        let _ = writeln!(out, "  invalid characters       {:>9} ({:.2}%)", self.invalid_characters, percent(self.invalid_characters));
+        let _ = writeln!(out, "  too long for the model   {:>9} ({:.2}%)  model length {}", self.too_long, percent(self.too_long), self.model_len);
+        let _ = writeln!(out, "  reads with hits          {:>9} ({:.2}%)", self.reads_with_hits, percent(self.reads_with_hits));
+        let _ = writeln!(out, "  strand                   {:>9} +  {} -", self.forward, self.reverse);
+        out += "  hits per locus\n";
+        for (locus, count) in &self.hits_per_locus {
+            let _ = writeln!(out, "    {locus:<22} {count:>9}");
+        }
+        if !self.hits_per_species.is_empty() {
+            out += "  hits per species\n";
+            for (species, count) in &self.hits_per_species {
+                let _ = writeln!(out, "    {species:<22} {count:>9}");
+            }
+        }
+        for (name, value) in &self.notes {
+            let _ = writeln!(out, "  {name:<24} {value}");
+        }
+        out += "  log odds of the best hit\n";
+        self.log_odds.text(&mut out);
+        out += "  posterior of the best hit\n";
+        self.posterior.text(&mut out);
+        let _ = writeln!(
+            out, "  time                     model {:.2}s, scan {:.2}s, total {:.2}s ({:.0} reads/s)",
+            self.timing.model.as_secs_f64(), self.timing.scan.as_secs_f64(), self.timing.total.as_secs_f64(), self.reads_per_second()
+        );
+        out
+    }
+}
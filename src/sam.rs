@@ -0,0 +1,404 @@
+// sam.rs
+
+use crate::banded::Cigar;
+use crate::errors::{HmmError, Result};
+use crate::pipeline::Seqrec;
+use crate::scan_result::{segments_text, ScanResult, Strand};
+use crate::scanner::reverse_complement;
+use crate::streams::{Codec, Output};
+use crate::HMM::HMM;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// The alignment output formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SamFormat {
+    /// text SAM - compressed like any other output by its extension (.sam.gz, .sam.zst)
+    Sam,
+    /// BGZF compressed binary BAM
+    Bam,
+}
+
+impl SamFormat {
+    /// the alignment format for an output file name - None for fasta/fastq output
+    pub fn from_path(path: &str) -> Option<Self> {
+        let lower = path.to_ascii_lowercase();
+        let stem = [".gz", ".bgz", ".bgzf", ".zst"].iter()
+            .find_map(|ext| lower.strip_suffix(ext))
+            .unwrap_or(&lower);
+        if lower.ends_with(".bam") {
+            Some(SamFormat::Bam)
+        } else if stem.ends_with(".sam") {
+            Some(SamFormat::Sam)
+        } else {
+            None
+        }
+    }
+}
+
+/// The SAM reference name of locus i: the locus label with ':' replaced, as samtools reads ':' as a region separator.
+pub fn reference_name(hmm: &HMM, i: usize) -> String {
+    hmm.label(i).replace(':', "_")
+}
+
+/// Write the per locus consensus sequences the SAM/BAM records are aligned to as fasta (e.g. to load them into IGV).
+pub fn write_reference(hmm: &HMM, path: &str) -> Result<()> {
+    let write = || -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        for i in 0..hmm.num_loci() {
+            writeln!(out, ">{}", reference_name(hmm, i))?;
+            for line in hmm.consensus(i).chunks(60) {
+                out.write_all(line)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        out.flush()
+    };
+    write().map_err(|err| HmmError::io(path, err))
+}
+
+/// The mapping quality of a hit: the phred scaled probability that the locus is wrong, capped at 60.
+pub fn mapq(posterior: f64) -> u8 {
+    let error = (1.0 - posterior).max(1e-6);
+    (-10.0 * error.log10()).round().clamp(0.0, 60.0) as u8
+}
+
+/// The CIGAR of a hit against the reference - the gapped alignment or the ungapped read interval with soft clips.
+/// Always in reference orientation, i.e. for the reverse complement of reverse strand reads.
+pub fn hit_cigar(hit: &ScanResult, read_len: usize) -> Cigar {
+    if let Some(cigar) = &hit.cigar {
+        return cigar.clone()
+    }
+    let start = match hit.strand {
+        Strand::Forward => hit.read_start,
+        Strand::Reverse => read_len - hit.read_end,
+    };
+    let len = hit.read_end - hit.read_start;
+    let mut cigar = Cigar::default();
+    cigar.push('S', start as u32);
+    cigar.push('M', len as u32);
+    cigar.push('S', (read_len - start - len) as u32);
+    cigar
+}
+
+/// Writes the best hit of every read as a SAM or BAM record aligned to the locus consensus sequences.
+///
+/// Besides the standard fields every record has these tags:
+/// ZL:Z locus label, ZS:f score, ZO:f log odds, ZP:f locus posterior, ZT:A read strand (+/-),
+/// ZQ:f target-decoy q-value (decoy scoring only), ZW:Z locus switches (switch detection only),
+/// ZC:Z start candidates (--report-starts only), ZG:Z all segments of the read (--long-reads only) and ZV:Z / ZD:Z / ZJ:Z for the V, D and J gene calls.
+/// SAM tags in the read header comment (e.g. 'CB:Z:ACGT UB:Z:TTGC' from BAM input or samtools fastq -T) are kept.
+pub struct SamWriter {
+    out: Output,
+    format: SamFormat,
+    ref_ids: HashMap<String, usize>,
+    /// the reference names and lengths (the locus profile lengths)
+    references: Vec<(String, usize)>,
+    /// reused buffers
+    oriented: Vec<u8>,
+    record: Vec<u8>,
+}
+
+impl SamWriter {
+    /// Create path ('-' for stdout) and write the header; command_line is recorded in the @PG line.
+    pub fn create(path: &str, format: SamFormat, hmm: &HMM, command_line: &str) -> Result<Self> {
+        let out = match format {
+            SamFormat::Sam => Output::create(path)?,
+            SamFormat::Bam => Output::create_with_codec(path, Codec::Bgzf)?,
+        };
+        let references: Vec<(String, usize)> = (0..hmm.num_loci()).map(|i| (reference_name(hmm, i), hmm.locus_len(i))).collect();
+        let mut writer = Self {
+            out,
+            format,
+            ref_ids: (0..hmm.num_loci()).map(|i| (hmm.label(i), i)).collect(),
+            references,
+            oriented: Vec::new(),
+            record: Vec::new(),
+        };
+        writer.write_header(command_line).map_err(|err| HmmError::io(path, err))?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self, command_line: &str) -> io::Result<()> {
+        let mut text = String::from("@HD\tVN:1.6\tSO:unsorted\n");
+        for (name, len) in &self.references {
+            text += &format!("@SQ\tSN:{name}\tLN:{len}\n");
+        }
+        text += &format!("@PG\tID:hmm_mapper\tPN:hmm_mapper\tVN:{}\tCL:{}\n", env!("CARGO_PKG_VERSION"), command_line.replace(['\t', '\n'], " "));
+        match self.format {
+            SamFormat::Sam => self.out.write_all(text.as_bytes()),
+            SamFormat::Bam => {
+                self.out.write_all(b"BAM\x01")?;
+                self.out.write_all(&(text.len() as i32).to_le_bytes())?;
+                self.out.write_all(text.as_bytes())?;
+                self.out.write_all(&(self.references.len() as i32).to_le_bytes())?;
+                for (name, len) in &self.references {
+                    self.out.write_all(&(name.len() as i32 + 1).to_le_bytes())?;
+                    self.out.write_all(name.as_bytes())?;
+                    self.out.write_all(&[0])?;
+                    self.out.write_all(&(*len as i32).to_le_bytes())?;
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Write record as aligned to the locus of hit.
+    pub fn write_hit(&mut self, record: &Seqrec, hit: &ScanResult) -> io::Result<()> {
+        self.write_record(record, hit, 0, Vec::new())
+    }
+
+    /// Write one record per segment of a long read (Scanner::scan_segments): the first segment is the primary
+    /// alignment, the others are supplementary (flag 2048). Reads with several segments list all of them in ZG:Z.
+    pub fn write_segments(&mut self, record: &Seqrec, segments: &[ScanResult]) -> io::Result<()> {
+        let tags = if segments.len() > 1 {
+            vec![("ZG".to_string(), TagValue::String(segments_text(segments)))]
+        } else {
+            Vec::new()
+        };
+        for (i, segment) in segments.iter().enumerate() {
+            self.write_record(record, segment, if i == 0 { 0 } else { 2048 }, tags.clone())?;
+        }
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &Seqrec, hit: &ScanResult, flag: u16, tags: Vec<(String, TagValue)>) -> io::Result<()> {
+        let ref_id = *self.ref_ids.get(&hit.label())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("locus {} is not part of the SAM header", hit.label())))?;
+        let seq = record.seq();
+        // SAM stores reverse strand reads as their reverse complement
+        let mut oriented = std::mem::take(&mut self.oriented);
+        let mut qual: Option<Vec<u8>> = record.qual().map(|q| q.to_vec());
+        match hit.strand {
+            Strand::Forward => {
+                oriented.clear();
+                oriented.extend_from_slice(seq);
+            },
+            Strand::Reverse => {
+                reverse_complement(seq, &mut oriented);
+                if let Some(q) = qual.as_mut() {
+                    q.reverse();
+                }
+            },
+        }
+        let cigar = hit_cigar(hit, seq.len());
+        let fields = Fields {
+            name: record.name(),
+            flag: flag | if hit.strand == Strand::Reverse { 16 } else { 0 },
+            ref_id,
+            pos: hit.model_start,
+            mapq: mapq(hit.posterior),
+            cigar: &cigar,
+            seq: &oriented,
+            qual: qual.as_deref(),
+            tags: comment_tags(record.comment()).into_iter().chain(hit_tags(hit)).chain(tags).collect(),
+        };
+        let ret = match self.format {
+            SamFormat::Sam => self.write_sam(&fields),
+            SamFormat::Bam => self.write_bam(&fields),
+        };
+        self.oriented = oriented;
+        ret
+    }
+
+    fn write_sam(&mut self, fields: &Fields) -> io::Result<()> {
+        let name = String::from_utf8_lossy(fields.name);
+        let mut line = format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t*\t0\t0\t{}\t",
+            if name.is_empty() { "*" } else { &name },
+            fields.flag,
+            self.references[fields.ref_id].0,
+            fields.pos + 1,
+            fields.mapq,
+            fields.cigar,
+            String::from_utf8_lossy(fields.seq),
+        );
+        match fields.qual {
+            Some(qual) => line += &String::from_utf8_lossy(qual),
+            None => line.push('*'),
+        }
+        for (key, value) in &fields.tags {
+            line += &match value {
+                TagValue::Char(c) => format!("\t{key}:A:{c}"),
+                TagValue::Int(i) => format!("\t{key}:i:{i}"),
+                TagValue::Float(f) => format!("\t{key}:f:{}", *f as f32),
+                TagValue::String(s) => format!("\t{key}:Z:{s}"),
+            };
+        }
+        line.push('\n');
+        self.out.write_all(line.as_bytes())
+    }
+
+    fn write_bam(&mut self, fields: &Fields) -> io::Result<()> {
+        let record = &mut self.record;
+        record.clear();
+        let name: &[u8] = if fields.name.is_empty() { b"*" } else { &fields.name[..fields.name.len().min(254)] };
+        let ref_len = fields.cigar.model_len();
+        let bin = reg2bin(fields.pos, fields.pos + ref_len.max(1));
+        record.extend_from_slice(&(fields.ref_id as i32).to_le_bytes());
+        record.extend_from_slice(&(fields.pos as i32).to_le_bytes());
+        record.push(name.len() as u8 + 1);
+        record.push(fields.mapq);
+        record.extend_from_slice(&bin.to_le_bytes());
+        record.extend_from_slice(&(fields.cigar.0.len() as u16).to_le_bytes());
+        record.extend_from_slice(&fields.flag.to_le_bytes());
+        record.extend_from_slice(&(fields.seq.len() as i32).to_le_bytes());
+        record.extend_from_slice(&(-1_i32).to_le_bytes());
+        record.extend_from_slice(&(-1_i32).to_le_bytes());
+        record.extend_from_slice(&0_i32.to_le_bytes());
+        record.extend_from_slice(name);
+        record.push(0);
+        for (len, op) in &fields.cigar.0 {
+            let code: u32 = match op {
+                'M' => 0,
+                'I' => 1,
+                'D' => 2,
+                'S' => 4,
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unsupported CIGAR operation {op}"))),
+            };
+            record.extend_from_slice(&((len << 4) | code).to_le_bytes());
+        }
+        for pair in fields.seq.chunks(2) {
+            let high = bam_base(pair[0]);
+            let low = pair.get(1).map(|b| bam_base(*b)).unwrap_or(0);
+            record.push((high << 4) | low);
+        }
+        match fields.qual {
+            Some(qual) => record.extend(qual.iter().map(|q| q.saturating_sub(33))),
+            None => record.extend(std::iter::repeat_n(0xff, fields.seq.len())),
+        }
+        for (key, value) in &fields.tags {
+            record.extend_from_slice(key.as_bytes());
+            match value {
+                TagValue::Char(c) => {
+                    record.push(b'A');
+                    record.push(*c as u8);
+                },
+                TagValue::Int(i) => {
+                    record.push(b'i');
+                    record.extend_from_slice(&(*i as i32).to_le_bytes());
+                },
+                TagValue::Float(f) => {
+                    record.push(b'f');
+                    record.extend_from_slice(&(*f as f32).to_le_bytes());
+                },
+                TagValue::String(s) => {
+                    record.push(b'Z');
+                    record.extend_from_slice(s.as_bytes());
+                    record.push(0);
+                },
+            }
+        }
+        self.out.write_all(&(record.len() as i32).to_le_bytes())?;
+        self.out.write_all(record)
+    }
+
+    /// Flush everything and write the BGZF end of file marker for BAM files.
+    pub fn finish(self) -> io::Result<()> {
+        self.out.finish()
+    }
+}
+
+#[derive(Clone)]
+enum TagValue {
+    Char(char),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+/// one alignment record in reference orientation
+struct Fields<'a> {
+    name: &'a [u8],
+    flag: u16,
+    ref_id: usize,
+    /// 0-based
+    pos: usize,
+    mapq: u8,
+    cigar: &'a Cigar,
+    seq: &'a [u8],
+    qual: Option<&'a [u8]>,
+    tags: Vec<(String, TagValue)>,
+}
+
+fn hit_tags(hit: &ScanResult) -> Vec<(String, TagValue)> {
+    let mut tags = vec![
+        ("ZL".to_string(), TagValue::String(hit.label())),
+        ("ZS".to_string(), TagValue::Float(hit.score)),
+        ("ZO".to_string(), TagValue::Float(hit.log_odds)),
+        ("ZP".to_string(), TagValue::Float(hit.posterior)),
+        ("ZT".to_string(), TagValue::Char(if hit.strand == Strand::Reverse { '-' } else { '+' })),
+    ];
+    if let Some(q_value) = hit.q_value {
+        tags.push(("ZQ".to_string(), TagValue::Float(q_value)));
+    }
+    if !hit.switches.is_empty() {
+        tags.push(("ZW".to_string(), TagValue::String(hit.switches_text())));
+    }
+    if !hit.starts.is_empty() {
+        tags.push(("ZC".to_string(), TagValue::String(hit.starts_text())));
+    }
+    if let Some(genes) = &hit.genes {
+        for (key, gene) in [("ZV", &genes.v), ("ZD", &genes.d), ("ZJ", &genes.j)] {
+            if let Some(gene) = gene {
+                tags.push((key.to_string(), TagValue::String(gene.clone())));
+            }
+        }
+    }
+    tags
+}
+
+/// the 'XX:T:value' SAM tags (types A, i, f and Z) in a read header comment - other words are ignored
+fn comment_tags(comment: &[u8]) -> Vec<(String, TagValue)> {
+    String::from_utf8_lossy(comment).split_ascii_whitespace().filter_map(|word| {
+        let mut parts = word.splitn(3, ':');
+        let (key, kind, value) = (parts.next()?, parts.next()?, parts.next()?);
+        let mut chars = key.chars();
+        if key.len() != 2 || !chars.next()?.is_ascii_alphabetic() || !chars.next()?.is_ascii_alphanumeric() {
+            return None
+        }
+        let value = match kind {
+            "A" if value.len() == 1 => TagValue::Char(value.chars().next()?),
+            "i" => TagValue::Int(value.parse().ok()?),
+            "f" => TagValue::Float(value.parse().ok()?),
+            "Z" => TagValue::String(value.to_string()),
+            _ => return None,
+        };
+        Some((key.to_string(), value))
+    }).collect()
+}
+
+/// the 4 bit BAM code of a base
+fn bam_base(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'=' => 0,
+        b'A' => 1,
+        b'C' => 2,
+        b'M' => 3,
+        b'G' => 4,
+        b'R' => 5,
+        b'S' => 6,
+        b'V' => 7,
+        b'T' => 8,
+        b'W' => 9,
+        b'Y' => 10,
+        b'H' => 11,
+        b'K' => 12,
+        b'D' => 13,
+        b'B' => 14,
+        _ => 15,
+    }
+}
+
+/// the BAM index bin of the 0-based interval [beg, end) (SAM specification 5.3)
+pub fn reg2bin(beg: usize, end: usize) -> u16 {
+    let end = end - 1;
+    for (shift, offset) in [(14, 4681), (17, 585), (20, 73), (23, 9), (26, 1)] {
+        if beg >> shift == end >> shift {
+            return (offset + (beg >> shift)) as u16
+        }
+    }
+    0
+}
@@ -0,0 +1,392 @@
+// sam_input.rs
+
+use crate::errors::{HmmError, Result};
+use crate::pipeline::Seqrec;
+use crate::scanner::reverse_complement;
+
+use std::io::{BufRead, Read};
+
+/// the IG and TR loci on GRCh38 (IMGT) - the 'grch38' region preset
+const GRCH38_LOCI: [(&str, u64, u64); 6] = [
+    ("chr14", 105_586_437, 106_879_844), // IGH
+    ("chr2", 88_857_361, 90_235_368),    // IGK
+    ("chr22", 22_026_076, 22_922_913),   // IGL
+    ("chr14", 21_621_904, 22_552_132),   // TRA/TRD
+    ("chr7", 142_299_011, 142_813_287),  // TRB
+    ("chr7", 38_240_024, 38_368_055),    // TRG
+];
+
+/// A 1-based, inclusive genome interval.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    pub contig: String,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Region {
+    /// Parse 'chr14:105586437-106879844' (commas are ignored) or a whole contig 'chr14'.
+    pub fn parse(text: &str) -> Result<Self> {
+        let error = || HmmError::parse("the regions", None, Some(text), "expected contig:start-end");
+        let text = text.trim();
+        let (contig, range) = match text.rsplit_once(':') {
+            Some((contig, range)) => (contig, Some(range)),
+            None => (text, None),
+        };
+        if contig.is_empty() {
+            return Err(error())
+        }
+        let (start, end) = match range {
+            None => (1, u64::MAX),
+            Some(range) => {
+                let range = range.replace(',', "");
+                let (start, end) = range.split_once('-').ok_or_else(error)?;
+                let start: u64 = start.parse().map_err(|_| error())?;
+                let end: u64 = end.parse().map_err(|_| error())?;
+                if start == 0 || end < start {
+                    return Err(error())
+                }
+                (start, end)
+            },
+        };
+        Ok(Self { contig: contig.to_string(), start, end })
+    }
+
+    /// Parse a comma separated list of regions or the preset 'grch38' (the IG/TR loci).
+    pub fn parse_list(text: &str) -> Result<Vec<Self>> {
+        if text.eq_ignore_ascii_case("grch38") || text.eq_ignore_ascii_case("hg38") {
+            return Ok(GRCH38_LOCI.iter()
+                .map(|(contig, start, end)| Region { contig: contig.to_string(), start: *start, end: *end })
+                .collect())
+        }
+        text.split(',').filter(|r| !r.trim().is_empty()).map(Self::parse).collect()
+    }
+
+    /// does the 0-based half open interval [start, end) on contig overlap this region?
+    /// 'chr14' and '14' are treated as the same contig.
+    fn overlaps(&self, contig: &str, start: u64, end: u64) -> bool {
+        let strip = |name: &str| name.strip_prefix("chr").unwrap_or(name).to_string();
+        strip(contig) == strip(&self.contig) && start < self.end && end > self.start - 1
+    }
+}
+
+/// Which reads of a SAM/BAM file are scanned. Secondary and supplementary alignments are always skipped.
+#[derive(Debug, Clone)]
+pub struct SamFilter {
+    /// only scan unmapped reads
+    pub unmapped_only: bool,
+    /// also scan mapped reads overlapping any of these regions (implies unmapped_only for all other reads)
+    pub regions: Vec<Region>,
+    /// the aux tags copied into the read header comment (as 'CB:Z:value')
+    pub keep_tags: Vec<[u8; 2]>,
+}
+
+impl Default for SamFilter {
+    fn default() -> Self {
+        Self {
+            unmapped_only: false,
+            regions: Vec::new(),
+            keep_tags: vec![*b"CB", *b"UB"],
+        }
+    }
+}
+
+impl SamFilter {
+    fn keep(&self, flag: u16, contig: &str, start: u64, end: u64) -> bool {
+        if flag & (0x100 | 0x800) != 0 {
+            return false
+        }
+        if flag & 0x4 != 0 || (!self.unmapped_only && self.regions.is_empty()) {
+            return true
+        }
+        self.regions.iter().any(|region| region.overlaps(contig, start, end))
+    }
+}
+
+/// The SAM flag 'read reverse complemented' - such reads are turned back into the sequenced orientation.
+const REVERSE: u16 = 0x10;
+
+/// Build the Seqrec for a kept alignment: name plus the kept tags as header comment, in sequencing orientation.
+fn to_seqrec(name: &[u8], flag: u16, mut seq: Vec<u8>, mut qual: Option<Vec<u8>>, tags: &[String]) -> Seqrec {
+    if flag & REVERSE != 0 {
+        let mut rc = Vec::with_capacity(seq.len());
+        reverse_complement(&seq, &mut rc);
+        seq = rc;
+        if let Some(qual) = qual.as_mut() {
+            qual.reverse();
+        }
+    }
+    let mut id = name.to_vec();
+    for tag in tags {
+        id.push(b' ');
+        id.extend_from_slice(tag.as_bytes());
+    }
+    Seqrec { id, seq, qual }
+}
+
+/// the number of reference bases a CIGAR covers
+fn reference_span(ops: impl Iterator<Item = (u64, u8)>) -> u64 {
+    ops.filter(|(_, op)| matches!(op, b'M' | b'D' | b'N' | b'=' | b'X')).map(|(len, _)| len).sum()
+}
+
+/// The reads of a text SAM file.
+pub struct SamRecords<R: BufRead> {
+    reader: R,
+    filter: SamFilter,
+    path: String,
+    line: String,
+    line_number: u64,
+}
+
+impl<R: BufRead> SamRecords<R> {
+    pub fn new(reader: R, filter: SamFilter, path: &str) -> Self {
+        Self { reader, filter, path: path.to_string(), line: String::new(), line_number: 0 }
+    }
+
+    fn parse_line(&self) -> Result<Option<Seqrec>> {
+        let error = |message: &str| HmmError::parse(&self.path, Some(self.line_number), None, message);
+        let fields: Vec<&str> = self.line.trim_end_matches(['\n', '\r']).split('\t').collect();
+        if fields.len() < 11 {
+            return Err(error("a SAM record needs at least 11 fields"))
+        }
+        let flag: u16 = fields[1].parse().map_err(|_| error("invalid FLAG"))?;
+        let pos: u64 = fields[3].parse().map_err(|_| error("invalid POS"))?;
+        let mut ops = Vec::new();
+        let mut len = 0u64;
+        if fields[5] != "*" {
+            for c in fields[5].bytes() {
+                if c.is_ascii_digit() {
+                    len = len * 10 + u64::from(c - b'0');
+                } else {
+                    ops.push((len, c));
+                    len = 0;
+                }
+            }
+        }
+        let start = pos.saturating_sub(1);
+        let end = start + reference_span(ops.into_iter()).max(1);
+        if !self.filter.keep(flag, fields[2], start, end) {
+            return Ok(None)
+        }
+        if fields[9] == "*" {
+            return Ok(None)
+        }
+        let qual = match fields[10] {
+            "*" => None,
+            qual => Some(qual.as_bytes().to_vec()),
+        };
+        let tags: Vec<String> = fields[11..].iter()
+            .filter(|tag| self.filter.keep_tags.iter().any(|keep| tag.as_bytes().starts_with(keep) && tag.as_bytes().get(2) == Some(&b':')))
+            .map(|tag| tag.to_string())
+            .collect();
+        Ok(Some(to_seqrec(fields[0].as_bytes(), flag, fields[9].as_bytes().to_vec(), qual, &tags)))
+    }
+}
+
+impl<R: BufRead> Iterator for SamRecords<R> {
+    type Item = Result<Seqrec>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(_) => {},
+                Err(err) => return Some(Err(HmmError::io(&self.path, err))),
+            }
+            self.line_number += 1;
+            if self.line.starts_with('@') || self.line.trim().is_empty() {
+                continue
+            }
+            match self.parse_line() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+/// The reads of a BAM file - reader has to deliver the decompressed BGZF stream.
+pub struct BamRecords<R: Read> {
+    reader: R,
+    filter: SamFilter,
+    path: String,
+    references: Vec<String>,
+    block: Vec<u8>,
+    /// stop after the first error
+    failed: bool,
+}
+
+impl<R: Read> BamRecords<R> {
+    /// Read the BAM header.
+    pub fn new(mut reader: R, filter: SamFilter, path: &str) -> Result<Self> {
+        let io_error = |err| HmmError::io(path, err);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic).map_err(io_error)?;
+        if &magic != b"BAM\x01" {
+            return Err(HmmError::parse(path, None, None, "not a BAM file"))
+        }
+        let l_text = read_i32(&mut reader).map_err(io_error)?;
+        let mut text = vec![0u8; l_text.max(0) as usize];
+        reader.read_exact(&mut text).map_err(io_error)?;
+        let n_ref = read_i32(&mut reader).map_err(io_error)?;
+        let mut references = Vec::with_capacity(n_ref.max(0) as usize);
+        for _ in 0..n_ref {
+            let l_name = read_i32(&mut reader).map_err(io_error)?;
+            let mut name = vec![0u8; l_name.max(0) as usize];
+            reader.read_exact(&mut name).map_err(io_error)?;
+            name.pop(); // NUL
+            references.push(String::from_utf8_lossy(&name).to_string());
+            read_i32(&mut reader).map_err(io_error)?;
+        }
+        Ok(Self { reader, filter, path: path.to_string(), references, block: Vec::new(), failed: false })
+    }
+
+    /// Parse the record in self.block; None if the filter rejects it.
+    fn parse_block(&self) -> Result<Option<Seqrec>> {
+        let b = &self.block;
+        let error = || HmmError::parse(&self.path, None, None, "truncated BAM record");
+        if b.len() < 32 {
+            return Err(error())
+        }
+        let i32_at = |at: usize| i32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]);
+        let u16_at = |at: usize| u16::from_le_bytes([b[at], b[at + 1]]);
+        let ref_id = i32_at(0);
+        let pos = i32_at(4);
+        let l_read_name = b[8] as usize;
+        let n_cigar = u16_at(12) as usize;
+        let flag = u16_at(14);
+        let l_seq = i32_at(16).max(0) as usize;
+        let name_end = 32 + l_read_name;
+        let cigar_end = name_end + 4 * n_cigar;
+        let seq_end = cigar_end + l_seq.div_ceil(2);
+        let qual_end = seq_end + l_seq;
+        if b.len() < qual_end {
+            return Err(error())
+        }
+
+        let ops = (0..n_cigar).map(|k| {
+            let value = u32::from_le_bytes([b[name_end + 4 * k], b[name_end + 4 * k + 1], b[name_end + 4 * k + 2], b[name_end + 4 * k + 3]]);
+            (u64::from(value >> 4), b"MIDNSHP=X".get((value & 0xf) as usize).copied().unwrap_or(b'?'))
+        });
+        let contig = usize::try_from(ref_id).ok().and_then(|id| self.references.get(id)).map(String::as_str).unwrap_or("*");
+        let start = pos.max(0) as u64;
+        let end = start + reference_span(ops).max(1);
+        if !self.filter.keep(flag, contig, start, end) || l_seq == 0 {
+            return Ok(None)
+        }
+
+        let name = &b[32..name_end.saturating_sub(1).max(32)];
+        let seq: Vec<u8> = (0..l_seq)
+            .map(|k| b"=ACMGRSVTWYHKDBN"[((b[cigar_end + k / 2] >> (4 * (1 - k % 2))) & 0xf) as usize])
+            .collect();
+        let qual = match b[seq_end] {
+            0xff => None,
+            _ => Some(b[seq_end..qual_end].iter().map(|q| q.saturating_add(33)).collect()),
+        };
+        let tags = self.kept_tags(&b[qual_end..]).ok_or_else(error)?;
+        Ok(Some(to_seqrec(name, flag, seq, qual, &tags)))
+    }
+
+    /// the kept aux fields in SAM text form - None if the aux data is truncated
+    fn kept_tags(&self, mut aux: &[u8]) -> Option<Vec<String>> {
+        let mut ret = Vec::new();
+        while aux.len() >= 3 {
+            let key = [aux[0], aux[1]];
+            let kind = aux[2];
+            aux = &aux[3..];
+            let fixed = |size: usize| -> Option<&[u8]> { aux.get(..size) };
+            let (text, used): (Option<String>, usize) = match kind {
+                b'A' => (fixed(1).map(|v| format!("A:{}", v[0] as char)), 1),
+                b'c' => (fixed(1).map(|v| format!("i:{}", v[0] as i8)), 1),
+                b'C' => (fixed(1).map(|v| format!("i:{}", v[0])), 1),
+                b's' => (fixed(2).map(|v| format!("i:{}", i16::from_le_bytes([v[0], v[1]]))), 2),
+                b'S' => (fixed(2).map(|v| format!("i:{}", u16::from_le_bytes([v[0], v[1]]))), 2),
+                b'i' => (fixed(4).map(|v| format!("i:{}", i32::from_le_bytes([v[0], v[1], v[2], v[3]]))), 4),
+                b'I' => (fixed(4).map(|v| format!("i:{}", u32::from_le_bytes([v[0], v[1], v[2], v[3]]))), 4),
+                b'f' => (fixed(4).map(|v| format!("f:{}", f32::from_le_bytes([v[0], v[1], v[2], v[3]]))), 4),
+                b'Z' | b'H' => {
+                    let end = aux.iter().position(|c| *c == 0)?;
+                    (Some(format!("{}:{}", kind as char, String::from_utf8_lossy(&aux[..end]))), end + 1)
+                },
+                b'B' => {
+                    // arrays are skipped - not useful in a read header
+                    let sub = *aux.first()?;
+                    let count = u32::from_le_bytes(aux.get(1..5)?.try_into().ok()?) as usize;
+                    let size = match sub {
+                        b'c' | b'C' => 1,
+                        b's' | b'S' => 2,
+                        _ => 4,
+                    };
+                    (None, 5 + count * size)
+                },
+                _ => return None,
+            };
+            if aux.len() < used {
+                return None
+            }
+            aux = &aux[used..];
+            if let Some(text) = text {
+                if self.filter.keep_tags.contains(&key) {
+                    ret.push(format!("{}{}:{}", key[0] as char, key[1] as char, text));
+                }
+            }
+        }
+        Some(ret)
+    }
+}
+
+impl<R: Read> Iterator for BamRecords<R> {
+    type Item = Result<Seqrec>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            let mut size = [0u8; 4];
+            match read_exact_or_eof(&mut self.reader, &mut size) {
+                Ok(false) => return None,
+                Ok(true) => {},
+                Err(err) => {
+                    self.failed = true;
+                    return Some(Err(HmmError::io(&self.path, err)))
+                },
+            }
+            let size = i32::from_le_bytes(size).max(0) as usize;
+            self.block.resize(size, 0);
+            if let Err(err) = self.reader.read_exact(&mut self.block) {
+                self.failed = true;
+                return Some(Err(HmmError::io(&self.path, err)))
+            }
+            match self.parse_block() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => continue,
+                Err(err) => {
+                    self.failed = true;
+                    return Some(Err(err))
+                },
+            }
+        }
+        None
+    }
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> std::io::Result<i32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+/// read_exact that returns Ok(false) at a clean end of file
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) if read == 0 => return Ok(false),
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {},
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
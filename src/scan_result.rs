@@ -0,0 +1,144 @@
+// scan_result.rs
+
+use crate::HMM::StartCandidate;
+use crate::VDJmodeler::SequenceModel;
+use crate::banded::Cigar;
+use std::fmt;
+
+/// The read strand a hit was found on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strand {
+    Forward,
+    /// the reverse complement of the read matched the model
+    Reverse,
+}
+
+impl fmt::Display for Strand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strand::Forward => write!(f, "+"),
+            Strand::Reverse => write!(f, "-"),
+        }
+    }
+}
+
+/// The best matching germline genes for a hit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeneCalls {
+    pub v: Option<String>,
+    pub d: Option<String>,
+    pub j: Option<String>,
+}
+
+/// A position where the best path of a hit switches from one locus to another (see HMM::allow_locus_switches).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocusSwitch {
+    /// the read position between the last base of 'from' and the first base of 'to' in the original read orientation
+    pub read_pos: usize,
+    /// the model position of the first base of 'to'
+    pub model_pos: usize,
+    pub from: String,
+    pub to: String,
+}
+
+impl fmt::Display for LocusSwitch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}>{}@{}", self.from, self.to, self.read_pos)
+    }
+}
+
+/// The segments of a long read (Scanner::scan_segments) as comma separated locus:strand:read_start-read_end
+/// in read order e.g. 'IGH-VDJ:+:12-1530,IGK-VDJ:-:1602-2710'.
+pub fn segments_text(segments: &[ScanResult]) -> String {
+    let mut ordered: Vec<&ScanResult> = segments.iter().collect();
+    ordered.sort_by_key(|segment| segment.read_start);
+    ordered.iter()
+        .map(|segment| format!("{}:{}:{}-{}", segment.label(), segment.strand, segment.read_start, segment.read_end))
+        .collect::<Vec<String>>().join(",")
+}
+
+/// The result of scanning one read against one locus model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanResult {
+    pub locus: SequenceModel,
+    /// the species of the locus model - empty if the database was not species-tagged
+    pub species: String,
+    pub strand: Strand,
+    /// the scanned read interval [read_start, read_end) in the original read orientation
+    pub read_start: usize,
+    pub read_end: usize,
+    /// the model positions [model_start, model_end) the read interval was aligned to
+    pub model_start: usize,
+    pub model_end: usize,
+    /// the log probability of the best path through the locus model
+    pub score: f64,
+    /// score minus the log probability of the read interval under a uniform background
+    pub log_odds: f64,
+    /// the posterior probability of this locus given all loci in the model
+    pub posterior: f64,
+    /// the best matching germline genes if gene calling is enabled
+    pub genes: Option<GeneCalls>,
+    /// the alignment of the read (in model orientation) if the gapped alignment is enabled
+    pub cigar: Option<Cigar>,
+    /// the target-decoy q-value - only known once the whole sample was scanned with decoys
+    pub q_value: Option<f64>,
+    /// the locus switches of the best path - only searched for if locus switch detection is enabled
+    pub switches: Vec<LocusSwitch>,
+    /// the start candidates of this locus and strand the hit was chosen from - only kept if the scanner reports them
+    pub starts: Vec<StartCandidate>,
+}
+
+impl ScanResult {
+    /// the locus name as used in the output e.g. 'IGH-VDJ' or 'human:IGH-VDJ'
+    pub fn label(&self) -> String {
+        match self.species.as_str() {
+            "" => self.locus.name(),
+            species => format!("{}:{}", species, self.locus.name()),
+        }
+    }
+
+    /// the locus switches as comma separated from>to@read_pos e.g. 'TRA-VDJ>TRD-VDJ@57'
+    pub fn switches_text(&self) -> String {
+        self.switches.iter().map(|switch| switch.to_string()).collect::<Vec<String>>().join(",")
+    }
+
+    /// the start candidates as comma separated diagonal:score e.g. '51:0.412,-12:0.318' -
+    /// the diagonal is the model position of the first read base, negative if the read overhangs the model start
+    pub fn starts_text(&self) -> String {
+        self.starts.iter()
+            .map(|candidate| format!("{}:{:.3}", candidate.diagonal(), candidate.score))
+            .collect::<Vec<String>>().join(",")
+    }
+
+    /// space separated key=value tags describing this hit (used in the output headers)
+    pub fn tags(&self) -> String {
+        let mut ret = format!(
+            "locus={} strand={} read={}-{} model={}-{} score={:.3} log_odds={:.3} posterior={:.4}",
+            self.locus.name(), self.strand, self.read_start, self.read_end,
+            self.model_start, self.model_end, self.score, self.log_odds, self.posterior
+        );
+        if !self.species.is_empty() {
+            ret += &format!(" species={}", self.species);
+        }
+        if let Some(cigar) = &self.cigar {
+            ret += &format!(" cigar={cigar}");
+        }
+        if let Some(q_value) = self.q_value {
+            ret += &format!(" q_value={q_value:.4}");
+        }
+        if !self.switches.is_empty() {
+            ret += &format!(" switches={}", self.switches_text());
+        }
+        if !self.starts.is_empty() {
+            ret += &format!(" starts={}", self.starts_text());
+        }
+        if let Some(genes) = &self.genes {
+            for (key, gene) in [("v", &genes.v), ("d", &genes.d), ("j", &genes.j)] {
+                if let Some(gene) = gene {
+                    ret += &format!(" {key}={gene}");
+                }
+            }
+        }
+        ret
+    }
+}
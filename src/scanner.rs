@@ -0,0 +1,623 @@
+// scanner.rs
+
+use crate::HMM::{StartCandidate, BACKGROUND, HMM, START_CANDIDATES};
+use crate::VDJmodeler::Chain;
+use crate::banded::{self, AlignWorkspace, BandStats, Cigar, GapModel};
+use crate::decoy::{DecoyKind, DecoyScores};
+use crate::errors::Result;
+use crate::kmer_index::{KmerIndex, PrefilterStats, Seed};
+use crate::scan_result::{GeneCalls, LocusSwitch, ScanResult, Strand};
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Reusable per scanner buffers for the reverse complement, the decoys and the gapped alignment of the current read.
+#[derive(Clone, Default)]
+pub struct Workspace {
+    /// the DP and traceback of the gapped alignment
+    pub align: AlignWorkspace,
+    /// the reverse complement of the current read
+    pub revcomp: Vec<u8>,
+    /// the decoy of the current read and its reverse complement
+    decoy: Vec<u8>,
+    decoy_revcomp: Vec<u8>,
+}
+
+/// the k-mers a seed diagonal needs (k + 3 shared bases) - one or two shared k-mers are often chance,
+/// a germline read shares dozens
+const MIN_SEED_HITS: usize = 4;
+
+/// (score, read_start, start, end, strand) of the best ungapped placement per locus -
+/// the score covers the whole read, the bases outside the model are scored by the background
+type BestPerLocus = Vec<Option<(f64, usize, usize, usize, Strand)>>;
+
+/// The final placement of the read on one locus in the orientation the locus matched.
+struct Placement {
+    score: f64,
+    read_start: usize,
+    read_end: usize,
+    model_start: usize,
+    model_end: usize,
+    strand: Strand,
+    cigar: Option<Cigar>,
+}
+
+/// Scans reads one by one against a shared HMM.
+/// Clone the scanner to get one per thread - the model is shared, the workspace is not.
+#[derive(Clone)]
+pub struct Scanner {
+    hmm: Arc<HMM>,
+    workspace: Workspace,
+    prefilter: Option<Arc<KmerIndex>>,
+    prefilter_stats: Arc<PrefilterStats>,
+    band_stats: Arc<BandStats>,
+    decoy_scores: Arc<DecoyScores>,
+    /// true while the decoy of a read is scanned - it is not counted in the prefilter and band statistics
+    scanning_decoy: bool,
+    /// true while the rest of a read is scanned for more segments - it is not counted as a read by the prefilter
+    scanning_segment: bool,
+    /// a read is only scanned if any locus has a start candidate with at least this mean emission probability
+    pub min_start_score: f64,
+    /// also scan the reverse complement of the read
+    pub both_strands: bool,
+    /// call the best matching V, D and J germline genes for every hit
+    pub gene_calls: bool,
+    /// the number of k-mer seeded start positions checked per strand
+    pub max_seeds: usize,
+    /// the number of sliding window start candidates checked per locus and strand
+    pub start_candidates: usize,
+    /// tag every hit with the start candidates of its locus and strand
+    pub report_starts: bool,
+    /// also run the full scan on reads the prefilter rejected and count the hits it lost
+    pub prefilter_audit: bool,
+    /// realign the best start of every locus allowing insertions and deletions (None: ungapped scores only)
+    pub gaps: Option<GapModel>,
+    /// restrict the gapped alignment to this many model positions around the start diagonal (None: full DP)
+    pub band: Option<usize>,
+    /// also scan a decoy version of every read and collect its best log odds for the target-decoy FDR
+    pub decoy: Option<DecoyKind>,
+    /// trace the best path of every hit back and report where it switches locus (needs HMM::allow_locus_switches)
+    pub switch_detection: bool,
+    /// scan_segments reports at most this many rearrangements per read (1: the best hit only)
+    pub max_segments: usize,
+    /// scan_segments only rescans read stretches of at least this many bases left of and right of a segment
+    pub min_segment_len: usize,
+    /// the log odds a hit in the rest of a read needs to count as another segment -
+    /// random 300 base stretches reach about 20 against all loci with the long read gap model
+    pub min_segment_log_odds: f64,
+}
+
+impl Scanner {
+    /// A scanner with the defaults of the library: forward strand only, no gene calls, no prefilter, ungapped.
+    pub fn new(hmm: HMM) -> Self {
+        Self::from_shared(Arc::new(hmm))
+    }
+
+    pub fn from_shared(hmm: Arc<HMM>) -> Self {
+        Self {
+            hmm,
+            workspace: Workspace::default(),
+            prefilter: None,
+            prefilter_stats: Arc::new(PrefilterStats::default()),
+            band_stats: Arc::new(BandStats::default()),
+            decoy_scores: Arc::new(DecoyScores::default()),
+            scanning_decoy: false,
+            scanning_segment: false,
+            min_start_score: 0.3,
+            both_strands: false,
+            gene_calls: false,
+            max_seeds: 8,
+            start_candidates: START_CANDIDATES,
+            report_starts: false,
+            prefilter_audit: false,
+            gaps: None,
+            band: Some(16),
+            decoy: None,
+            switch_detection: false,
+            max_segments: 1,
+            min_segment_len: 100,
+            min_segment_log_odds: 25.0,
+        }
+    }
+
+    pub fn hmm(&self) -> &HMM {
+        &self.hmm
+    }
+
+    /// Build a k-mer index from the germline sequences and only scan reads sharing at least one k-mer with them,
+    /// starting at the diagonals the shared k-mers support instead of sliding the read over the whole model.
+    /// Fails for k-mer lengths outside kmer_index::KMER_LENGTHS.
+    pub fn enable_prefilter(&mut self, k: usize) -> Result<()> {
+        self.prefilter = Some(Arc::new(KmerIndex::build(&self.hmm, k)?));
+        self.prefilter_stats = Arc::new(PrefilterStats::default());
+        Ok(())
+    }
+
+    /// Settings for Nanopore and PacBio reads: the long read gap model (GapModel::long_reads) with a band of
+    /// at least 48 positions, up to max_segments rearrangements per read (see scan_segments) and the starts of
+    /// a 12-mer prefilter unless one is enabled already. The ungapped start score filter is switched off -
+    /// the indels of long reads leave an ungapped diagonal after a few bases.
+    pub fn enable_long_reads(&mut self, max_segments: usize) {
+        self.gaps = Some(GapModel::long_reads());
+        self.band = self.band.map(|band| band.max(48));
+        self.max_segments = max_segments;
+        self.min_start_score = 0.0;
+        if self.prefilter.is_none() {
+            self.enable_prefilter(12).expect("12 is a supported k-mer length");
+        }
+    }
+
+    /// the prefilter pass counts of this scanner and all its clones
+    pub fn prefilter_stats(&self) -> Option<&PrefilterStats> {
+        self.prefilter.as_ref().map(|_| self.prefilter_stats.as_ref())
+    }
+
+    /// the banded alignment counts of this scanner and all its clones if gapped alignment is enabled
+    pub fn band_stats(&self) -> Option<&BandStats> {
+        self.gaps.and(self.band).map(|_| self.band_stats.as_ref())
+    }
+
+    /// the best decoy log odds of every read scanned by this scanner and all its clones if decoy scoring is enabled
+    pub fn decoy_scores(&self) -> Option<&DecoyScores> {
+        self.decoy.map(|_| self.decoy_scores.as_ref())
+    }
+
+    /// Scan one read and return one result per locus (best strand and start),
+    /// sorted by posterior with the best hit first. Reads without a start candidate return an empty Vec.
+    pub fn scan(&mut self, sequence: &[u8]) -> Vec<ScanResult> {
+        let mut revcomp = std::mem::take(&mut self.workspace.revcomp);
+        revcomp.clear();
+        if self.both_strands {
+            reverse_complement(sequence, &mut revcomp);
+        }
+        let results = self.scan_oriented(sequence, &revcomp);
+        self.workspace.revcomp = revcomp;
+        if let Some(kind) = self.decoy {
+            self.scan_decoy(kind, sequence);
+        }
+        results
+    }
+
+    /// scan the decoy of the read the same way (without gene calls) and record the log odds of its best hit
+    fn scan_decoy(&mut self, kind: DecoyKind, sequence: &[u8]) {
+        let mut decoy = std::mem::take(&mut self.workspace.decoy);
+        let mut revcomp = std::mem::take(&mut self.workspace.decoy_revcomp);
+        kind.decoy(sequence, &mut decoy);
+        revcomp.clear();
+        if self.both_strands {
+            reverse_complement(&decoy, &mut revcomp);
+        }
+        let gene_calls = std::mem::replace(&mut self.gene_calls, false);
+        self.scanning_decoy = true;
+        if let Some(best) = self.scan_oriented(&decoy, &revcomp).first() {
+            self.decoy_scores.add(best.log_odds);
+        }
+        self.scanning_decoy = false;
+        self.gene_calls = gene_calls;
+        self.workspace.decoy = decoy;
+        self.workspace.decoy_revcomp = revcomp;
+    }
+
+    /// the best locus for this read if it passes the start candidate filter
+    pub fn best_hit(&mut self, sequence: &[u8]) -> Option<ScanResult> {
+        self.scan(sequence).into_iter().next()
+    }
+
+    /// Scan a read that may hold several rearrangements (long reads, concatemers): the best hit of the whole read
+    /// is the first segment, then the read stretches left of and right of every segment are scanned again
+    /// until max_segments hits were found or no stretch of min_segment_len bases has a hit with min_segment_log_odds.
+    /// Gapped hits of the same locus and strand that continue each other along the model are one rearrangement
+    /// (e.g. the V and the J gene split at the junction) and are joined.
+    /// Returns one hit per segment, the best segment first and the others in read order;
+    /// their read intervals, scores, CIGARs, switches and starts refer to the whole read.
+    pub fn scan_segments(&mut self, sequence: &[u8]) -> Vec<ScanResult> {
+        let mut hits: Vec<ScanResult> = match self.best_hit(sequence) {
+            Some(best) => vec![best],
+            None => return Vec::new(),
+        };
+        let mut stretches = vec![(0, hits[0].read_start), (hits[0].read_end, sequence.len())];
+        // the decoy of the read was scanned with the whole read
+        let decoy = self.decoy.take();
+        self.scanning_segment = true;
+        while let Some((lo, hi)) = stretches.pop() {
+            if hits.len() >= self.max_segments {
+                break
+            }
+            if hi - lo < self.min_segment_len.max(1) {
+                continue
+            }
+            let mut hit = match self.best_hit(&sequence[lo..hi]) {
+                Some(hit) if hit.log_odds >= self.min_segment_log_odds => hit,
+                _ => continue,
+            };
+            shift_segment(&mut hit, lo, hi, sequence.len());
+            stretches.push((lo, hit.read_start));
+            stretches.push((hit.read_end, hi));
+            hits.push(hit);
+        }
+        self.scanning_segment = false;
+        self.decoy = decoy;
+
+        hits.sort_by_key(|hit| hit.read_start);
+        let mut segments: Vec<ScanResult> = Vec::with_capacity(hits.len());
+        for hit in hits {
+            match segments.last_mut() {
+                Some(last) if continues(last, &hit) => join_segments(last, hit, sequence.len()),
+                _ => segments.push(hit),
+            }
+        }
+        let best = segments.iter().enumerate()
+            .max_by(|a, b| a.1.log_odds.total_cmp(&b.1.log_odds))
+            .map_or(0, |(i, _)| i);
+        let best = segments.remove(best);
+        segments.insert(0, best);
+        segments
+    }
+
+    fn scan_oriented(&mut self, sequence: &[u8], revcomp: &[u8]) -> Vec<ScanResult> {
+        let (forward, reverse) = match &self.prefilter {
+            None => (self.window_starts(sequence), self.window_starts(revcomp)),
+            Some(index) => {
+                let mut forward_seeds = index.seeds(sequence);
+                let mut reverse_seeds = index.seeds(revcomp);
+                forward_seeds.retain(|seed| seed.hits >= MIN_SEED_HITS);
+                reverse_seeds.retain(|seed| seed.hits >= MIN_SEED_HITS);
+                let count = !self.scanning_decoy && !self.scanning_segment;
+                if count {
+                    self.prefilter_stats.reads.fetch_add(1, Ordering::Relaxed);
+                }
+                if forward_seeds.is_empty() && reverse_seeds.is_empty() {
+                    if self.prefilter_audit && count {
+                        let (forward, reverse) = (self.window_starts(sequence), self.window_starts(revcomp));
+                        if !self.results_for_starts(sequence, revcomp, &forward, &reverse).is_empty() {
+                            self.prefilter_stats.lost_hits.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    return Vec::new()
+                }
+                if count {
+                    self.prefilter_stats.passed.fetch_add(1, Ordering::Relaxed);
+                }
+                (self.seeded_starts(sequence, &forward_seeds), self.seeded_starts(revcomp, &reverse_seeds))
+            },
+        };
+        self.results_for_starts(sequence, revcomp, &forward, &reverse)
+    }
+
+    /// the start_candidates best starts per locus of the sliding window - none unless one passes min_start_score
+    fn window_starts(&self, sequence: &[u8]) -> Vec<StartCandidate> {
+        let starts = self.hmm.start_candidates(sequence, self.start_candidates);
+        if starts.iter().any(|candidate| candidate.score > self.min_start_score) {
+            starts
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// the best supported k-mer diagonals in the locus of their k-mers - none unless one passes min_start_score in any locus
+    fn seeded_starts(&self, sequence: &[u8], seeds: &[Seed]) -> Vec<StartCandidate> {
+        let mut starts: Vec<StartCandidate> = Vec::new();
+        let mut passed = false;
+        for seed in seeds.iter().take(self.max_seeds) {
+            if let Some(scores) = self.hmm.start_score(sequence, seed.read_start, seed.start) {
+                passed |= scores.iter().any(|score| *score > self.min_start_score);
+                let len = (sequence.len() - seed.read_start).min(self.hmm.locus_len(seed.locus).saturating_sub(seed.start));
+                starts.push(StartCandidate { locus: seed.locus, start: seed.start, read_start: seed.read_start, len, score: scores[seed.locus] });
+            }
+        }
+        if !passed {
+            starts.clear();
+        }
+        // ordered like HMM::start_candidates
+        starts.sort_by(|a, b| a.locus.cmp(&b.locus).then(b.excess().total_cmp(&a.excess())));
+        starts
+    }
+
+    fn results_for_starts(&mut self, sequence: &[u8], revcomp: &[u8], forward: &[StartCandidate], reverse: &[StartCandidate]) -> Vec<ScanResult> {
+        let num_states = self.hmm.names.len();
+        let mut best: BestPerLocus = vec![None; num_states];
+
+        self.scan_strand(sequence, forward, Strand::Forward, &mut best);
+        self.scan_strand(revcomp, reverse, Strand::Reverse, &mut best);
+
+        let placements: Vec<Option<Placement>> = best.iter().enumerate()
+            .map(|(i, b)| b.map(|(score, read_start, start, end, strand)| {
+                let oriented = match strand {
+                    Strand::Forward => sequence,
+                    Strand::Reverse => revcomp,
+                };
+                self.align_gapped(i, oriented, start as i64 - read_start as i64, strand).unwrap_or(Placement {
+                    score,
+                    read_start,
+                    read_end: read_start + end - start,
+                    model_start: start,
+                    model_end: end,
+                    strand,
+                    cigar: None,
+                })
+            }))
+            .collect();
+
+        let max_score = placements.iter().flatten().map(|p| p.score).fold(-f64::INFINITY, f64::max);
+        if max_score == -f64::INFINITY {
+            return Vec::new()
+        }
+        let total: f64 = placements.iter().flatten().map(|p| (p.score - max_score).exp()).sum();
+
+        let mut results: Vec<ScanResult> = placements.into_iter().enumerate()
+            .filter_map(|(i, placement)| placement.map(|placement| {
+                let oriented = match placement.strand {
+                    Strand::Forward => sequence,
+                    Strand::Reverse => revcomp,
+                };
+                let genes = if !self.gene_calls {
+                    None
+                } else if placement.cigar.is_some() {
+                    Some(self.call_genes(i, &oriented[placement.read_start..placement.read_end], placement.model_start))
+                } else {
+                    // the germlines are compared along the whole diagonal, not only the best scoring stretch of it
+                    let diagonal = placement.model_start as i64 - placement.read_start as i64;
+                    let from = (-diagonal).max(0) as usize;
+                    let to = oriented.len().min((self.hmm.locus_len(i) as i64 - diagonal).max(0) as usize).max(from);
+                    Some(self.call_genes(i, &oriented[from..to], (from as i64 + diagonal) as usize))
+                };
+                // reported in the original read orientation
+                let (read_start, read_end) = match placement.strand {
+                    Strand::Forward => (placement.read_start, placement.read_end),
+                    Strand::Reverse => (sequence.len() - placement.read_end, sequence.len() - placement.read_start),
+                };
+                let switches = if self.switch_detection && !self.scanning_decoy {
+                    self.locus_switches(i, oriented, &placement)
+                } else {
+                    Vec::new()
+                };
+                let starts = if self.report_starts {
+                    let candidates = match placement.strand {
+                        Strand::Forward => forward,
+                        Strand::Reverse => reverse,
+                    };
+                    candidates.iter().filter(|candidate| candidate.locus == i).copied().collect()
+                } else {
+                    Vec::new()
+                };
+                ScanResult {
+                    locus: self.hmm.names[i].clone(),
+                    species: self.hmm.species[i].clone(),
+                    strand: placement.strand,
+                    read_start,
+                    read_end,
+                    model_start: placement.model_start,
+                    model_end: placement.model_end,
+                    score: placement.score,
+                    // soft clipped and overhanging bases are scored by the background model, so the log odds cover the whole read
+                    log_odds: placement.score - oriented.len() as f64 * BACKGROUND.ln(),
+                    posterior: (placement.score - max_score).exp() / total,
+                    genes,
+                    cigar: placement.cigar,
+                    q_value: None,
+                    switches,
+                    starts,
+                }
+            }))
+            .collect();
+        results.sort_by(|a, b| b.posterior.partial_cmp(&a.posterior).unwrap_or(std::cmp::Ordering::Equal));
+        results
+    }
+
+    /// The gapped alignment of the oriented read against locus around the diagonal of the ungapped start -
+    /// None if gapped alignment is disabled.
+    fn align_gapped(&mut self, locus: usize, oriented: &[u8], diagonal: i64, strand: Strand) -> Option<Placement> {
+        let gaps = self.gaps.as_ref()?;
+        let stats = (!self.scanning_decoy).then_some(self.band_stats.as_ref());
+        let alignment = match self.band {
+            Some(band) => banded::align_banded(&self.hmm, locus, oriented, diagonal, band, gaps, stats, &mut self.workspace.align)?,
+            None => banded::align_full(&self.hmm, locus, oriented, gaps, &mut self.workspace.align)?,
+        };
+        Some(Placement {
+            score: alignment.score,
+            read_start: alignment.read_start,
+            read_end: alignment.read_end,
+            model_start: alignment.model_start,
+            model_end: alignment.model_end,
+            strand,
+            cigar: Some(alignment.cigar),
+        })
+    }
+
+    /// The best start candidate of every locus on this strand - a locus is only scored at its own candidates,
+    /// and only the best scoring stretch of the diagonal counts (HMM::best_stretch): the rest of the read is
+    /// scored by the background like the bases overhanging the profile.
+    fn scan_strand(&mut self, sequence: &[u8], starts: &[StartCandidate], strand: Strand, best: &mut BestPerLocus) {
+        for candidate in starts {
+            let (log_odds, from, to) = match self.hmm.best_stretch(candidate.locus, sequence, candidate.read_start, candidate.start) {
+                Some(stretch) => stretch,
+                None => continue,
+            };
+            let score = log_odds + sequence.len() as f64 * BACKGROUND.ln();
+            if best[candidate.locus].is_none_or(|b| score > b.0) {
+                let start = candidate.start + from - candidate.read_start;
+                best[candidate.locus] = Some((score, from, start, start + to - from, strand));
+            }
+        }
+    }
+
+    /// The switches of the best ungapped path ending in locus for the placed read interval
+    /// (gapped placements are traced without their insertions and deletions).
+    fn locus_switches(&self, locus: usize, oriented: &[u8], placement: &Placement) -> Vec<LocusSwitch> {
+        let interval = &oriented[placement.read_start..placement.read_end];
+        let path = match self.hmm.viterbi_path(interval, placement.model_start, locus) {
+            Some(path) => path,
+            None => return Vec::new(),
+        };
+        path.windows(2).enumerate()
+            .filter(|(_, pair)| pair[0] != pair[1])
+            .map(|(k, pair)| {
+                let read_pos = placement.read_start + k + 1;
+                LocusSwitch {
+                    read_pos: match placement.strand {
+                        Strand::Forward => read_pos,
+                        Strand::Reverse => oriented.len() - read_pos,
+                    },
+                    model_pos: placement.model_start + k + 1,
+                    from: self.hmm.label(pair[0]),
+                    to: self.hmm.label(pair[1]),
+                }
+            })
+            .collect()
+    }
+
+    /// The germline V, D and J gene with the most identical bases to the read aligned at model position start.
+    fn call_genes(&self, locus: usize, sequence: &[u8], start: usize) -> GeneCalls {
+        let mut calls = GeneCalls::default();
+        // (matches, gene) per segment
+        let mut best: [(usize, Option<&str>); 3] = [(0, None); 3];
+        for germline in &self.hmm.germlines[locus] {
+            let min_compared = match germline.segment {
+                Chain::D => 8,
+                Chain::V | Chain::J => 20,
+            };
+            let mut compared = 0;
+            let mut matches = 0;
+            for (i, base) in sequence.iter().enumerate() {
+                let pos = start + i;
+                if pos < germline.start {
+                    continue
+                }
+                let g = match germline.sequence.get(pos - germline.start) {
+                    Some(g) => g.to_ascii_uppercase(),
+                    None => break,
+                };
+                if HMM::char2pos(g).is_none() {
+                    continue
+                }
+                compared += 1;
+                if g == base.to_ascii_uppercase() {
+                    matches += 1;
+                }
+            }
+            let id = germline.segment.id();
+            if compared >= min_compared && matches > best[id].0 {
+                best[id] = (matches, Some(&germline.gene));
+            }
+        }
+        calls.v = best[Chain::V.id()].1.map(str::to_string);
+        calls.d = best[Chain::D.id()].1.map(str::to_string);
+        calls.j = best[Chain::J.id()].1.map(str::to_string);
+        calls
+    }
+}
+
+/// move a hit of the read stretch [lo, hi) to the coordinates of the whole read of length len -
+/// the bases outside the stretch are scored by the background
+fn shift_segment(hit: &mut ScanResult, lo: usize, hi: usize, len: usize) {
+    hit.score += (len - (hi - lo)) as f64 * BACKGROUND.ln();
+    // the bases before the stretch in the orientation the locus matched
+    let (before, after) = match hit.strand {
+        Strand::Forward => (lo, len - hi),
+        Strand::Reverse => (len - hi, lo),
+    };
+    hit.read_start += lo;
+    hit.read_end += lo;
+    for switch in hit.switches.iter_mut() {
+        switch.read_pos += lo;
+    }
+    for candidate in hit.starts.iter_mut() {
+        candidate.read_start += before;
+    }
+    if let Some(cigar) = hit.cigar.as_mut() {
+        let mut shifted = Cigar::default();
+        shifted.push('S', before as u32);
+        for (n, op) in &cigar.0 {
+            shifted.push(*op, *n);
+        }
+        shifted.push('S', after as u32);
+        *cigar = shifted;
+    }
+}
+
+/// true if next (later in the read) is the same gapped rearrangement as last, continuing it along the model
+fn continues(last: &ScanResult, next: &ScanResult) -> bool {
+    if last.label() != next.label() || last.strand != next.strand || last.cigar.is_none() || next.cigar.is_none() {
+        return false
+    }
+    match last.strand {
+        Strand::Forward => next.model_start >= last.model_end,
+        Strand::Reverse => last.model_start >= next.model_end,
+    }
+}
+
+/// Join next into last (see continues): the read bases between them are an insertion, the model positions
+/// between them a deletion; both are scored by the background like clipped bases.
+fn join_segments(last: &mut ScanResult, next: ScanResult, len: usize) {
+    let read_gap = next.read_start - last.read_end;
+    // the two hits in model order
+    let (first, second) = match last.strand {
+        Strand::Forward => (last.clone(), next),
+        Strand::Reverse => (next, last.clone()),
+    };
+    let (first_cigar, second_cigar) = match (&first.cigar, &second.cigar) {
+        (Some(first), Some(second)) => (first, second),
+        _ => return,
+    };
+    let mut cigar = Cigar::default();
+    let aligned = |cigar: &Cigar| cigar.0.iter().filter(|(_, op)| *op != 'S').cloned().collect::<Vec<(u32, char)>>();
+    cigar.push('S', first_cigar.0.first().filter(|(_, op)| *op == 'S').map_or(0, |(n, _)| *n));
+    for (n, op) in aligned(first_cigar) {
+        cigar.push(op, n);
+    }
+    cigar.push('I', read_gap as u32);
+    cigar.push('D', (second.model_start - first.model_end) as u32);
+    for (n, op) in aligned(second_cigar) {
+        cigar.push(op, n);
+    }
+    cigar.push('S', second_cigar.0.last().filter(|(_, op)| *op == 'S').map_or(0, |(n, _)| *n));
+
+    let log_odds = first.log_odds + second.log_odds;
+    let better = if first.log_odds >= second.log_odds { &first } else { &second };
+    let genes = match (&first.genes, &second.genes) {
+        (Some(a), Some(b)) => Some(GeneCalls {
+            v: a.v.clone().or_else(|| b.v.clone()),
+            d: a.d.clone().or_else(|| b.d.clone()),
+            j: b.j.clone().or_else(|| a.j.clone()),
+        }),
+        (a, b) => a.clone().or_else(|| b.clone()),
+    };
+    let mut switches = first.switches.clone();
+    switches.extend(second.switches.iter().cloned());
+    switches.sort_by_key(|switch| switch.read_pos);
+    let mut starts = first.starts.clone();
+    starts.extend(second.starts.iter().copied());
+    *last = ScanResult {
+        locus: better.locus.clone(),
+        species: better.species.clone(),
+        strand: better.strand,
+        read_start: first.read_start.min(second.read_start),
+        read_end: first.read_end.max(second.read_end),
+        model_start: first.model_start,
+        model_end: second.model_end,
+        score: log_odds + len as f64 * BACKGROUND.ln(),
+        log_odds,
+        posterior: better.posterior,
+        genes,
+        cigar: Some(cigar),
+        q_value: better.q_value,
+        switches,
+        starts,
+    };
+}
+
+/// write the reverse complement of sequence into ret
+pub fn reverse_complement(sequence: &[u8], ret: &mut Vec<u8>) {
+    ret.clear();
+    ret.extend(sequence.iter().rev().map(|base| match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        b'a' => b't',
+        b'c' => b'g',
+        b'g' => b'c',
+        b't' => b'a',
+        other => *other,
+    }));
+}
@@ -0,0 +1,235 @@
+// simd.rs
+
+use crate::HMM::LogProb;
+
+use std::sync::OnceLock;
+
+/// The implementation of the per base DP step.
+/// All backends do the same additions and maximums, so their results are identical.
+/// Like f64::max the maximum ignores NaN values: the x86 max instructions return their second operand
+/// if one is NaN, so the running maximum is always passed second; NEON uses the IEEE maxNum instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Scalar,
+    /// x86_64 SSE2 (always available on x86_64)
+    Sse2,
+    /// x86_64 AVX2, detected at runtime
+    Avx2,
+    /// aarch64 NEON (always available on aarch64)
+    Neon,
+}
+
+impl Backend {
+    /// the fastest backend this CPU supports - detected once
+    pub fn detect() -> Self {
+        static BACKEND: OnceLock<Backend> = OnceLock::new();
+        *BACKEND.get_or_init(|| {
+            #[cfg(target_arch = "x86_64")]
+            {
+                if is_x86_feature_detected!("avx2") {
+                    return Backend::Avx2
+                }
+                Backend::Sse2
+            }
+            #[cfg(target_arch = "aarch64")]
+            {
+                Backend::Neon
+            }
+            #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+            {
+                Backend::Scalar
+            }
+        })
+    }
+}
+
+/// One Viterbi step over all loci at once:
+/// cur[to] = max over from (prev[from] + transitions[from * n + to]) + emissions[to]
+/// with transitions stored as [from][to] and n = prev.len().
+pub fn viterbi_step(backend: Backend, prev: &[LogProb], transitions: &[LogProb], emissions: &[LogProb], cur: &mut [LogProb]) {
+    let n = prev.len();
+    assert!(cur.len() == n && emissions.len() == n && transitions.len() == n * n, "viterbi_step: inconsistent buffer sizes");
+    match backend {
+        #[cfg(target_arch = "x86_64")]
+        // SAFETY: Avx2 is only returned by Backend::detect if the CPU supports it
+        Backend::Avx2 if is_x86_feature_detected!("avx2") => unsafe { x86::step_avx2(prev, transitions, emissions, cur) },
+        #[cfg(target_arch = "x86_64")]
+        // SAFETY: SSE2 is part of the x86_64 baseline
+        Backend::Sse2 | Backend::Avx2 => unsafe { x86::step_sse2(prev, transitions, emissions, cur) },
+        #[cfg(target_arch = "aarch64")]
+        // SAFETY: NEON is part of the aarch64 baseline
+        Backend::Neon => unsafe { neon::step_neon(prev, transitions, emissions, cur) },
+        _ => step_scalar(prev, transitions, emissions, cur),
+    }
+}
+
+pub fn step_scalar(prev: &[LogProb], transitions: &[LogProb], emissions: &[LogProb], cur: &mut [LogProb]) {
+    let n = prev.len();
+    cur.fill(LogProb::NEG_INFINITY);
+    for (from, p) in prev.iter().enumerate() {
+        for (c, t) in cur.iter_mut().zip(&transitions[from * n..(from + 1) * n]) {
+            *c = c.max(p + t);
+        }
+    }
+    for (c, e) in cur.iter_mut().zip(emissions) {
+        *c += e;
+    }
+}
+
+/// the scalar tail for the loci that do not fill a whole vector
+#[cfg_attr(not(any(target_arch = "x86_64", target_arch = "aarch64")), allow(dead_code))]
+fn step_tail(prev: &[LogProb], transitions: &[LogProb], emissions: &[LogProb], cur: &mut [LogProb], from_to: usize) {
+    let n = prev.len();
+    for to in from_to..n {
+        let mut max = LogProb::NEG_INFINITY;
+        for (from, p) in prev.iter().enumerate() {
+            max = max.max(p + transitions[from * n + to]);
+        }
+        cur[to] = max + emissions[to];
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::step_tail;
+    use crate::HMM::LogProb;
+
+    #[cfg(not(feature = "f32"))]
+    mod lanes {
+        use std::arch::x86_64::*;
+        pub const SSE: usize = 2;
+        pub const AVX: usize = 4;
+        pub type Sse = __m128d;
+        pub type Avx = __m256d;
+        #[inline(always)] pub unsafe fn sse_set1(v: f64) -> Sse { _mm_set1_pd(v) }
+        #[inline(always)] pub unsafe fn sse_load(p: *const f64) -> Sse { _mm_loadu_pd(p) }
+        #[inline(always)] pub unsafe fn sse_store(p: *mut f64, v: Sse) { _mm_storeu_pd(p, v) }
+        #[inline(always)] pub unsafe fn sse_add(a: Sse, b: Sse) -> Sse { _mm_add_pd(a, b) }
+        #[inline(always)] pub unsafe fn sse_max(a: Sse, b: Sse) -> Sse { _mm_max_pd(a, b) }
+        #[target_feature(enable = "avx2")] pub unsafe fn avx_set1(v: f64) -> Avx { _mm256_set1_pd(v) }
+        #[target_feature(enable = "avx2")] pub unsafe fn avx_load(p: *const f64) -> Avx { _mm256_loadu_pd(p) }
+        #[target_feature(enable = "avx2")] pub unsafe fn avx_store(p: *mut f64, v: Avx) { _mm256_storeu_pd(p, v) }
+        #[target_feature(enable = "avx2")] pub unsafe fn avx_add(a: Avx, b: Avx) -> Avx { _mm256_add_pd(a, b) }
+        #[target_feature(enable = "avx2")] pub unsafe fn avx_max(a: Avx, b: Avx) -> Avx { _mm256_max_pd(a, b) }
+    }
+
+    #[cfg(feature = "f32")]
+    mod lanes {
+        use std::arch::x86_64::*;
+        pub const SSE: usize = 4;
+        pub const AVX: usize = 8;
+        pub type Sse = __m128;
+        pub type Avx = __m256;
+        #[inline(always)] pub unsafe fn sse_set1(v: f32) -> Sse { _mm_set1_ps(v) }
+        #[inline(always)] pub unsafe fn sse_load(p: *const f32) -> Sse { _mm_loadu_ps(p) }
+        #[inline(always)] pub unsafe fn sse_store(p: *mut f32, v: Sse) { _mm_storeu_ps(p, v) }
+        #[inline(always)] pub unsafe fn sse_add(a: Sse, b: Sse) -> Sse { _mm_add_ps(a, b) }
+        #[inline(always)] pub unsafe fn sse_max(a: Sse, b: Sse) -> Sse { _mm_max_ps(a, b) }
+        #[target_feature(enable = "avx2")] pub unsafe fn avx_set1(v: f32) -> Avx { _mm256_set1_ps(v) }
+        #[target_feature(enable = "avx2")] pub unsafe fn avx_load(p: *const f32) -> Avx { _mm256_loadu_ps(p) }
+        #[target_feature(enable = "avx2")] pub unsafe fn avx_store(p: *mut f32, v: Avx) { _mm256_storeu_ps(p, v) }
+        #[target_feature(enable = "avx2")] pub unsafe fn avx_add(a: Avx, b: Avx) -> Avx { _mm256_add_ps(a, b) }
+        #[target_feature(enable = "avx2")] pub unsafe fn avx_max(a: Avx, b: Avx) -> Avx { _mm256_max_ps(a, b) }
+    }
+
+    use lanes::*;
+
+    /// # Safety
+    /// the caller has to make sure the CPU supports SSE2 (always true on x86_64)
+    pub unsafe fn step_sse2(prev: &[LogProb], transitions: &[LogProb], emissions: &[LogProb], cur: &mut [LogProb]) {
+        let n = prev.len();
+        let vectors = n / SSE * SSE;
+        let mut to = 0;
+        while to < vectors {
+            let mut max = sse_set1(LogProb::NEG_INFINITY);
+            for (from, p) in prev.iter().enumerate() {
+                let t = sse_load(transitions.as_ptr().add(from * n + to));
+                max = sse_max(sse_add(sse_set1(*p), t), max);
+            }
+            let e = sse_load(emissions.as_ptr().add(to));
+            sse_store(cur.as_mut_ptr().add(to), sse_add(max, e));
+            to += SSE;
+        }
+        step_tail(prev, transitions, emissions, cur, vectors);
+    }
+
+    /// # Safety
+    /// the caller has to make sure the CPU supports AVX2
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn step_avx2(prev: &[LogProb], transitions: &[LogProb], emissions: &[LogProb], cur: &mut [LogProb]) {
+        let n = prev.len();
+        let vectors = n / AVX * AVX;
+        let mut to = 0;
+        while to < vectors {
+            let mut max = avx_set1(LogProb::NEG_INFINITY);
+            for (from, p) in prev.iter().enumerate() {
+                let t = avx_load(transitions.as_ptr().add(from * n + to));
+                max = avx_max(avx_add(avx_set1(*p), t), max);
+            }
+            let e = avx_load(emissions.as_ptr().add(to));
+            avx_store(cur.as_mut_ptr().add(to), avx_add(max, e));
+            to += AVX;
+        }
+        // the rest fits into SSE vectors and a scalar tail
+        let n_sse = vectors + (n - vectors) / SSE * SSE;
+        let mut to = vectors;
+        while to < n_sse {
+            let mut max = sse_set1(LogProb::NEG_INFINITY);
+            for (from, p) in prev.iter().enumerate() {
+                let t = sse_load(transitions.as_ptr().add(from * n + to));
+                max = sse_max(sse_add(sse_set1(*p), t), max);
+            }
+            let e = sse_load(emissions.as_ptr().add(to));
+            sse_store(cur.as_mut_ptr().add(to), sse_add(max, e));
+            to += SSE;
+        }
+        step_tail(prev, transitions, emissions, cur, n_sse);
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use super::step_tail;
+    use crate::HMM::LogProb;
+    use std::arch::aarch64::*;
+
+    /// # Safety
+    /// NEON is part of the aarch64 baseline
+    #[cfg(not(feature = "f32"))]
+    pub unsafe fn step_neon(prev: &[LogProb], transitions: &[LogProb], emissions: &[LogProb], cur: &mut [LogProb]) {
+        let n = prev.len();
+        let vectors = n / 2 * 2;
+        let mut to = 0;
+        while to < vectors {
+            let mut max = vdupq_n_f64(LogProb::NEG_INFINITY);
+            for (from, p) in prev.iter().enumerate() {
+                let t = vld1q_f64(transitions.as_ptr().add(from * n + to));
+                max = vmaxnmq_f64(max, vaddq_f64(vdupq_n_f64(*p), t));
+            }
+            let e = vld1q_f64(emissions.as_ptr().add(to));
+            vst1q_f64(cur.as_mut_ptr().add(to), vaddq_f64(max, e));
+            to += 2;
+        }
+        step_tail(prev, transitions, emissions, cur, vectors);
+    }
+
+    /// # Safety
+    /// NEON is part of the aarch64 baseline
+    #[cfg(feature = "f32")]
+    pub unsafe fn step_neon(prev: &[LogProb], transitions: &[LogProb], emissions: &[LogProb], cur: &mut [LogProb]) {
+        let n = prev.len();
+        let vectors = n / 4 * 4;
+        let mut to = 0;
+        while to < vectors {
+            let mut max = vdupq_n_f32(LogProb::NEG_INFINITY);
+            for (from, p) in prev.iter().enumerate() {
+                let t = vld1q_f32(transitions.as_ptr().add(from * n + to));
+                max = vmaxnmq_f32(max, vaddq_f32(vdupq_n_f32(*p), t));
+            }
+            let e = vld1q_f32(emissions.as_ptr().add(to));
+            vst1q_f32(cur.as_mut_ptr().add(to), vaddq_f32(max, e));
+            to += 4;
+        }
+        step_tail(prev, transitions, emissions, cur, vectors);
+    }
+}
@@ -0,0 +1,492 @@
+// simulate.rs
+
+use crate::errors::{HmmError, Result};
+use crate::germline::{read_file, read_germline_database, GermlineFormat, GermlineRecord};
+use crate::pipeline::Seqrec;
+use crate::scan_result::Strand;
+use crate::scanner::reverse_complement;
+use crate::VDJmodeler::{Chain, SequenceModel};
+
+use needletail::parse_fastx_file;
+
+use std::io::{self, Write};
+
+/// What the simulated reads look like.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimConfig {
+    pub read_length: usize,
+    /// only simulate these loci (all loci with V and J genes if empty)
+    pub loci: Vec<SequenceModel>,
+    /// only use these genes (e.g. IGHV1-2, TRBJ2*01 or the subgroup IGHV3) -
+    /// segments without a chosen gene use all their genes
+    pub genes: Vec<String>,
+    /// at most this many bases are removed from each joined segment end
+    pub max_trim: usize,
+    /// at most this many P nucleotides are added to an untrimmed segment end
+    pub max_p: usize,
+    /// at most this many random N nucleotides are added per junction
+    pub max_n: usize,
+    /// the bases of the constant gene following J (0 for none)
+    pub constant_length: usize,
+    /// somatic hypermutation - the substitution rate of the V(D)J bases
+    pub shm_rate: f64,
+    /// the substitution rate of the sequencer
+    pub error_rate: f64,
+    /// the fraction of reads reported as reverse complement
+    pub reverse_fraction: f64,
+    /// the fraction of decoy reads without a rearrangement
+    pub decoy_fraction: f64,
+    pub seed: u64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            read_length: 150,
+            loci: Vec::new(),
+            genes: Vec::new(),
+            max_trim: 8,
+            max_p: 2,
+            max_n: 10,
+            constant_length: 60,
+            shm_rate: 0.0,
+            error_rate: 0.001,
+            reverse_fraction: 0.5,
+            decoy_fraction: 0.1,
+            seed: 1,
+        }
+    }
+}
+
+/// An ungapped germline allele.
+#[derive(Debug, Clone)]
+struct Gene {
+    name: String,
+    sequence: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct LocusGenes {
+    v: Vec<Gene>,
+    d: Vec<Gene>,
+    j: Vec<Gene>,
+    c: Vec<Gene>,
+}
+
+/// The true origin of a simulated read - one line of the truth table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Truth {
+    pub read: String,
+    /// the locus label as the scanner reports it (e.g. IGH-VDJ or human:IGH-VDJ), None for decoys
+    pub locus: Option<String>,
+    pub v: Option<String>,
+    pub d: Option<String>,
+    pub j: Option<String>,
+    pub c: Option<String>,
+    pub strand: Strand,
+    /// bases removed from the V 3', D 5', D 3' and J 5' ends
+    pub trimmed: [usize; 4],
+    pub p_nucleotides: usize,
+    pub n_nucleotides: usize,
+    /// somatic hypermutations within the read
+    pub mutations: usize,
+    /// sequencing errors within the read
+    pub errors: usize,
+    /// the bases between the trimmed V and J genes (forward strand)
+    pub junction: Vec<u8>,
+}
+
+impl Truth {
+    pub const HEADER: &'static str = "read\tlocus\tv_gene\td_gene\tj_gene\tc_gene\tstrand\tv_3_trim\td_5_trim\td_3_trim\tj_5_trim\tp_nucleotides\tn_nucleotides\tmutations\terrors\tjunction";
+
+    fn decoy(read: String, strand: Strand) -> Self {
+        Self {
+            read,
+            locus: None,
+            v: None,
+            d: None,
+            j: None,
+            c: None,
+            strand,
+            trimmed: [0; 4],
+            p_nucleotides: 0,
+            n_nucleotides: 0,
+            mutations: 0,
+            errors: 0,
+            junction: Vec::new(),
+        }
+    }
+
+    pub fn is_decoy(&self) -> bool {
+        self.locus.is_none()
+    }
+
+    /// one tab separated line - missing values are written as '-' and decoys have the locus 'decoy'
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let field = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+        let junction = if self.junction.is_empty() { "-".to_string() } else { String::from_utf8_lossy(&self.junction).to_string() };
+        writeln!(
+            out, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.read, self.locus.as_deref().unwrap_or("decoy"), field(&self.v), field(&self.d), field(&self.j), field(&self.c),
+            self.strand, self.trimmed[0], self.trimmed[1], self.trimmed[2], self.trimmed[3],
+            self.p_nucleotides, self.n_nucleotides, self.mutations, self.errors, junction,
+        )
+    }
+}
+
+/// One simulated read and where it came from.
+#[derive(Debug, Clone)]
+pub struct SimRead {
+    pub record: Seqrec,
+    pub truth: Truth,
+}
+
+/// The same linear congruential generator the benchmark uses - reproducible for a seed without extra dependencies.
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+
+    /// uniform in 0..n
+    fn below(&mut self, n: usize) -> usize {
+        if n == 0 { 0 } else { self.next() as usize % n }
+    }
+
+    /// true with probability p
+    fn chance(&mut self, p: f64) -> bool {
+        p > 0.0 && (self.next() as f64 / (1u64 << 31) as f64) < p
+    }
+
+    fn base(&mut self) -> u8 {
+        b"ACGT"[self.below(4)]
+    }
+
+    /// a base other than base
+    fn substitute(&mut self, base: u8) -> u8 {
+        match b"ACGT".iter().position(|b| *b == base) {
+            Some(i) => b"ACGT"[(i + 1 + self.below(3)) % 4],
+            None => self.base(),
+        }
+    }
+
+    fn pick<'a>(&mut self, genes: &'a [Gene]) -> &'a Gene {
+        &genes[self.below(genes.len())]
+    }
+}
+
+/// The locus of a constant gene name (e.g. IGHG1*01, IGKC*01 or TRBC2*01).
+/// IGHD without a number is the delta constant gene, IGHD1-1 a D segment.
+pub fn constant_locus(name: &str) -> Option<SequenceModel> {
+    let gene = name.split('*').next()?.to_ascii_uppercase();
+    if let Some(isotype) = gene.strip_prefix("IGH") {
+        return match isotype.as_bytes() {
+            [b'D', next, ..] if next.is_ascii_digit() => None,
+            [b'M' | b'D' | b'G' | b'A' | b'E', ..] => Some(SequenceModel::IGH),
+            _ => None,
+        }
+    }
+    [
+        ("IGKC", SequenceModel::IGK),
+        ("IGLC", SequenceModel::IGL),
+        ("TRAC", SequenceModel::TRA),
+        ("TRBC", SequenceModel::TRB),
+        ("TRGC", SequenceModel::TRG),
+        ("TRDC", SequenceModel::TRD),
+    ].into_iter().find(|(prefix, _)| gene.starts_with(prefix)).map(|(_, locus)| locus)
+}
+
+/// The constant genes of a germline database - the model building ignores them.
+/// Fasta headers are identified by name, tsv files use the segment 'C'; AIRR-C JSON sets have none.
+pub fn read_constant_genes(path: &str) -> Result<Vec<(String, SequenceModel, Vec<u8>)>> {
+    let mut ret = Vec::new();
+    match GermlineFormat::from_path(path) {
+        GermlineFormat::Fasta => {
+            let mut reader = parse_fastx_file(path).map_err(|err| HmmError::from_needletail(path, err))?;
+            while let Some(record) = reader.next() {
+                let record = record.map_err(|err| HmmError::from_needletail(path, err))?;
+                let header = String::from_utf8_lossy(record.id()).to_string();
+                let found = header.split(['|', ' ', '\t'])
+                    .find_map(|field| constant_locus(field).map(|locus| (field.to_string(), locus)));
+                if let Some((gene, locus)) = found {
+                    ret.push((gene, locus, record.seq().into_owned()));
+                }
+            }
+        },
+        GermlineFormat::Tsv => {
+            let content = read_file(path)?;
+            for line in content.lines() {
+                let fields: Vec<&str> = line.trim_end().split('\t').collect();
+                if fields.len() >= 4 && fields[1].trim().eq_ignore_ascii_case("C") {
+                    if let Some(locus) = SequenceModel::from_locus(fields[2]) {
+                        ret.push((fields[0].to_string(), locus, fields[3].trim().as_bytes().to_vec()));
+                    }
+                }
+            }
+        },
+        GermlineFormat::AirrJson => {},
+    }
+    Ok(ret)
+}
+
+/// Generates V(D)J rearranged reads with a known origin from a germline database.
+pub struct Simulator {
+    config: SimConfig,
+    /// the loci reads are simulated from with their label
+    loci: Vec<(String, LocusGenes)>,
+    /// the sequences decoy reads are cut from - random sequence if empty
+    background: Vec<Vec<u8>>,
+    rng: Rng,
+    count: usize,
+}
+
+impl Simulator {
+    /// Read the V, D, J and constant genes of a database (species=path or path, as for the scan).
+    pub fn from_database(species: &str, path: &str, config: SimConfig) -> Result<Self> {
+        let records = read_germline_database(path)?;
+        let constants = read_constant_genes(path)?;
+        Self::new(species, &records, &constants, config)
+    }
+
+    pub fn new(species: &str, records: &[GermlineRecord], constants: &[(String, SequenceModel, Vec<u8>)], config: SimConfig) -> Result<Self> {
+        if config.read_length == 0 {
+            return Err(HmmError::InconsistentModel("the read length has to be at least 1".to_string()));
+        }
+        let mut rng = Rng(config.seed);
+        let mut per_locus: Vec<(SequenceModel, LocusGenes)> = Vec::new();
+        let mut add = |locus: &SequenceModel, segment: Option<Chain>, name: &str, sequence: &[u8]| {
+            let sequence: Vec<u8> = sequence.iter()
+                .filter(|c| c.is_ascii_alphabetic())
+                .map(|c| match c.to_ascii_uppercase() {
+                    base @ (b'A' | b'C' | b'G' | b'T') => base,
+                    // IUPAC codes are resolved once
+                    _ => rng.base(),
+                })
+                .collect();
+            if sequence.is_empty() {
+                return
+            }
+            let index = match per_locus.iter().position(|(l, _)| l == locus) {
+                Some(index) => index,
+                None => {
+                    per_locus.push((locus.clone(), LocusGenes::default()));
+                    per_locus.len() - 1
+                }
+            };
+            let genes = &mut per_locus[index].1;
+            let list = match segment {
+                Some(Chain::V) => &mut genes.v,
+                Some(Chain::D) => &mut genes.d,
+                Some(Chain::J) => &mut genes.j,
+                None => &mut genes.c,
+            };
+            list.push(Gene { name: name.to_string(), sequence });
+        };
+        for record in records {
+            add(&record.locus, Some(record.segment), &record.gene, &record.sequence);
+        }
+        for (name, locus, sequence) in constants {
+            add(locus, None, name, sequence);
+        }
+        per_locus.sort_by_key(|(locus, _)| locus.id());
+
+        for gene in &config.genes {
+            let known = per_locus.iter()
+                .flat_map(|(_, genes)| genes.v.iter().chain(&genes.d).chain(&genes.j).chain(&genes.c))
+                .any(|g| gene_matches(&g.name, gene));
+            if !known {
+                return Err(HmmError::InconsistentModel(format!("the gene {gene} is not part of the database")));
+            }
+        }
+        let is_chosen = |g: &Gene| config.genes.iter().any(|gene| gene_matches(&g.name, gene));
+        let chosen = |list: &mut Vec<Gene>| {
+            if list.iter().any(is_chosen) {
+                list.retain(is_chosen);
+                true
+            } else {
+                false
+            }
+        };
+        let mut loci = Vec::new();
+        for (locus, mut genes) in per_locus {
+            if genes.v.is_empty() || genes.j.is_empty() || !(config.loci.is_empty() || config.loci.contains(&locus)) {
+                continue
+            }
+            let mut any_chosen = false;
+            for list in [&mut genes.v, &mut genes.d, &mut genes.j, &mut genes.c] {
+                any_chosen |= chosen(list);
+            }
+            if !config.genes.is_empty() && !any_chosen {
+                continue
+            }
+            let label = match species {
+                "" => locus.name(),
+                species => format!("{}:{}", species, locus.name()),
+            };
+            loci.push((label, genes));
+        }
+        if loci.is_empty() && config.decoy_fraction < 1.0 {
+            return Err(HmmError::InconsistentModel("no locus with V and J genes left to simulate".to_string()));
+        }
+        Ok(Self { config, loci, background: Vec::new(), rng, count: 0 })
+    }
+
+    /// Cut the decoy reads from the sequences of this fasta/fastq file (e.g. a genome or transcriptome) instead of random sequence.
+    pub fn with_background(mut self, path: &str) -> Result<Self> {
+        let mut reader = parse_fastx_file(path).map_err(|err| HmmError::from_needletail(path, err))?;
+        while let Some(record) = reader.next() {
+            let record = record.map_err(|err| HmmError::from_needletail(path, err))?;
+            let sequence = record.seq().to_ascii_uppercase();
+            if !sequence.is_empty() {
+                self.background.push(sequence);
+            }
+        }
+        Ok(self)
+    }
+
+    /// the labels of the simulated loci
+    pub fn loci(&self) -> Vec<&str> {
+        self.loci.iter().map(|(label, _)| label.as_str()).collect()
+    }
+
+    pub fn next_read(&mut self) -> SimRead {
+        self.count += 1;
+        let name = format!("sim{}", self.count);
+        if self.loci.is_empty() || self.rng.chance(self.config.decoy_fraction) {
+            self.decoy(name)
+        } else {
+            self.rearranged(name)
+        }
+    }
+
+    fn rearranged(&mut self, name: String) -> SimRead {
+        let Simulator { config, loci, rng, .. } = self;
+        let (label, genes) = &loci[rng.below(loci.len())];
+        let v = rng.pick(&genes.v);
+        let d = (!genes.d.is_empty()).then(|| rng.pick(&genes.d));
+        let j = rng.pick(&genes.j);
+        let c = (config.constant_length > 0 && !genes.c.is_empty()).then(|| rng.pick(&genes.c));
+
+        let mut truth = Truth::decoy(name, Strand::Forward);
+        truth.locus = Some(label.clone());
+        truth.v = Some(v.name.clone());
+        truth.d = d.map(|g| g.name.clone());
+        truth.j = Some(j.name.clone());
+        truth.c = c.map(|g| g.name.clone());
+
+        let mut sequence = Vec::new();
+        let v_trim = rng.below(config.max_trim + 1).min(v.sequence.len());
+        sequence.extend_from_slice(&v.sequence[..v.sequence.len() - v_trim]);
+        let junction_start = sequence.len();
+        if v_trim == 0 {
+            truth.p_nucleotides += palindrome(rng, config.max_p, &v.sequence, true, &mut sequence);
+        }
+        truth.n_nucleotides += random_bases(rng, config.max_n, &mut sequence);
+        if let Some(d) = d {
+            let len = d.sequence.len();
+            let d5 = rng.below(config.max_trim + 1).min(len);
+            let d3 = rng.below(config.max_trim + 1).min(len - d5);
+            if d5 == 0 {
+                truth.p_nucleotides += palindrome(rng, config.max_p, &d.sequence, false, &mut sequence);
+            }
+            sequence.extend_from_slice(&d.sequence[d5..len - d3]);
+            if d3 == 0 {
+                truth.p_nucleotides += palindrome(rng, config.max_p, &d.sequence, true, &mut sequence);
+            }
+            truth.n_nucleotides += random_bases(rng, config.max_n, &mut sequence);
+            truth.trimmed[1] = d5;
+            truth.trimmed[2] = d3;
+        }
+        let j_trim = rng.below(config.max_trim + 1).min(j.sequence.len());
+        if j_trim == 0 {
+            truth.p_nucleotides += palindrome(rng, config.max_p, &j.sequence, false, &mut sequence);
+        }
+        let junction_end = sequence.len();
+        sequence.extend_from_slice(&j.sequence[j_trim..]);
+        let vdj_end = sequence.len();
+        if let Some(c) = c {
+            sequence.extend_from_slice(&c.sequence[..config.constant_length.min(c.sequence.len())]);
+        }
+        truth.trimmed[0] = v_trim;
+        truth.trimmed[3] = j_trim;
+        truth.junction = sequence[junction_start..junction_end].to_vec();
+
+        // a read covering the junction if it fits
+        let len = config.read_length.min(sequence.len());
+        let latest = sequence.len() - len;
+        let lo = junction_end.saturating_sub(len).min(latest);
+        let hi = junction_start.min(latest).max(lo);
+        let start = lo + rng.below(hi - lo + 1);
+        let mut read = sequence[start..start + len].to_vec();
+        for (i, base) in read.iter_mut().enumerate() {
+            if start + i < vdj_end && rng.chance(config.shm_rate) {
+                *base = rng.substitute(*base);
+                truth.mutations += 1;
+            }
+        }
+        self.finish(read, truth)
+    }
+
+    fn decoy(&mut self, name: String) -> SimRead {
+        let len = self.config.read_length;
+        let read = if self.background.is_empty() {
+            (0..len).map(|_| self.rng.base()).collect()
+        } else {
+            let source = &self.background[self.rng.below(self.background.len())];
+            let len = len.min(source.len());
+            let start = self.rng.below(source.len() - len + 1);
+            source[start..start + len].to_vec()
+        };
+        self.finish(read, Truth::decoy(name, Strand::Forward))
+    }
+
+    /// strand, sequencing errors and qualities
+    fn finish(&mut self, mut read: Vec<u8>, mut truth: Truth) -> SimRead {
+        if self.rng.chance(self.config.reverse_fraction) {
+            let mut reverse = Vec::with_capacity(read.len());
+            reverse_complement(&read, &mut reverse);
+            read = reverse;
+            truth.strand = Strand::Reverse;
+        }
+        for base in read.iter_mut() {
+            if self.rng.chance(self.config.error_rate) {
+                *base = self.rng.substitute(*base);
+                truth.errors += 1;
+            }
+        }
+        let phred = if self.config.error_rate > 0.0 { (-10.0 * self.config.error_rate.log10()).round().clamp(2.0, 41.0) as u8 } else { 41 };
+        let qual = vec![phred + 33; read.len()];
+        SimRead {
+            record: Seqrec::with_qual(truth.read.as_bytes(), &read, &qual),
+            truth,
+        }
+    }
+}
+
+/// gene is the allele name or a prefix of it ending at an allele or subgroup separator (IGHV1-2 matches IGHV1-2*01, not IGHV1-20*01)
+fn gene_matches(name: &str, gene: &str) -> bool {
+    name.strip_prefix(gene).is_some_and(|rest| rest.is_empty() || rest.starts_with(['*', '-']))
+}
+
+/// Append up to max_p P nucleotides - the reverse complement of the untrimmed 3' (or 5') end of gene.
+/// Returns the number of added bases.
+fn palindrome(rng: &mut Rng, max_p: usize, gene: &[u8], three_prime: bool, sequence: &mut Vec<u8>) -> usize {
+    let k = rng.below(max_p + 1).min(gene.len());
+    let end = if three_prime { &gene[gene.len() - k..] } else { &gene[..k] };
+    let mut bases = Vec::with_capacity(k);
+    reverse_complement(end, &mut bases);
+    sequence.extend_from_slice(&bases);
+    k
+}
+
+/// Append up to max_n random N nucleotides and return how many.
+fn random_bases(rng: &mut Rng, max_n: usize, sequence: &mut Vec<u8>) -> usize {
+    let n = rng.below(max_n + 1);
+    for _ in 0..n {
+        sequence.push(rng.base());
+    }
+    n
+}
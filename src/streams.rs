@@ -0,0 +1,191 @@
+// streams.rs
+
+use crate::bgzf::BgzfWriter;
+use crate::errors::{HmmError, Result};
+use crate::pipeline::Seqrec;
+use crate::sam_input::{BamRecords, SamFilter, SamRecords};
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use needletail::{parse_fastx_reader, FastxReader};
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+/// the first bytes of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// The compression of an output file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    Plain,
+    /// .gz
+    Gzip,
+    /// .bgz / .bgzf - blocked gzip that samtools and tabix can index
+    Bgzf,
+    /// .zst - needs the zstd feature
+    Zstd,
+}
+
+impl Codec {
+    /// the codec for a file name - stdout ('-') is written uncompressed
+    pub fn from_path(path: &str) -> Self {
+        let lower = path.to_ascii_lowercase();
+        if lower.ends_with(".gz") {
+            Codec::Gzip
+        } else if lower.ends_with(".bgz") || lower.ends_with(".bgzf") {
+            Codec::Bgzf
+        } else if lower.ends_with(".zst") {
+            Codec::Zstd
+        } else {
+            Codec::Plain
+        }
+    }
+}
+
+/// the buffered file or stdout below the compression
+pub type Sink = BufWriter<Box<dyn Write + Send>>;
+
+/// A buffered, possibly compressed output file or stdout.
+/// Call finish at the end - it writes the compression trailers and reports errors dropping would hide.
+pub enum Output {
+    Plain(Sink),
+    Gzip(GzEncoder<Sink>),
+    Bgzf(BgzfWriter<Sink>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, Sink>),
+}
+
+impl Output {
+    /// Create path ('-' for stdout) compressed according to its extension.
+    pub fn create(path: &str) -> Result<Self> {
+        Self::create_with_codec(path, Codec::from_path(path))
+    }
+
+    /// Create path ('-' for stdout) with a fixed codec (e.g. BGZF for BAM files).
+    pub fn create_with_codec(path: &str, codec: Codec) -> Result<Self> {
+        let inner: Box<dyn Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(File::create(path).map_err(|err| HmmError::io(path, err))?)
+        };
+        Self::with_codec(BufWriter::new(inner), codec).map_err(|err| HmmError::io(path, err))
+    }
+
+    pub fn with_codec(sink: Sink, codec: Codec) -> io::Result<Self> {
+        Ok(match codec {
+            Codec::Plain => Output::Plain(sink),
+            Codec::Gzip => Output::Gzip(GzEncoder::new(sink, Compression::default())),
+            Codec::Bgzf => Output::Bgzf(BgzfWriter::new(sink)),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Output::Zstd(zstd::Encoder::new(sink, 0)?),
+            #[cfg(not(feature = "zstd"))]
+            Codec::Zstd => return Err(io::Error::new(io::ErrorKind::Unsupported, "zstd output needs the zstd feature (cargo build --features zstd)")),
+        })
+    }
+
+    /// Finish the compressed stream and flush everything.
+    pub fn finish(self) -> io::Result<()> {
+        let mut sink = match self {
+            Output::Plain(sink) => sink,
+            Output::Gzip(encoder) => encoder.finish()?,
+            Output::Bgzf(writer) => writer.finish()?,
+            #[cfg(feature = "zstd")]
+            Output::Zstd(encoder) => encoder.finish()?,
+        };
+        sink.flush()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Plain(sink) => sink.write(buf),
+            Output::Gzip(encoder) => encoder.write(buf),
+            Output::Bgzf(writer) => writer.write(buf),
+            #[cfg(feature = "zstd")]
+            Output::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Plain(sink) => sink.flush(),
+            Output::Gzip(encoder) => encoder.flush(),
+            Output::Bgzf(writer) => writer.flush(),
+            #[cfg(feature = "zstd")]
+            Output::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// the first bytes of a gzip (and BGZF) member
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// the header lines a SAM file can start with
+const SAM_HEADERS: [&[u8]; 5] = [b"@HD\t", b"@SQ\t", b"@RG\t", b"@PG\t", b"@CO\t"];
+
+/// All reads of a fasta/fastq, SAM or BAM file or stdin ('-'), detected by content:
+/// gzip/BGZF and (with the zstd feature) zstd are decompressed first, BAM is recognized by its magic bytes,
+/// SAM by its header (or the .sam extension); everything else is parsed by needletail (which also handles bzip2 and xz).
+/// The filter selects the SAM/BAM alignments to scan.
+pub fn open_records(path: &str, filter: SamFilter) -> Result<Box<dyn Iterator<Item = Result<Seqrec>> + Send>> {
+    let inner: Box<dyn Read + Send> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path).map_err(|err| HmmError::io(path, err))?)
+    };
+    let mut reader = BufReader::new(inner);
+    let head = reader.fill_buf().map_err(|err| HmmError::io(path, err))?;
+    let mut reader: BufReader<Box<dyn Read + Send>> = if head.starts_with(&GZIP_MAGIC) {
+        BufReader::new(Box::new(MultiGzDecoder::new(reader)))
+    } else if head.starts_with(&ZSTD_MAGIC) {
+        BufReader::new(zstd_decoder(path, reader)?)
+    } else {
+        reader
+    };
+
+    let head = reader.fill_buf().map_err(|err| HmmError::io(path, err))?;
+    let lower = path.to_ascii_lowercase();
+    if head.starts_with(b"BAM\x01") {
+        Ok(Box::new(BamRecords::new(reader, filter, path)?))
+    } else if head.starts_with(b"CRAM") {
+        Err(HmmError::parse(path, None, None, "CRAM input is not supported - convert it with 'samtools view -b' or 'samtools fastq -T CB,UB'"))
+    } else if SAM_HEADERS.iter().any(|tag| head.starts_with(tag)) || lower.ends_with(".sam") || lower.ends_with(".sam.gz") {
+        Ok(Box::new(SamRecords::new(reader, filter, path)))
+    } else {
+        let reader = parse_fastx_reader(reader).map_err(|err| HmmError::from_needletail(path, err))?;
+        Ok(Box::new(FastxRecords { reader, path: path.to_string() }))
+    }
+}
+
+/// The reads of a fasta/fastq file.
+struct FastxRecords {
+    reader: Box<dyn FastxReader>,
+    path: String,
+}
+
+impl Iterator for FastxRecords {
+    type Item = Result<Seqrec>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(match self.reader.next()? {
+            Ok(record) => Ok(match record.qual() {
+                Some(qual) => Seqrec::with_qual(record.id(), &record.seq(), qual),
+                None => Seqrec::new(record.id(), &record.seq()),
+            }),
+            Err(err) => Err(HmmError::from_needletail(&self.path, err)),
+        })
+    }
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decoder(path: &str, reader: BufReader<Box<dyn Read + Send>>) -> Result<Box<dyn Read + Send>> {
+    Ok(Box::new(zstd::Decoder::with_buffer(reader).map_err(|err| HmmError::io(path, err))?))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decoder(path: &str, _reader: BufReader<Box<dyn Read + Send>>) -> Result<Box<dyn Read + Send>> {
+    Err(HmmError::parse(path, None, None, "zstd compressed input needs the zstd feature (cargo build --features zstd)"))
+}
@@ -0,0 +1,161 @@
+// tests/evaluate.rs
+//
+// The simulated reads with their truth table, the evaluation of scan results against it and the target-decoy q-values.
+
+use hmm_mapper::decoy::q_values;
+use hmm_mapper::evaluate::{read_truth_table, Called, Evaluation, Expected, ScoreField};
+use hmm_mapper::germline::read_germline_database;
+use hmm_mapper::scanner::reverse_complement;
+use hmm_mapper::simulate::{read_constant_genes, SimConfig, Simulator, Truth};
+use hmm_mapper::Strand;
+
+use std::collections::HashMap;
+
+const DATABASE: &str = "testData/IMGT+C.fa";
+
+fn expected(locus: Option<&str>) -> Expected {
+    Expected { locus: locus.map(str::to_string), v: Vec::new(), j: Vec::new() }
+}
+
+fn called(locus: &str, score: f64) -> Called {
+    Called { locus: locus.to_string(), score, v: None, j: None }
+}
+
+#[test]
+fn loci_without_calls_have_no_precision() {
+    let truth: HashMap<String, Expected> = [
+        ("a".to_string(), expected(Some("IGH"))),
+        ("b".to_string(), expected(Some("TRB"))),
+        ("c".to_string(), expected(None)),
+    ].into_iter().collect();
+    let calls: HashMap<String, Called> = [("a".to_string(), called("IGH", 10.0))].into_iter().collect();
+    let evaluation = Evaluation::new(&truth, &calls, ScoreField::LogOdds);
+
+    let trb = &evaluation.per_locus["TRB"];
+    assert_eq!((trb.true_positives, trb.false_positives, trb.false_negatives), (0, 0, 1));
+    assert!(trb.precision().is_nan());
+    assert_eq!(trb.recall(), 0.0);
+
+    // '-' in the text, null in the JSON
+    let text = evaluation.text();
+    let line = text.lines().find(|line| line.trim_start().starts_with("TRB")).expect("a TRB line");
+    assert_eq!(line.split_whitespace().collect::<Vec<_>>(), ["TRB", "0", "0", "1", "-", "0.0000"]);
+    assert!(!text.contains("NaN"), "{text}");
+    let json = evaluation.to_json();
+    assert!(json["per_locus"]["TRB"]["precision"].is_null());
+    assert_eq!(json["per_locus"]["IGH"]["precision"], 1.0);
+    assert_eq!(json["per_locus"]["TRB"]["recall"], 0.0);
+}
+
+#[test]
+fn simulated_reads_match_their_truth() {
+    // the germline alleles without IUPAC codes - the simulator resolves those randomly
+    let mut germlines: HashMap<String, Vec<u8>> = HashMap::new();
+    let records = read_germline_database(DATABASE).expect("the bundled database can be read");
+    let constants = read_constant_genes(DATABASE).expect("the bundled database can be read");
+    let genes = records.iter().map(|r| (&r.gene, &r.sequence)).chain(constants.iter().map(|(gene, _, sequence)| (gene, sequence)));
+    for (gene, sequence) in genes {
+        let sequence: Vec<u8> = sequence.iter().filter(|c| c.is_ascii_alphabetic()).map(|c| c.to_ascii_uppercase()).collect();
+        if sequence.iter().all(|c| b"ACGT".contains(c)) {
+            germlines.entry(gene.clone()).or_insert(sequence);
+        }
+    }
+
+    // long enough to hold the whole rearrangement, no mutations or errors
+    let config = SimConfig { read_length: 1000, shm_rate: 0.0, error_rate: 0.0, decoy_fraction: 0.2, seed: 5, ..SimConfig::default() };
+    let mut simulator = Simulator::new("", &records, &constants, config.clone()).expect("the bundled database simulates");
+    let mut table = format!("{}\n", Truth::HEADER).into_bytes();
+    let (mut checked, mut decoys) = (0, 0);
+    for _ in 0..300 {
+        let read = simulator.next_read();
+        let truth = &read.truth;
+        truth.write(&mut table).unwrap();
+        assert_eq!(read.record.id(), truth.read.as_bytes());
+        assert_eq!((truth.mutations, truth.errors), (0, 0));
+        if truth.is_decoy() {
+            assert_eq!((&truth.v, &truth.j, read.record.seq().len()), (&None, &None, config.read_length));
+            decoys += 1;
+            continue
+        }
+        let mut forward = read.record.seq().to_vec();
+        if truth.strand == Strand::Reverse {
+            reverse_complement(read.record.seq(), &mut forward);
+        }
+        let germline = |gene: &Option<String>| gene.as_ref().and_then(|gene| germlines.get(gene));
+        let (v, j) = match (germline(&truth.v), germline(&truth.j)) {
+            (Some(v), Some(j)) => (v, j),
+            _ => continue,
+        };
+        let [v_trim, d5, d3, j_trim] = truth.trimmed;
+        assert!(truth.trimmed.iter().all(|trim| *trim <= config.max_trim), "{:?}", truth.trimmed);
+        // V, the junction, J and the start of the constant gene, in this order
+        let v_end = v.len() - v_trim;
+        let j_end = v_end + truth.junction.len() + j.len() - j_trim;
+        assert_eq!(&forward[..v_end], &v[..v_end], "{}", truth.read);
+        assert_eq!(&forward[v_end..v_end + truth.junction.len()], &truth.junction[..], "{}", truth.read);
+        assert_eq!(&forward[v_end + truth.junction.len()..j_end], &j[j_trim..], "{}", truth.read);
+        match (&truth.c, germline(&truth.c)) {
+            (None, _) => assert_eq!(forward.len(), j_end, "{}", truth.read),
+            (Some(_), Some(c)) => assert_eq!(&forward[j_end..], &c[..config.constant_length.min(c.len())], "{}", truth.read),
+            // with IUPAC codes resolved by the simulator
+            (Some(_), None) => assert!(forward.len() > j_end, "{}", truth.read),
+        }
+        // the junction is made of the P and N nucleotides and what is left of D
+        let d_len = truth.d.as_ref().map_or(0, |d| germlines.get(d).map_or(0, |d| d.len() - d5 - d3));
+        if truth.d.is_none() || germline(&truth.d).is_some() {
+            assert_eq!(truth.junction.len(), truth.p_nucleotides + truth.n_nucleotides + d_len, "{}", truth.read);
+        }
+        checked += 1;
+    }
+    assert!(checked > 150 && decoys > 30, "{checked} rearranged and {decoys} decoy reads checked");
+
+    // the evaluation reads the table back
+    let path = std::env::temp_dir().join(format!("hmm_mapper_{}_truth.tsv", std::process::id()));
+    std::fs::write(&path, &table).unwrap();
+    let expected = read_truth_table(&path.to_string_lossy()).expect("the truth table can be read");
+    assert_eq!(expected.len(), 300);
+    for line in String::from_utf8(table).unwrap().lines().skip(1) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        assert_eq!(fields.len(), Truth::HEADER.split('\t').count());
+        let read = &expected[fields[0]];
+        assert_eq!(read.locus.as_deref(), if fields[1] == "decoy" { None } else { fields[1].split('-').next() });
+        assert_eq!(read.v.first().map(String::as_str), Some(fields[2]).filter(|v| *v != "-"));
+    }
+}
+
+#[test]
+fn q_values_of_ties() {
+    // 10 beats every decoy, the two 9s tie with a decoy, 5 lowers their FDR to 1 / 4
+    assert_eq!(q_values(&[10.0, 9.0, 9.0, 5.0, 1.0], &[9.0, 2.0]), [0.0, 0.25, 0.25, 0.25, 0.4]);
+    // unsorted input and a NaN target, which ranks last: accepting it lowers the FDR of 3 from 1 / 2 to 1 / 3
+    assert_eq!(q_values(&[f64::NAN, 3.0, 7.0], &[5.0]), [1.0 / 3.0, 1.0 / 3.0, 0.0]);
+    assert_eq!(q_values(&[1.0, 2.0], &[]), [0.0, 0.0]);
+    assert_eq!(q_values(&[1.0], &[4.0, 5.0]), [1.0]);
+    assert!(q_values(&[], &[1.0]).is_empty());
+}
+
+#[test]
+fn q_values_are_monotone() {
+    let mut state: u64 = 9;
+    let mut score = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        // few distinct values, so there are many ties
+        (state % 40) as f64
+    };
+    let targets: Vec<f64> = (0..500).map(|_| score() + 10.0).collect();
+    let decoys: Vec<f64> = (0..200).map(|_| score()).collect();
+    let q = q_values(&targets, &decoys);
+    let mut ranked: Vec<(f64, f64)> = targets.iter().copied().zip(q).collect();
+    ranked.sort_by(|a, b| b.0.total_cmp(&a.0));
+    for pair in ranked.windows(2) {
+        let ((score, q), (next_score, next_q)) = (pair[0], pair[1]);
+        assert!((0.0..=1.0).contains(&q));
+        if score == next_score {
+            assert_eq!(q, next_q, "tied targets get the same q-value");
+        } else {
+            assert!(q <= next_q, "a better target never gets a higher q-value: {score} {q} {next_score} {next_q}");
+        }
+    }
+}
@@ -0,0 +1,350 @@
+// tests/formats.rs
+//
+// The file formats we read and write: germline databases, compressed streams, SAM/BAM and the simulation truth tables.
+
+use hmm_mapper::bgzf::BgzfWriter;
+use hmm_mapper::germline::{read_germline_database, GermlineFormat, GermlineRecord};
+use hmm_mapper::pipeline::Seqrec;
+use hmm_mapper::sam::{reg2bin, SamFormat, SamWriter};
+use hmm_mapper::sam_input::{BamRecords, SamFilter};
+use hmm_mapper::scanner::reverse_complement;
+use hmm_mapper::streams::open_records;
+use hmm_mapper::VDJmodeler::{Chain, SequenceModel, VDJmodeler};
+use hmm_mapper::{Result, Scanner, Strand};
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// a file in the temp dir that is unique to this test process
+fn temp_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("hmm_mapper_{}_{name}", std::process::id()))
+}
+
+fn write_file(name: &str, content: &[u8]) -> String {
+    let path = temp_file(name);
+    if name.ends_with(".gz") {
+        let mut encoder = GzEncoder::new(fs::File::create(&path).unwrap(), Compression::default());
+        encoder.write_all(content).unwrap();
+        encoder.finish().unwrap();
+    } else {
+        fs::write(&path, content).unwrap();
+    }
+    path.to_string_lossy().to_string()
+}
+
+const FASTA: &str = ">X|IGHV1-2*02|Homo sapiens|F|V-REGION|\nCAGGTGCAG..CTGGTG\n>IGKJ1*01\nGTGGACGTTC\n>IGHG1*01\nGCCTCCACC\n";
+
+const TSV: &str = "gene\tsegment\tlocus\tsequence\n# a comment\nIGHV1-2*02\tV\tIGH\tCAGGTGCAG..CTGGTG\nIGHG1*01\tC\tIGH\tGCCTCCACC\nTRAJ1*01\tJ\tTRA\tTGGAAGAGC\n";
+
+const JSON: &str = r#"{"GermlineSet": [{"locus": "IGK", "allele_descriptions": [
+    {"label": "IGKV1-5*01", "sequence_type": "V", "sequence": "GACATCCAG",
+     "v_gene_delineations": [{"aligned_sequence": "GACATC...CAG"}]},
+    {"label": "IGKJ1*01", "sequence_type": "J", "locus": "IGK", "sequence": "GTGGACGTTC"},
+    {"label": "IGKC*01", "sequence_type": "C", "sequence": "CGAACTGTG"}
+]}]}"#;
+
+fn summary(records: &[GermlineRecord]) -> Vec<(String, Chain, SequenceModel, String)> {
+    records.iter()
+        .map(|r| (r.gene.clone(), r.segment, r.locus.clone(), String::from_utf8_lossy(&r.sequence).to_string()))
+        .collect()
+}
+
+#[test]
+fn germline_formats_from_the_extension() {
+    assert_eq!(GermlineFormat::from_path("IMGT.fa"), GermlineFormat::Fasta);
+    assert_eq!(GermlineFormat::from_path("IMGT.fasta.gz"), GermlineFormat::Fasta);
+    assert_eq!(GermlineFormat::from_path("set.JSON"), GermlineFormat::AirrJson);
+    assert_eq!(GermlineFormat::from_path("set.json.gz"), GermlineFormat::AirrJson);
+    assert_eq!(GermlineFormat::from_path("novel.tsv.gz"), GermlineFormat::Tsv);
+    // a .txt file is not necessarily a table
+    assert_eq!(GermlineFormat::from_path("notes.txt"), GermlineFormat::Fasta);
+}
+
+#[test]
+fn germline_fasta() {
+    for name in ["germline.fa", "germline.fa.gz"] {
+        let records = read_germline_database(&write_file(name, FASTA.as_bytes())).unwrap();
+        assert_eq!(summary(&records), vec![
+            ("IGHV1-2*02".to_string(), Chain::V, SequenceModel::IGH, "CAGGTGCAG..CTGGTG".to_string()),
+            ("IGKJ1*01".to_string(), Chain::J, SequenceModel::IGK, "GTGGACGTTC".to_string()),
+        ], "{name}");
+    }
+}
+
+#[test]
+fn germline_tsv() {
+    for name in ["germline.tsv", "germline.tsv.gz"] {
+        let records = read_germline_database(&write_file(name, TSV.as_bytes())).unwrap();
+        assert_eq!(summary(&records), vec![
+            ("IGHV1-2*02".to_string(), Chain::V, SequenceModel::IGH, "CAGGTGCAG..CTGGTG".to_string()),
+            ("TRAJ1*01".to_string(), Chain::J, SequenceModel::TRA, "TGGAAGAGC".to_string()),
+        ], "{name}");
+    }
+    let broken = write_file("broken.tsv", b"IGHV1-2*02\tV\tIGX\tCAGGTG\n");
+    assert!(read_germline_database(&broken).is_err());
+}
+
+#[test]
+fn germline_airr_json() {
+    for name in ["germline.json", "germline.json.gz"] {
+        let records = read_germline_database(&write_file(name, JSON.as_bytes())).unwrap();
+        assert_eq!(summary(&records), vec![
+            // the IMGT gapped sequence is preferred
+            ("IGKV1-5*01".to_string(), Chain::V, SequenceModel::IGK, "GACATC...CAG".to_string()),
+            ("IGKJ1*01".to_string(), Chain::J, SequenceModel::IGK, "GTGGACGTTC".to_string()),
+        ], "{name}");
+    }
+    // a set without usable alleles is an error, not an empty database
+    let empty = write_file("empty.json", br#"{"GermlineSet": {"locus": "IGK", "allele_descriptions": [{"label": "IGKV1-5*01"}]}}"#);
+    assert!(read_germline_database(&empty).is_err());
+}
+
+/// the BGZF end of file marker (SAM/BAM specification 4.1.2)
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43,
+    0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// the (block size, uncompressed size) of every BGZF block
+fn bgzf_blocks(mut data: &[u8]) -> Vec<(usize, usize)> {
+    let mut ret = Vec::new();
+    while !data.is_empty() {
+        assert!(data.len() >= 28, "a truncated block");
+        // gzip member with FEXTRA and the 6 byte BC subfield
+        assert_eq!(&data[..4], &[0x1f, 0x8b, 0x08, 0x04]);
+        assert_eq!(&data[10..16], &[0x06, 0x00, b'B', b'C', 0x02, 0x00]);
+        let size = u16::from_le_bytes([data[16], data[17]]) as usize + 1;
+        let isize = u32::from_le_bytes(data[size - 4..size].try_into().unwrap()) as usize;
+        ret.push((size, isize));
+        data = &data[size..];
+    }
+    ret
+}
+
+#[test]
+fn bgzf_blocks_and_end_of_file() {
+    let mut state: u64 = 3;
+    let content: Vec<u8> = (0..200_000).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        b"ACGT\n"[(state % 5) as usize]
+    }).collect();
+    let mut writer = BgzfWriter::new(Vec::new());
+    // written in pieces that do not line up with the blocks
+    for chunk in content.chunks(7777) {
+        writer.write_all(chunk).unwrap();
+    }
+    let compressed = writer.finish().unwrap();
+
+    assert!(compressed.ends_with(&BGZF_EOF));
+    let blocks = bgzf_blocks(&compressed);
+    assert_eq!(blocks.last(), Some(&(28, 0)));
+    // full blocks of 0xff00 bytes and the rest
+    let sizes: Vec<usize> = blocks.iter().map(|(_, isize)| *isize).collect();
+    assert_eq!(sizes, [0xff00, 0xff00, 0xff00, 200_000 - 3 * 0xff00, 0]);
+    assert!(blocks.iter().all(|(size, _)| *size <= 0x10000));
+
+    let mut decompressed = Vec::new();
+    MultiGzDecoder::new(&compressed[..]).read_to_end(&mut decompressed).unwrap();
+    assert_eq!(decompressed, content);
+
+    // nothing written: only the end of file marker
+    assert_eq!(BgzfWriter::new(Vec::new()).finish().unwrap(), BGZF_EOF);
+}
+
+#[test]
+fn bam_bins() {
+    // SAM specification 5.3: 16 kb bins at level 5 down to the one 512 Mb bin
+    assert_eq!(reg2bin(0, 1), 4681);
+    assert_eq!(reg2bin(0, 1 << 14), 4681);
+    assert_eq!(reg2bin(1 << 14, (1 << 14) + 1), 4682);
+    assert_eq!(reg2bin(0, (1 << 14) + 1), 585);
+    assert_eq!(reg2bin(0, (1 << 17) + 1), 73);
+    assert_eq!(reg2bin(0, (1 << 20) + 1), 9);
+    assert_eq!(reg2bin(0, (1 << 23) + 1), 1);
+    assert_eq!(reg2bin(0, (1 << 26) + 1), 0);
+}
+
+#[test]
+fn sam_and_bam_round_trip() {
+    let hmm = Arc::new(VDJmodeler::build_models("testData/IMGT+C.fa".to_string()).expect("the bundled database builds a model"));
+    let trb = hmm.names.iter().position(|name| *name == SequenceModel::TRB).expect("TRB is modelled");
+    let germline: Vec<u8> = hmm.consensus(trb)[20..170].iter().map(|base| if *base == b'N' { b'A' } else { *base }).collect();
+    let mut reverse = Vec::new();
+    reverse_complement(&germline, &mut reverse);
+    let qual: Vec<u8> = (0..germline.len()).map(|i| b'!' + (i % 40) as u8).collect();
+    let records = [
+        Seqrec::with_qual(b"forward CB:Z:AACC UB:Z:GGTT", &germline, &qual),
+        Seqrec::with_qual(b"reverse CB:Z:TTAA", &reverse, &qual),
+        Seqrec::new(b"fasta", &germline),
+    ];
+
+    let mut scanner = Scanner::from_shared(hmm.clone());
+    scanner.both_strands = true;
+    let hits: Vec<_> = records.iter().map(|record| scanner.best_hit(record.seq()).expect("a hit")).collect();
+    assert_eq!(hits.iter().map(|hit| hit.strand).collect::<Vec<_>>(), [Strand::Forward, Strand::Reverse, Strand::Forward]);
+
+    let filter = SamFilter { keep_tags: vec![*b"CB", *b"UB", *b"ZL", *b"ZO", *b"ZT"], ..SamFilter::default() };
+    let mut read_back = Vec::new();
+    for name in ["hits.sam", "hits.bam"] {
+        let path = temp_file(name).to_string_lossy().to_string();
+        let mut writer = SamWriter::create(&path, SamFormat::from_path(&path).unwrap(), &hmm, "hmm_mapper scan").unwrap();
+        for (record, hit) in records.iter().zip(&hits) {
+            writer.write_hit(record, hit).unwrap();
+        }
+        writer.finish().unwrap();
+        let back: Vec<Seqrec> = open_records(&path, filter.clone()).unwrap().collect::<Result<_>>().unwrap();
+        // the reads come back in sequencing orientation with the kept tags in the header comment
+        for ((record, hit), back) in records.iter().zip(&hits).zip(&back) {
+            assert_eq!((back.name(), back.seq(), back.qual()), (record.name(), record.seq(), record.qual()), "{name}");
+            let comment = String::from_utf8_lossy(back.comment()).to_string();
+            let expected = format!("{} ZL:Z:TRB-VDJ ZO:f:{} ZT:A:{}", String::from_utf8_lossy(record.comment()), hit.log_odds as f32, hit.strand);
+            assert_eq!(comment.trim_start(), expected.trim_start(), "{name}");
+        }
+        assert_eq!(back.len(), records.len());
+        read_back.push(back.into_iter().map(|record| record.id).collect::<Vec<_>>());
+    }
+    assert_eq!(read_back[0], read_back[1]);
+
+    // the BAM header lists every locus with its length, the records carry the bin of their interval
+    let mut bam = Vec::new();
+    MultiGzDecoder::new(fs::File::open(temp_file("hits.bam")).unwrap()).read_to_end(&mut bam).unwrap();
+    let i32_at = |at: usize| i32::from_le_bytes(bam[at..at + 4].try_into().unwrap()) as usize;
+    assert_eq!(&bam[..4], b"BAM\x01");
+    let mut at = 8 + i32_at(4);
+    assert_eq!(i32_at(at), hmm.num_loci());
+    at += 4;
+    for i in 0..hmm.num_loci() {
+        let l_name = i32_at(at);
+        assert_eq!(&bam[at + 4..at + 3 + l_name], hmm.label(i).as_bytes());
+        assert_eq!(i32_at(at + 4 + l_name), hmm.locus_len(i));
+        at += 8 + l_name;
+    }
+    for hit in &hits {
+        let size = i32_at(at);
+        let record = &bam[at + 4..at + 4 + size];
+        assert_eq!(i32::from_le_bytes(record[0..4].try_into().unwrap()) as usize, trb);
+        assert_eq!(i32::from_le_bytes(record[4..8].try_into().unwrap()) as usize, hit.model_start);
+        assert_eq!(u16::from_le_bytes([record[10], record[11]]), reg2bin(hit.model_start, hit.model_end));
+        at += 4 + size;
+    }
+    assert_eq!(at, bam.len());
+}
+
+/// an uncompressed BAM stream with one reference and these records
+fn bam_stream(records: &[Vec<u8>]) -> Vec<u8> {
+    let text = b"@SQ\tSN:chr14\tLN:1000\n";
+    let mut ret = b"BAM\x01".to_vec();
+    ret.extend_from_slice(&(text.len() as i32).to_le_bytes());
+    ret.extend_from_slice(text);
+    ret.extend_from_slice(&1_i32.to_le_bytes());
+    ret.extend_from_slice(&6_i32.to_le_bytes());
+    ret.extend_from_slice(b"chr14\0");
+    ret.extend_from_slice(&1000_i32.to_le_bytes());
+    for record in records {
+        ret.extend_from_slice(&(record.len() as i32).to_le_bytes());
+        ret.extend_from_slice(record);
+    }
+    ret
+}
+
+/// an unmapped BAM record of the read ACGTN without qualities
+fn bam_record(name: &[u8], flag: u16, aux: &[u8]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    ret.extend_from_slice(&(-1_i32).to_le_bytes());
+    ret.extend_from_slice(&(-1_i32).to_le_bytes());
+    ret.push(name.len() as u8 + 1);
+    ret.push(0);
+    ret.extend_from_slice(&4680_u16.to_le_bytes());
+    ret.extend_from_slice(&0_u16.to_le_bytes());
+    ret.extend_from_slice(&flag.to_le_bytes());
+    ret.extend_from_slice(&5_i32.to_le_bytes());
+    ret.extend_from_slice(&(-1_i32).to_le_bytes());
+    ret.extend_from_slice(&(-1_i32).to_le_bytes());
+    ret.extend_from_slice(&0_i32.to_le_bytes());
+    ret.extend_from_slice(name);
+    ret.push(0);
+    ret.extend_from_slice(&[0x12, 0x48, 0xf0]);
+    ret.extend_from_slice(&[0xff; 5]);
+    ret.extend_from_slice(aux);
+    ret
+}
+
+fn bam_reads(stream: &[u8], keep_tags: &[&[u8; 2]]) -> Vec<Result<Seqrec>> {
+    let filter = SamFilter { keep_tags: keep_tags.iter().map(|tag| **tag).collect(), ..SamFilter::default() };
+    BamRecords::new(stream, filter, "test.bam").expect("the header is complete").collect()
+}
+
+#[test]
+fn bam_tags() {
+    let mut aux = Vec::new();
+    aux.extend_from_slice(b"XAAx");
+    aux.extend_from_slice(b"Xcc\xfe");
+    aux.extend_from_slice(b"XCC\xfe");
+    aux.extend_from_slice(b"Xss");
+    aux.extend_from_slice(&(-300_i16).to_le_bytes());
+    aux.extend_from_slice(b"XSS");
+    aux.extend_from_slice(&60000_u16.to_le_bytes());
+    aux.extend_from_slice(b"Xii");
+    aux.extend_from_slice(&(-70000_i32).to_le_bytes());
+    aux.extend_from_slice(b"XII");
+    aux.extend_from_slice(&3_000_000_000_u32.to_le_bytes());
+    aux.extend_from_slice(b"Xff");
+    aux.extend_from_slice(&0.5_f32.to_le_bytes());
+    // arrays are skipped
+    aux.extend_from_slice(b"XBBs");
+    aux.extend_from_slice(&2_u32.to_le_bytes());
+    aux.extend_from_slice(&[1, 0, 2, 0]);
+    aux.extend_from_slice(b"CBZAACC\0");
+    aux.extend_from_slice(b"XHH1AE901\0");
+    let keep: [&[u8; 2]; 11] = [b"XA", b"Xc", b"XC", b"Xs", b"XS", b"Xi", b"XI", b"Xf", b"XB", b"CB", b"XH"];
+    let reads = bam_reads(&bam_stream(&[bam_record(b"read1", 4, &aux)]), &keep);
+    assert_eq!(reads.len(), 1);
+    let read = reads[0].as_ref().expect("a complete record");
+    assert_eq!((read.name(), read.seq(), read.qual()), (&b"read1"[..], &b"ACGTN"[..], None));
+    assert_eq!(
+        String::from_utf8_lossy(read.comment()),
+        "XA:A:x Xc:i:-2 XC:i:254 Xs:i:-300 XS:i:60000 Xi:i:-70000 XI:i:3000000000 Xf:f:0.5 CB:Z:AACC XH:H:1AE901"
+    );
+
+    // only the kept tags, secondary and supplementary alignments are skipped
+    let stream = bam_stream(&[bam_record(b"read1", 4, &aux), bam_record(b"secondary", 0x100, b""), bam_record(b"read2", 4, b"")]);
+    let reads: Vec<Seqrec> = bam_reads(&stream, &[b"CB"]).into_iter().collect::<Result<_>>().unwrap();
+    assert_eq!(reads.iter().map(|read| read.id.clone()).collect::<Vec<_>>(), [b"read1 CB:Z:AACC".to_vec(), b"read2".to_vec()]);
+}
+
+#[test]
+fn truncated_bam() {
+    let complete = bam_stream(&[bam_record(b"read1", 4, b"CBZAACC\0"), bam_record(b"read2", 4, b"")]);
+
+    // the header ends early
+    let filter = SamFilter::default();
+    assert!(BamRecords::new(&complete[..20], filter.clone(), "test.bam").is_err());
+    assert!(BamRecords::new(&b"BAM\x02"[..], filter, "test.bam").is_err());
+
+    // a record ends early: the reads before it and one error
+    let cut = complete.len() - 10;
+    let reads = bam_reads(&complete[..cut], &[b"CB"]);
+    assert_eq!(reads.len(), 2);
+    assert_eq!(reads[0].as_ref().expect("the first record is complete").name(), b"read1");
+    assert!(reads[1].is_err());
+
+    // the size of the next record ends early
+    let reads = bam_reads(&complete[..complete.len() - bam_record(b"read2", 4, b"").len() - 2], &[b"CB"]);
+    assert_eq!(reads.len(), 2);
+    assert!(reads[1].is_err());
+
+    // a string tag without its NUL and a record too short for its sequence
+    for record in [bam_record(b"read1", 4, b"CBZAACC"), bam_record(b"read1", 4, b"")[..40].to_vec()] {
+        let reads = bam_reads(&bam_stream(&[record, bam_record(b"read2", 4, b"")]), &[b"CB"]);
+        assert_eq!(reads.len(), 1, "reading stops after the first error");
+        assert!(reads[0].is_err());
+    }
+}
@@ -0,0 +1,47 @@
+// tests/pipeline.rs
+//
+// The threaded reader - scanner - writer pipeline: input order and the bounded number of batches in flight.
+
+use hmm_mapper::pipeline::{Pipeline, Seqrec};
+use hmm_mapper::VDJmodeler::VDJmodeler;
+use hmm_mapper::Scanner;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[test]
+fn results_keep_the_input_order() {
+    let hmm = VDJmodeler::build_models("testData/IMGT+C.fa".to_string()).expect("the bundled database builds a model");
+    let scanner = Scanner::new(hmm);
+
+    // reads of very different lengths, so the scanner threads finish their batches out of order
+    let mut state: u64 = 11;
+    let reads: Vec<Seqrec> = (0..60).map(|i| {
+        let len = if i % 5 == 0 { 400 } else { 40 };
+        let seq: Vec<u8> = (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            b"ACGT"[(state % 4) as usize]
+        }).collect();
+        Seqrec::new(format!("read{i}").as_bytes(), &seq)
+    }).collect();
+
+    let pipeline = Pipeline { threads: 3, batch_size: 2, queue_depth: 1 };
+    let read = AtomicUsize::new(0);
+    let records = reads.clone().into_iter().inspect(|_| {
+        read.fetch_add(1, Ordering::Relaxed);
+    });
+    let mut written = Vec::new();
+    let stats = pipeline.run(records, &scanner, |record, _| {
+        // the batch of this record still holds its permit
+        let batches_written = written.len() / pipeline.batch_size;
+        let ahead = read.load(Ordering::Relaxed);
+        assert!(ahead <= (batches_written + pipeline.in_flight()) * pipeline.batch_size, "{ahead} reads read, {} written", written.len());
+        written.push(record.name().to_vec());
+        Ok(())
+    }).expect("the pipeline runs");
+
+    let names: Vec<Vec<u8>> = reads.iter().map(|record| record.name().to_vec()).collect();
+    assert_eq!(written, names);
+    assert_eq!((stats.reads, stats.batches), (60, 30));
+}
@@ -0,0 +1,486 @@
+// tests/regression.rs
+//
+// The model built from the bundled IMGT database and the DP algorithms on small hand computed HMMs.
+
+use hmm_mapper::banded::{self, AlignWorkspace, GapModel};
+use hmm_mapper::decoy::DecoyKind;
+use hmm_mapper::priors::{henikoff_weights, EmissionConfig, EmissionPrior};
+use hmm_mapper::germline::{read_germline_database, GermlineRecord};
+use hmm_mapper::HMM::{log_prob_to_f64, HMMState, HMM, DpWorkspace};
+use hmm_mapper::VDJmodeler::{Chain, HMMmodel, PlacedGermline, SequenceModel, VDJmodeler};
+use hmm_mapper::Scanner;
+use hmm_mapper::scan_result::Strand;
+use hmm_mapper::scanner::reverse_complement;
+
+use std::sync::{Arc, OnceLock};
+
+const DATABASE: &str = "testData/IMGT+C.fa";
+
+fn records() -> &'static [GermlineRecord] {
+    static RECORDS: OnceLock<Vec<GermlineRecord>> = OnceLock::new();
+    RECORDS.get_or_init(|| read_germline_database(DATABASE).expect("the bundled database can be read"))
+}
+
+fn hmm() -> Arc<HMM> {
+    static HMM: OnceLock<Arc<HMM>> = OnceLock::new();
+    HMM.get_or_init(|| Arc::new(VDJmodeler::build_models(DATABASE.to_string()).expect("the bundled database builds a model"))).clone()
+}
+
+/// the germlines of every modelled locus with the locus column they belong to
+fn placed_germlines() -> Vec<(usize, PlacedGermline)> {
+    let models = VDJmodeler::collect_models("", records()).expect("the models can be collected");
+    let hmm = hmm();
+    models.into_iter().flat_map(|model| {
+        let column = hmm.names.iter().position(|name| *name == model.name).expect("every model is a column of the HMM");
+        model.germlines.into_iter().map(move |germline| (column, germline))
+    }).collect()
+}
+
+/// the ungapped upper case sequence - None if it contains IUPAC codes
+fn ungapped(sequence: &[u8]) -> Option<Vec<u8>> {
+    let ret: Vec<u8> = sequence.iter().filter(|c| **c != b'.').map(|c| c.to_ascii_uppercase()).collect();
+    ret.iter().all(|c| b"ACGT".contains(c)).then_some(ret)
+}
+
+fn assert_close(a: f64, b: f64) {
+    let tolerance = if cfg!(feature = "f32") { 1e-4 } else { 1e-9 };
+    assert!((a - b).abs() <= tolerance * a.abs().max(1.0), "{a} != {b}");
+}
+
+#[test]
+fn modelled_loci() {
+    let hmm = hmm();
+    let labels: Vec<String> = (0..hmm.num_loci()).map(|i| hmm.label(i)).collect();
+    assert_eq!(labels, ["IGH-VDJ", "IGL-VDJ", "IGK-VDJ", "TRA-VDJ", "TRB-VDJ", "TRG-VDJ", "TRD-VDJ"]);
+    assert!(hmm.species.iter().all(String::is_empty));
+}
+
+#[test]
+fn species_is_picked_by_its_best_locus() {
+    let hits = vec![
+        ("human:IGH-VDJ".to_string(), 0.9),
+        ("mouse:IGH-VDJ".to_string(), 0.5),
+        ("mouse:IGK-VDJ".to_string(), 0.5),
+    ];
+    assert_eq!(HMM::most_likely_species(&hits).as_deref(), Some("human"));
+    assert_eq!(HMM::most_likely_species(&[("IGH-VDJ".to_string(), 1.0)]), None);
+}
+
+#[test]
+fn restricted_species_keep_normalised_transitions() {
+    let databases = [("human".to_string(), DATABASE.to_string()), ("mouse".to_string(), DATABASE.to_string())];
+    let mut hmm = VDJmodeler::build_species_models_with(&databases, &EmissionConfig::default()).expect("the bundled database builds a model");
+    hmm.allow_locus_switches(0.001);
+    assert!(!hmm.restrict_to_species("rat"));
+    assert_eq!(hmm.num_loci(), 14);
+    assert!(hmm.restrict_to_species("mouse"));
+    assert_eq!(hmm.num_loci(), 7);
+    assert!(hmm.species.iter().all(|species| species == "mouse"));
+    for row in &hmm.transition_matrix {
+        assert_eq!(row.len(), 7);
+        assert_close(row.iter().sum(), 1.0);
+    }
+}
+
+#[test]
+fn model_lengths() {
+    let models = VDJmodeler::collect_models("", records()).expect("the models can be collected");
+    let lengths: Vec<(SequenceModel, usize)> = models.iter().map(|model| (model.name.clone(), model.collector.len())).collect();
+    assert_eq!(lengths, [
+        (SequenceModel::IGH, 418),
+        (SequenceModel::IGL, 389),
+        (SequenceModel::IGK, 385),
+        (SequenceModel::TRA, 406),
+        (SequenceModel::TRB, 393),
+        (SequenceModel::TRG, 389),
+        (SequenceModel::TRD, 402),
+    ]);
+    for model in &models {
+        // V, D and J are placed one after the other - every model is as long as its longest V, D and J together
+        let longest = |segment: Chain| records().iter()
+            .filter(|r| r.locus == model.name && r.segment == segment)
+            .map(|r| r.sequence.len())
+            .max()
+            .unwrap_or(0);
+        assert_eq!(model.collector.len(), longest(Chain::V) + longest(Chain::D) + longest(Chain::J), "{}", model.name.name());
+    }
+    assert_eq!(hmm().states.len(), 418);
+    // every locus keeps its own profile length in the HMM
+    for (locus, (_, len)) in lengths.iter().enumerate() {
+        assert_eq!(hmm().locus_len(locus), *len);
+    }
+}
+
+#[test]
+fn germlines_score_their_own_locus_highest() {
+    let hmm = hmm();
+    let mut checked = 0;
+    for (column, germline) in placed_germlines() {
+        if germline.segment == Chain::D || ungapped(&germline.sequence).is_none() {
+            continue
+        }
+        // the emission log probabilities of the germline at the position it was added to the model
+        let mut scores = vec![0.0; hmm.num_loci()];
+        for (offset, base) in germline.sequence.iter().enumerate() {
+            if let Some(base) = HMM::char2pos(*base) {
+                for (score, emission) in scores.iter_mut().zip(hmm.log_emissions(germline.start + offset, base)) {
+                    *score += log_prob_to_f64(*emission);
+                }
+            }
+        }
+        // loci whose profile ends before the germline only emit the background there
+        let best = (0..scores.len())
+            .filter(|locus| hmm.fits_locus(*locus, germline.start, germline.sequence.len()))
+            .max_by(|a, b| scores[*a].total_cmp(&scores[*b]))
+            .expect("the germline fits its own locus");
+        assert_eq!(best, column, "{} scores {:?}", germline.gene, scores);
+        checked += 1;
+    }
+    assert!(checked > 900, "only {checked} V and J germlines checked");
+}
+
+/// the ungapped V and J germlines the scanner places in their own locus and all of them
+fn placed_in_own_locus(hmm: Arc<HMM>, gaps: Option<GapModel>) -> (usize, usize) {
+    let mut scanner = Scanner::from_shared(hmm.clone());
+    scanner.gaps = gaps;
+    let (mut right, mut total) = (0, 0);
+    for (column, germline) in placed_germlines() {
+        if germline.segment == Chain::D {
+            continue
+        }
+        let sequence = match ungapped(&germline.sequence) {
+            Some(sequence) => sequence,
+            None => continue,
+        };
+        total += 1;
+        if scanner.best_hit(&sequence).is_some_and(|hit| hit.locus == hmm.names[column]) {
+            right += 1;
+        }
+    }
+    (right, total)
+}
+
+#[test]
+fn scanner_places_germlines_in_their_locus() {
+    // the gapped alignment follows the germlines through the IMGT gaps of the model columns
+    let (right, total) = placed_in_own_locus(hmm(), Some(GapModel::default()));
+    assert!(right * 100 >= total * 90, "{right} of {total} germlines placed in their locus with indels");
+
+    // ungapped, the germlines leave the gapped model columns after the first IMGT gap -
+    // only the best scoring stretch of the read counts, so that does not push them into another locus
+    let (right, total) = placed_in_own_locus(hmm(), None);
+    assert!(right * 100 >= total * 75, "{right} of {total} germlines placed in their locus");
+
+    // the observed frequencies of every locus on its own
+    let config = EmissionConfig { prior: EmissionPrior::Floor, sequence_weights: true };
+    let floor = VDJmodeler::build_species_models_with(&[(String::new(), DATABASE.to_string())], &config).expect("the bundled database builds a model");
+    let (right, total) = placed_in_own_locus(Arc::new(floor), None);
+    assert!(right * 100 >= total * 75, "{right} of {total} germlines placed in their locus without a prior");
+}
+
+#[test]
+fn shuffled_sequences_do_not_pass() {
+    let hmm = hmm();
+    let mut filtered = Scanner::from_shared(hmm.clone());
+    // k-mers have to fit into a u64 code, longer ones are rejected instead of silently shortened
+    assert!(filtered.enable_prefilter(3).is_err() && filtered.enable_prefilter(32).is_err());
+    filtered.enable_prefilter(16).expect("16 is a supported k-mer length");
+    let mut unfiltered = Scanner::from_shared(hmm);
+    let mut shuffled = Vec::new();
+    let mut checked = 0;
+    for (_, germline) in placed_germlines() {
+        if germline.segment != Chain::V {
+            continue
+        }
+        let sequence = match ungapped(&germline.sequence) {
+            Some(sequence) => sequence,
+            None => continue,
+        };
+        DecoyKind::Shuffle.decoy(&sequence, &mut shuffled);
+        assert!(filtered.best_hit(&sequence).is_some(), "{} does not pass the prefilter", germline.gene);
+        assert!(filtered.best_hit(&shuffled).is_none(), "shuffled {} passes the prefilter", germline.gene);
+        // without the prefilter a shuffled read may be placed, but never as well as the germline itself
+        if let Some(decoy) = unfiltered.best_hit(&shuffled) {
+            let original = unfiltered.best_hit(&sequence).expect("a germline placed with its shuffle");
+            assert!(decoy.log_odds < original.log_odds, "shuffled {} scores {} >= {}", germline.gene, decoy.log_odds, original.log_odds);
+        }
+        checked += 1;
+    }
+    assert!(checked > 800, "only {checked} V germlines checked");
+}
+
+/// Two loci over two model positions with these emissions (A, G, C, T per locus) and transitions.
+fn small_hmm(emissions: [[[f64; 4]; 2]; 2], transitions: [[f64; 2]; 2]) -> HMM {
+    let models = vec![HMMmodel::new(SequenceModel::IGH, "", 2), HMMmodel::new(SequenceModel::IGK, "", 2)];
+    let mut hmm = HMM::from_sequence_models(models);
+    hmm.states = emissions.iter().map(|per_locus| HMMState {
+        match_emission: per_locus.iter().map(|p| vec![p[0], p[1], p[2], p[3], 0.0]).collect(),
+    }).collect();
+    hmm.transition_matrix = transitions.iter().map(|row| row.to_vec()).collect();
+    hmm.build_tables();
+    hmm
+}
+
+#[test]
+fn hand_computed_two_locus_hmm() {
+    let hmm = small_hmm(
+        [
+            [[0.5, 0.2, 0.2, 0.1], [0.1, 0.3, 0.3, 0.3]],
+            [[0.4, 0.3, 0.2, 0.1], [0.1, 0.6, 0.2, 0.1]],
+        ],
+        [[0.9, 0.1], [0.2, 0.8]],
+    );
+    // "AG": A from locus 0 (0.5) or 1 (0.1), then G from locus 0 (0.3) or 1 (0.6)
+    // Viterbi:  locus 0 max(0.5 * 0.9, 0.1 * 0.2) * 0.3 = 0.135   locus 1 max(0.5 * 0.1, 0.1 * 0.8) * 0.6 = 0.048
+    // forward:  locus 0 (0.45 + 0.02) * 0.3 = 0.141              locus 1 (0.05 + 0.08) * 0.6 = 0.078
+    // backward: beta(0) = 0.9 * 0.3 + 0.1 * 0.6 = 0.33, beta(1) = 0.2 * 0.3 + 0.8 * 0.6 = 0.54
+    //           0.5 * 0.33 + 0.1 * 0.54 = 0.219 = 0.141 + 0.078
+    let mut ws = DpWorkspace::default();
+    assert_eq!(hmm.locus_scores(b"AG", 0, &mut ws), Some(2));
+    assert_close(log_prob_to_f64(ws.prev[0]), 0.135_f64.ln());
+    assert_close(log_prob_to_f64(ws.prev[1]), 0.048_f64.ln());
+
+    let forward = hmm.forward_log_probs(b"AG", 0).expect("valid bases");
+    assert_close(forward[0], 0.141_f64.ln());
+    assert_close(forward[1], 0.078_f64.ln());
+    assert_close(hmm.forward_log_prob(b"AG", 0).expect("valid bases"), 0.219_f64.ln());
+    assert_close(hmm.backward_log_prob(b"AG", 0).expect("valid bases"), 0.219_f64.ln());
+
+    // one base: all three are the emission
+    assert_close(hmm.forward_log_prob(b"G", 1).expect("valid bases"), (0.3_f64 + 0.6).ln());
+    assert_close(hmm.backward_log_prob(b"G", 1).expect("valid bases"), (0.3_f64 + 0.6).ln());
+    // the sequence is truncated at the model end
+    assert_close(hmm.forward_log_prob(b"AGT", 0).expect("valid bases"), 0.219_f64.ln());
+    assert_eq!(hmm.forward_log_prob(b"AN", 0), None);
+    assert_eq!(hmm.backward_log_prob(b"AN", 0), None);
+}
+
+#[test]
+fn without_locus_switches_all_algorithms_agree() {
+    // no switching: every locus has exactly one path, so the best path is the only path
+    let hmm = small_hmm(
+        [
+            [[0.7, 0.1, 0.1, 0.1], [0.25, 0.25, 0.25, 0.25]],
+            [[0.1, 0.1, 0.1, 0.7], [0.4, 0.1, 0.4, 0.1]],
+        ],
+        [[1.0, 0.0], [0.0, 1.0]],
+    );
+    let mut ws = DpWorkspace::default();
+    hmm.locus_scores(b"AT", 0, &mut ws);
+    let forward = hmm.forward_log_probs(b"AT", 0).expect("valid bases");
+    for (viterbi, forward) in ws.prev.iter().zip(&forward) {
+        assert_close(log_prob_to_f64(*viterbi), *forward);
+    }
+    assert_close(forward[0], (0.7_f64 * 0.7).ln());
+    assert_close(forward[1], (0.25_f64 * 0.1).ln());
+    assert_close(hmm.backward_log_prob(b"AT", 0).expect("valid bases"), (0.7_f64 * 0.7 + 0.25 * 0.1).ln());
+}
+
+#[test]
+fn forward_and_backward_agree_on_the_imgt_model() {
+    let hmm = hmm();
+    let mut ws = DpWorkspace::default();
+    let mut checked = 0;
+    for (_, germline) in placed_germlines().into_iter().filter(|(_, g)| g.segment == Chain::J).take(20) {
+        let sequence = match ungapped(&germline.sequence) {
+            Some(sequence) => sequence,
+            None => continue,
+        };
+        let start = germline.start;
+        let forward = hmm.forward_log_prob(&sequence, start).expect("valid bases");
+        let backward = hmm.backward_log_prob(&sequence, start).expect("valid bases");
+        assert!((forward - backward).abs() <= 1e-6 * forward.abs(), "{}: forward {forward} backward {backward}", germline.gene);
+        // the best path can not be more likely than all paths together
+        hmm.locus_scores(&sequence, start, &mut ws);
+        let viterbi = ws.prev.iter().map(|p| log_prob_to_f64(*p)).fold(f64::NEG_INFINITY, f64::max);
+        assert!(viterbi <= forward + 1e-6 * forward.abs(), "{}: viterbi {viterbi} forward {forward}", germline.gene);
+        checked += 1;
+    }
+    assert!(checked > 10);
+}
+
+#[test]
+fn viterbi_path_reports_locus_switches() {
+    // A is likely in locus 0, G in locus 1: switching (0.8 * 0.2) beats staying in locus 1 (0.1 * 0.8)
+    let emissions = [[0.8, 0.1, 0.05, 0.05], [0.1, 0.8, 0.05, 0.05]];
+    let switching = small_hmm([[emissions[0], emissions[1]], [emissions[0], emissions[1]]], [[0.8, 0.2], [0.2, 0.8]]);
+    assert_eq!(switching.viterbi_path(b"AG", 0, 1), Some(vec![0, 1]));
+    assert_eq!(switching.viterbi_path(b"AG", 0, 0), Some(vec![0, 0]));
+    assert_eq!(switching.viterbi_path(b"GG", 0, 1), Some(vec![1, 1]));
+    assert_eq!(switching.viterbi_path(b"AN", 0, 1), None);
+
+    // the traced path has the score locus_scores reports
+    let mut ws = DpWorkspace::default();
+    switching.locus_scores(b"AG", 0, &mut ws);
+    assert_close(log_prob_to_f64(ws.prev[1]), (0.8_f64 * 0.2 * 0.8).ln());
+
+    // the default model never switches
+    let mut fixed = small_hmm([[emissions[0], emissions[1]], [emissions[0], emissions[1]]], [[0.8, 0.2], [0.2, 0.8]]);
+    fixed.allow_locus_switches(0.0);
+    assert_eq!(fixed.viterbi_path(b"AG", 0, 1), Some(vec![1, 1]));
+    fixed.locus_scores(b"AG", 0, &mut ws);
+    assert_close(log_prob_to_f64(ws.prev[1]), (0.1_f64 * 0.8).ln());
+}
+
+#[test]
+fn henikoff_weights_and_priors() {
+    // two identical alleles and one distinct gene: the alleles share the weight of one sequence
+    let weights = henikoff_weights(&[(0, b"ACGT"), (0, b"ACGT"), (0, b"TGCA")]);
+    assert_close(weights[0], weights[1]);
+    assert_close(weights[0] + weights[1], weights[2]);
+    assert_close(weights.iter().sum(), 3.0);
+
+    // one observed A with one pseudocount spread like a background of half A: (1 + 0.5) / 2 and 0.5 / 6 for the rest
+    let background = [0.5, 0.5 / 3.0, 0.5 / 3.0, 0.5 / 3.0];
+    let estimate = EmissionPrior::Background { strength: 1.0 }.estimate(&[1.0, 0.0, 0.0, 0.0, 0.0], &background);
+    assert_close(estimate[0], 0.75);
+    assert_close(estimate[1], 0.5 / 6.0);
+    assert_close(estimate[4], 0.0);
+    // without counts the prior is the background
+    let estimate = EmissionPrior::Background { strength: 1.0 }.estimate(&[0.0; 5], &background);
+    assert_close(estimate[0], 0.5);
+    // every locus is normalized on its own: 1 A of 1 is as sure as 100 of 100
+    let few = EmissionPrior::Floor.estimate(&[1.0, 0.0, 0.0, 0.0, 0.0], &[0.25; 4]);
+    let many = EmissionPrior::Floor.estimate(&[100.0, 0.0, 0.0, 0.0, 0.0], &[0.25; 4]);
+    assert_close(few[0], many[0]);
+    assert!(few[0] > 0.999);
+}
+
+#[test]
+fn start_candidates_keep_their_locus() {
+    let hmm = hmm();
+    let (column, germline) = placed_germlines().into_iter()
+        .find(|(_, germline)| germline.gene == "TRBV1*01")
+        .expect("TRBV1*01 is part of the database");
+    let sequence = ungapped(&germline.sequence).expect("TRBV1*01 has no IUPAC codes");
+
+    let candidates = hmm.start_candidates(&sequence, 3);
+    for locus in 0..hmm.num_loci() {
+        let own: Vec<_> = candidates.iter().filter(|candidate| candidate.locus == locus).collect();
+        assert!(!own.is_empty() && own.len() <= 3, "{} candidates for {}", own.len(), hmm.label(locus));
+        // best first and scored in their own locus
+        assert!(own.windows(2).all(|pair| pair[0].excess() >= pair[1].excess()));
+        for candidate in &own {
+            let scores = hmm.start_score(&sequence, candidate.read_start, candidate.start).expect("the candidate overlaps the model");
+            assert_close(candidate.score, scores[locus]);
+        }
+        assert_eq!(hmm.find_probable_start(&sequence)[locus], (own[0].start, own[0].score));
+    }
+    // a V germline starts the model
+    assert!(candidates.iter().any(|candidate| candidate.locus == column && candidate.diagonal() == 0));
+
+    // every locus reports a start out of its own candidates
+    let result = hmm.forward_algorithm(&sequence).expect("the germline has start candidates");
+    for (key, _) in result {
+        let (label, diagonal) = key.split_once('|').expect("the keys are locus|start");
+        let locus = (0..hmm.num_loci()).find(|locus| hmm.label(*locus) == label).expect("a known locus");
+        let diagonal: i64 = diagonal.parse().expect("a model position");
+        assert!(candidates.iter().any(|candidate| candidate.locus == locus && candidate.diagonal() == diagonal), "{key}");
+    }
+}
+
+#[test]
+fn long_reads_overhang_the_model() {
+    let hmm = hmm();
+    // the TRB consensus follows the model columns without indels
+    let column = hmm.names.iter().position(|name| *name == SequenceModel::TRB).expect("TRB is modelled");
+    let sequence: Vec<u8> = hmm.consensus(column).into_iter()
+        .map(|base| if base == b'N' { b'A' } else { base })
+        .collect();
+    // random flanks make the read longer than the whole model
+    let mut state: u64 = 42;
+    let mut random = |len: usize| -> Vec<u8> {
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            b"ACGT"[(state % 4) as usize]
+        }).collect()
+    };
+    let read = [random(1000), sequence.clone(), random(1000)].concat();
+    assert!(read.len() > hmm.states.len());
+
+    let candidates = hmm.start_candidates(&read, 3);
+    assert!(candidates.iter().any(|candidate| candidate.locus == column && candidate.diagonal() == -1000), "{candidates:?}");
+
+    for gaps in [None, Some(GapModel::default())] {
+        let mut scanner = Scanner::from_shared(hmm.clone());
+        scanner.gaps = gaps;
+        let hit = scanner.best_hit(&read).expect("the read is placed");
+        assert_eq!(hit.locus, hmm.names[column]);
+        // the reported interval is the part of the read inside the locus
+        assert_eq!((hit.read_start, hit.read_end), (1000, 1000 + sequence.len()), "{}", hit.tags());
+        assert_eq!((hit.model_start, hit.model_end), (0, hmm.locus_len(column)), "{}", hit.tags());
+        assert!(hit.log_odds > 0.0, "{}", hit.tags());
+    }
+}
+
+#[test]
+fn long_read_segments() {
+    let hmm = hmm();
+    let consensus = |locus: SequenceModel| -> (usize, Vec<u8>) {
+        let column = hmm.names.iter().position(|name| *name == locus).expect("the locus is modelled");
+        let sequence = hmm.consensus(column).into_iter()
+            .map(|base| if base == b'N' { b'A' } else { base })
+            .collect();
+        (column, sequence)
+    };
+    let (trb, trb_sequence) = consensus(SequenceModel::TRB);
+    let (igk, igk_sequence) = consensus(SequenceModel::IGK);
+
+    // a homopolymer insertion costs less than inserting any other base
+    let middle = (1..trb_sequence.len()).find(|i| *i > 150 && trb_sequence[i - 1] != trb_sequence[*i]).expect("a base change");
+    let inserted = |base: u8| [&trb_sequence[..middle], &[base], &trb_sequence[middle..]].concat();
+    let other = *b"ACGT".iter().find(|base| **base != trb_sequence[middle - 1] && **base != trb_sequence[middle]).expect("a third base");
+    let gaps = GapModel::long_reads();
+    let mut ws = AlignWorkspace::default();
+    let homopolymer = banded::align_full(&hmm, trb, &inserted(trb_sequence[middle - 1]), &gaps, &mut ws).expect("aligned");
+    let substitution = banded::align_full(&hmm, trb, &inserted(other), &gaps, &mut ws).expect("aligned");
+    assert!(homopolymer.score > substitution.score, "{} {}", homopolymer.cigar, substitution.cigar);
+    // an N is aligned like any other base, with the background probability
+    let with_n = banded::align_full(&hmm, trb, &inserted(b'N'), &gaps, &mut ws).expect("a read with an N is aligned");
+    assert_eq!((with_n.read_start, with_n.read_end, with_n.model_end), (0, trb_sequence.len() + 1, trb_sequence.len()), "{}", with_n.cigar);
+    let banded = banded::align_banded(&hmm, trb, &inserted(b'N'), 0, 16, &gaps, None, &mut ws).expect("a read with an N is aligned");
+    assert_eq!(banded.cigar, with_n.cigar);
+
+    // a concatemer of TRB and the reverse complement of IGK between random bases
+    let mut state: u64 = 7;
+    let mut random = |len: usize| -> Vec<u8> {
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            b"ACGT"[(state % 4) as usize]
+        }).collect()
+    };
+    let mut igk_reverse = Vec::new();
+    reverse_complement(&igk_sequence, &mut igk_reverse);
+    let parts = [random(300), trb_sequence.clone(), random(200), igk_reverse, random(300)];
+    let read = parts.concat();
+    let trb_interval = (300, 300 + trb_sequence.len());
+    let igk_interval = (trb_interval.1 + 200, trb_interval.1 + 200 + igk_sequence.len());
+
+    let mut scanner = Scanner::from_shared(hmm.clone());
+    scanner.both_strands = true;
+    scanner.enable_long_reads(8);
+    let segments = scanner.scan_segments(&read);
+    let mut placed: Vec<(usize, Strand, usize, usize)> = segments.iter()
+        .map(|segment| (hmm.names.iter().position(|name| *name == segment.locus).expect("a modelled locus"), segment.strand, segment.read_start, segment.read_end))
+        .collect();
+    placed.sort_by_key(|segment| segment.2);
+    assert_eq!(placed.len(), 2, "{placed:?}");
+    for ((locus, strand, start, end), (expected_locus, expected_strand, interval)) in placed.iter().zip([(trb, Strand::Forward, trb_interval), (igk, Strand::Reverse, igk_interval)]) {
+        assert_eq!((*locus, *strand), (expected_locus, expected_strand));
+        // most of the inserted sequence is covered and nothing of the other one
+        let overlap = (*end).min(interval.1).saturating_sub((*start).max(interval.0));
+        assert!(overlap * 10 >= (interval.1 - interval.0) * 9, "{start}-{end} {interval:?}");
+        assert!(*start + 10 >= interval.0 && *end <= interval.1 + 10, "{start}-{end} {interval:?}");
+    }
+    // every segment is aligned in whole read coordinates
+    for segment in &segments {
+        let cigar = segment.cigar.as_ref().expect("long reads are aligned with gaps");
+        assert_eq!(cigar.read_len(), read.len(), "{}", segment.tags());
+        assert_eq!(cigar.model_len(), segment.model_end - segment.model_start, "{}", segment.tags());
+    }
+    assert!(segments[0].log_odds >= segments[1].log_odds);
+}
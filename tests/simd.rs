@@ -0,0 +1,115 @@
+// tests/simd.rs
+//
+// The vectorised DP step has to give the same scores as the scalar step.
+
+use hmm_mapper::HMM::{DpWorkspace, LogProb, HMM};
+use hmm_mapper::VDJmodeler::VDJmodeler;
+use hmm_mapper::simd::{self, Backend};
+
+/// all backends that can run on this machine
+fn backends() -> Vec<Backend> {
+    let mut ret = vec![Backend::Scalar, Backend::detect()];
+    if cfg!(target_arch = "x86_64") {
+        ret.push(Backend::Sse2);
+    }
+    ret
+}
+
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+    /// a log probability in (-10, 0]
+    fn log_prob(&mut self) -> LogProb {
+        -((self.next() % 10_000) as LogProb) / 1000.0
+    }
+}
+
+fn assert_close(a: &[LogProb], b: &[LogProb]) {
+    assert_eq!(a.len(), b.len());
+    for (x, y) in a.iter().zip(b) {
+        let tolerance: LogProb = 1e-5;
+        assert!(
+            (x == y) || (x.is_nan() && y.is_nan()) || (x - y).abs() <= tolerance * x.abs().max(1.0),
+            "{x} != {y}"
+        );
+    }
+}
+
+#[test]
+fn viterbi_step_matches_scalar_for_all_locus_counts() {
+    let mut rng = Lcg(7);
+    // cover full vectors, partial vectors and scalar tails of every width
+    for n in 1..=19 {
+        for _ in 0..20 {
+            let prev: Vec<LogProb> = (0..n).map(|_| rng.log_prob() * 10.0).collect();
+            let transitions: Vec<LogProb> = (0..n * n).map(|_| rng.log_prob()).collect();
+            let emissions: Vec<LogProb> = (0..n).map(|_| rng.log_prob()).collect();
+
+            let mut expected = vec![0.0; n];
+            simd::step_scalar(&prev, &transitions, &emissions, &mut expected);
+            for backend in backends() {
+                let mut cur = vec![0.0; n];
+                simd::viterbi_step(backend, &prev, &transitions, &emissions, &mut cur);
+                assert_close(&expected, &cur);
+            }
+        }
+    }
+}
+
+#[test]
+fn viterbi_step_ignores_nan_like_f64_max() {
+    let n = 6;
+    let mut prev = vec![-1.0; n];
+    prev[2] = LogProb::NAN;
+    let transitions = vec![-0.5; n * n];
+    let emissions = vec![-1.0; n];
+    let mut expected = vec![0.0; n];
+    simd::step_scalar(&prev, &transitions, &emissions, &mut expected);
+    assert!(expected.iter().all(|c| *c == -2.5), "{expected:?}");
+    for backend in backends() {
+        let mut cur = vec![0.0; n];
+        simd::viterbi_step(backend, &prev, &transitions, &emissions, &mut cur);
+        assert_close(&expected, &cur);
+    }
+}
+
+#[test]
+fn viterbi_step_keeps_impossible_paths() {
+    let n = 5;
+    let prev = vec![LogProb::NEG_INFINITY; n];
+    let transitions = vec![-1.0; n * n];
+    let emissions = vec![-1.0; n];
+    for backend in backends() {
+        let mut cur = vec![0.0; n];
+        simd::viterbi_step(backend, &prev, &transitions, &emissions, &mut cur);
+        assert!(cur.iter().all(|c| *c == LogProb::NEG_INFINITY), "{backend:?}: {cur:?}");
+    }
+}
+
+#[test]
+fn locus_scores_match_scalar_on_the_imgt_model() {
+    let hmm = VDJmodeler::build_models("testData/IMGT+C.fa".to_string()).expect("testData/IMGT+C.fa");
+    let mut rng = Lcg(11);
+    let germlines: Vec<Vec<u8>> = hmm.germlines.iter().flatten()
+        .map(|g| g.sequence.iter().filter(|c| HMM::char2pos(**c).is_some()).cloned().collect())
+        .filter(|s: &Vec<u8>| s.len() >= 60)
+        .collect();
+
+    let mut scalar = DpWorkspace::default();
+    let mut vector = DpWorkspace::default();
+    for i in 0..200 {
+        let g = &germlines[i * 7 % germlines.len()];
+        let read = &g[..60];
+        let start = rng.next() as usize % (hmm.states.len() - 1);
+        let expected = hmm.locus_scores_with(Backend::Scalar, read, start, &mut scalar);
+        for backend in backends() {
+            let got = hmm.locus_scores_with(backend, read, start, &mut vector);
+            assert_eq!(expected, got);
+            assert_close(&scalar.prev, &vector.prev);
+        }
+    }
+}